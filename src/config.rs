@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use tracing_subscriber::EnvFilter;
+use url::Url;
+
+use crate::logging;
+use crate::provider::{self, ChatTemplate, LocalApi, MockFailure, MockMode};
+use crate::proxy::Proxy;
+
+pub struct Config {
+  pub tls: bool,
+  pub port: u16,
+  pub rate_limit: RateLimit,
+  /// Tokens allowed to use the API, an empty list disables authentication.
+  pub access_tokens: Vec<String>,
+  /// Outbound proxies of the providers without their own.
+  pub proxies: Vec<Proxy>,
+  /// Outbound proxies by provider name.
+  pub provider_proxies: HashMap<&'static str, Vec<Proxy>>,
+  /// Timeouts by provider name.
+  pub timeouts: HashMap<&'static str, Timeouts>,
+  /// Endpoints replacing the default ones by provider name.
+  pub urls: HashMap<&'static str, Url>,
+  /// Extra attempts after a failure that happened before a reply started.
+  pub max_retries: u32,
+  /// Serves the `mock` provider when set.
+  pub mock: Option<Mock>,
+  /// Serves the `anthropic` provider when set.
+  pub anthropic: Option<Anthropic>,
+  /// Serves the `gemini` provider when set.
+  pub gemini: Option<Gemini>,
+  /// Serves the `local` provider when set.
+  pub local: Option<Local>,
+  /// Stores the conversations of the clients server-side when set.
+  pub store: Option<Store>,
+  /// Tokens of history past which clients ask for the oldest messages to be
+  /// summarized, 0 disables summaries.
+  pub summary_threshold: usize,
+  /// Zero disables health checks.
+  pub health_check_interval: Duration,
+  /// Whether the providers billing the operator are checked too.
+  pub health_check_paid: bool,
+  /// Serves `/metrics` on this port instead of the main one when set.
+  pub metrics_port: Option<u16>,
+  /// Address the metrics are served on, with `metrics_port`.
+  pub metrics_addr: IpAddr,
+  pub log: Log,
+}
+
+pub struct RateLimit {
+  /// Maximum number of requests a client can make in a row, 0 disables the
+  /// per-IP limit.
+  pub burst: u32,
+  pub per_minute: u32,
+  /// Proxies allowed to set the `X-Forwarded-For` header.
+  pub trusted_proxies: Vec<IpAddr>,
+  /// 0 disables the cap.
+  pub max_concurrent: usize,
+  /// 0 disables the cap.
+  pub max_concurrent_per_provider: usize,
+  /// Login attempts a client IP can make per minute, 0 disables the limit.
+  pub login_per_minute: u32,
+  /// Requests a client IP can make to the store per minute, 0 disables the
+  /// limit.
+  pub store_per_minute: u32,
+  /// Attachments a client IP can have extracted per minute, 0 disables the
+  /// limit.
+  pub attachments_per_minute: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+  pub connect: Duration,
+  /// Between sending a prompt and receiving the first bytes of the reply.
+  pub first_token: Duration,
+  /// Between two chunks of a reply.
+  pub inter_token: Duration,
+}
+
+/// How a provider reaches its upstream.
+pub struct Upstream {
+  /// Replaces the provider's endpoint, e.g. with a local server replaying
+  /// recorded replies.
+  pub url: Option<Url>,
+  pub proxies: Vec<Proxy>,
+  pub timeouts: Timeouts,
+  pub max_retries: u32,
+}
+
+#[derive(Clone)]
+pub struct Mock {
+  pub mode: MockMode,
+  /// Directory of the replies of the `replay` mode, one per file.
+  pub fixtures: PathBuf,
+  /// Delay before a reply and between its chunks.
+  pub latency: Duration,
+  /// Probability for a prompt to fail with `failure`.
+  pub failure_rate: f64,
+  pub failure: MockFailure,
+}
+
+#[derive(Clone)]
+pub struct Anthropic {
+  pub api_key: String,
+  /// The first one is the default.
+  pub models: Vec<String>,
+  /// Models that take images.
+  pub vision_models: Vec<String>,
+  pub system_prompt: Option<String>,
+  pub max_tokens: u32,
+}
+
+#[derive(Clone)]
+pub struct Gemini {
+  pub api_key: String,
+  /// The first one is the default.
+  pub models: Vec<String>,
+  pub system_prompt: Option<String>,
+  pub max_tokens: u32,
+}
+
+#[derive(Clone)]
+pub struct Local {
+  /// Has no default since the model is served by the operator.
+  pub url: Url,
+  pub api: LocalApi,
+  /// Sent to the OpenAI-compatible APIs, which may serve several models.
+  pub model: Option<String>,
+  /// Unused by the OpenAI-compatible APIs, which render the chat themselves.
+  pub template: ChatTemplate,
+  pub system_prompt: Option<String>,
+  pub max_tokens: u32,
+  /// Tokens the model can take in, prompt and reply included.
+  pub context_limit: usize,
+  /// Whether the model takes images, only through an OpenAI-compatible API.
+  pub vision: bool,
+}
+
+#[derive(Clone)]
+pub struct Store {
+  /// SQLite database, created if missing.
+  pub path: PathBuf,
+  /// Conversations kept per identity, 0 disables the cap.
+  pub max_conversations: usize,
+  /// Unexpired shares per identity, 0 disables the cap.
+  pub max_shares: usize,
+}
+
+pub struct Log {
+  /// `tracing_subscriber::EnvFilter` directives.
+  pub filter: String,
+  pub format: logging::Format,
+}
+
+impl Config {
+  pub fn from_env() -> anyhow::Result<Self> {
+    let tls = env::args().any(|arg| arg == "--tls");
+    let log_filter = var("LOG_FILTER", "info".to_owned())?;
+    let mut provider_proxies = HashMap::new();
+    let mut timeouts = HashMap::new();
+    let mut urls = HashMap::new();
+    let default_timeouts = Timeouts {
+      connect: secs("CONNECT_TIMEOUT", 10)?,
+      first_token: secs("FIRST_TOKEN_TIMEOUT", 60)?,
+      inter_token: secs("INTER_TOKEN_TIMEOUT", 30)?,
+    };
+
+    if let Err(err) = EnvFilter::try_new(&log_filter) {
+      return Err(anyhow!("invalid LOG_FILTER: {err}"));
+    }
+
+    // the mock feature serves the provider without any configuration
+    let mock = match opt_var("MOCK_MODE")? {
+      Some(mode) => Some(mode),
+      None => cfg!(feature = "mock").then_some(MockMode::Echo),
+    };
+    let mock = mock.map(mock_config).transpose()?;
+    let anthropic = opt_var("ANTHROPIC_API_KEY")?
+      .map(anthropic_config)
+      .transpose()?;
+    let gemini = opt_var("GEMINI_API_KEY")?.map(gemini_config).transpose()?;
+    let local = opt_var("LOCAL_URL")?.map(local_config).transpose()?;
+    let store = opt_var("STORE_PATH")?.map(store_config).transpose()?;
+
+    for &name in provider::NAMES {
+      let prefix = name.to_uppercase();
+      let proxies = list(&format!("{prefix}_PROXIES"))?;
+
+      if !proxies.is_empty() {
+        provider_proxies.insert(name, proxies);
+      }
+
+      if let Some(url) = opt_var(&format!("{prefix}_URL"))? {
+        urls.insert(name, url);
+      }
+
+      timeouts.insert(
+        name,
+        Timeouts {
+          connect: secs(
+            &format!("{prefix}_CONNECT_TIMEOUT"),
+            default_timeouts.connect.as_secs(),
+          )?,
+          first_token: secs(
+            &format!("{prefix}_FIRST_TOKEN_TIMEOUT"),
+            default_timeouts.first_token.as_secs(),
+          )?,
+          inter_token: secs(
+            &format!("{prefix}_INTER_TOKEN_TIMEOUT"),
+            default_timeouts.inter_token.as_secs(),
+          )?,
+        },
+      );
+    }
+
+    Ok(Self {
+      tls,
+      port: var("PORT", if tls { 443 } else { 80 })?,
+      rate_limit: RateLimit {
+        burst: var("RATE_LIMIT_BURST", 10)?,
+        per_minute: var("RATE_LIMIT_PER_MINUTE", 10)?,
+        trusted_proxies: list("TRUSTED_PROXIES")?,
+        max_concurrent: var("MAX_CONCURRENT_ASKS", 64)?,
+        max_concurrent_per_provider: var("MAX_CONCURRENT_ASKS_PER_PROVIDER", 16)?,
+        login_per_minute: var("LOGIN_RATE_LIMIT_PER_MINUTE", 5)?,
+        store_per_minute: var("STORE_RATE_LIMIT_PER_MINUTE", 60)?,
+        attachments_per_minute: var("ATTACHMENT_RATE_LIMIT_PER_MINUTE", 20)?,
+      },
+      access_tokens: list("ACCESS_TOKENS")?,
+      proxies: list("PROXIES")?,
+      provider_proxies,
+      timeouts,
+      urls,
+      max_retries: var("MAX_RETRIES", 2)?,
+      mock,
+      anthropic,
+      gemini,
+      local,
+      store,
+      summary_threshold: var("SUMMARY_THRESHOLD", 0)?,
+      health_check_interval: secs("HEALTH_CHECK_INTERVAL", 300)?,
+      health_check_paid: var("HEALTH_CHECK_PAID", false)?,
+      metrics_port: opt_var("METRICS_PORT")?,
+      metrics_addr: var("METRICS_ADDR", IpAddr::from([127, 0, 0, 1]))?,
+      log: Log {
+        filter: log_filter,
+        format: var("LOG_FORMAT", logging::Format::Text)?,
+      },
+    })
+  }
+
+  pub fn upstream(&self, provider: &str) -> Upstream {
+    Upstream {
+      url: self.urls.get(provider).cloned(),
+      proxies: self
+        .provider_proxies
+        .get(provider)
+        .unwrap_or(&self.proxies)
+        .clone(),
+      timeouts: self.timeouts[provider],
+      max_retries: self.max_retries,
+    }
+  }
+}
+
+fn var<T>(key: &str, default: T) -> anyhow::Result<T>
+where
+  T: FromStr,
+  T::Err: Display,
+{
+  Ok(opt_var(key)?.unwrap_or(default))
+}
+
+fn opt_var<T>(key: &str) -> anyhow::Result<Option<T>>
+where
+  T: FromStr,
+  T::Err: Display,
+{
+  env::var(key)
+    .ok()
+    .map(|val| val.parse().map_err(|err| anyhow!("invalid {key}: {err}")))
+    .transpose()
+}
+
+fn mock_config(mode: MockMode) -> anyhow::Result<Mock> {
+  let failure_rate = var("MOCK_FAILURE_RATE", 0.0)?;
+
+  if !(0.0..=1.0).contains(&failure_rate) {
+    return Err(anyhow!("invalid MOCK_FAILURE_RATE: expected a probability"));
+  }
+
+  Ok(Mock {
+    mode,
+    fixtures: var("MOCK_FIXTURES", PathBuf::from("fixtures"))?,
+    latency: Duration::from_millis(var("MOCK_LATENCY", 50)?),
+    failure_rate,
+    failure: var("MOCK_FAILURE", MockFailure::Timeout)?,
+  })
+}
+
+fn anthropic_config(api_key: String) -> anyhow::Result<Anthropic> {
+  let mut models = list("ANTHROPIC_MODELS")?;
+
+  if models.is_empty() {
+    models.push("claude-3-5-haiku-latest".to_owned());
+  }
+
+  // the Claude 3 models and later all take images
+  let vision_models = match opt_var::<String>("ANTHROPIC_VISION_MODELS")? {
+    Some(_) => list("ANTHROPIC_VISION_MODELS")?,
+    None => models.clone(),
+  };
+
+  Ok(Anthropic {
+    api_key,
+    models,
+    vision_models,
+    system_prompt: opt_var("ANTHROPIC_SYSTEM_PROMPT")?,
+    max_tokens: var("ANTHROPIC_MAX_TOKENS", 1024)?,
+  })
+}
+
+fn gemini_config(api_key: String) -> anyhow::Result<Gemini> {
+  let mut models = list("GEMINI_MODELS")?;
+
+  if models.is_empty() {
+    models.push("gemini-1.5-flash".to_owned());
+  }
+
+  Ok(Gemini {
+    api_key,
+    models,
+    system_prompt: opt_var("GEMINI_SYSTEM_PROMPT")?,
+    max_tokens: var("GEMINI_MAX_TOKENS", 1024)?,
+  })
+}
+
+fn local_config(url: Url) -> anyhow::Result<Local> {
+  let api = var("LOCAL_API", LocalApi::Tgi)?;
+  let vision = var("LOCAL_VISION", false)?;
+
+  if vision && !matches!(api, LocalApi::OpenAi) {
+    return Err(anyhow!(
+      "invalid LOCAL_VISION: images can only be sent through the openai API"
+    ));
+  }
+
+  Ok(Local {
+    url,
+    api,
+    model: opt_var("LOCAL_MODEL")?,
+    template: var("LOCAL_TEMPLATE", ChatTemplate::ChatMl)?,
+    system_prompt: opt_var("LOCAL_SYSTEM_PROMPT")?,
+    max_tokens: var("LOCAL_MAX_TOKENS", 512)?,
+    context_limit: var("LOCAL_CONTEXT_LIMIT", 4096)?,
+    vision,
+  })
+}
+
+fn store_config(path: PathBuf) -> anyhow::Result<Store> {
+  Ok(Store {
+    path,
+    max_conversations: var("STORE_MAX_CONVERSATIONS", 1000)?,
+    max_shares: var("STORE_MAX_SHARES", 100)?,
+  })
+}
+
+fn secs(key: &str, default: u64) -> anyhow::Result<Duration> {
+  var(key, default).map(Duration::from_secs)
+}
+
+/// Parses a comma-separated list.
+fn list<T>(key: &str) -> anyhow::Result<Vec<T>>
+where
+  T: FromStr,
+  T::Err: Display,
+{
+  let Ok(val) = env::var(key) else {
+    return Ok(Vec::new());
+  };
+
+  val
+    .split(',')
+    .map(str::trim)
+    .filter(|item| !item.is_empty())
+    .map(|item| item.parse().map_err(|err| anyhow!("invalid {key}: {err}")))
+    .collect()
+}
@@ -0,0 +1,209 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::{task, time as tokio_time};
+use tracing::warn;
+
+use crate::provider::{self, ProviderError};
+
+const CANARY_PROMPT: &str = "Reply with \"ok\".";
+const CANARY_TIMEOUT: Duration = Duration::from_secs(60);
+/// Number of checks the success rate is computed over.
+const HISTORY_LEN: usize = 10;
+
+pub type Statuses = RwLock<HashMap<&'static str, Status>>;
+
+#[derive(Default)]
+pub struct Status {
+  models: Vec<String>,
+  /// Of the default model.
+  context_limit: usize,
+  summary_threshold: usize,
+  /// Whether the default model takes images.
+  accepts_images: bool,
+  history: VecDeque<bool>,
+  latency: Option<Duration>,
+  /// Code of the error of the last failed check.
+  last_error: Option<&'static str>,
+  checked_at: Option<OffsetDateTime>,
+}
+
+/// What `/api/status` returns for each provider. The errors are only told by
+/// their code since it's public, their messages can quote the upstreams.
+#[derive(Serialize)]
+pub struct Report<'s> {
+  models: &'s [String],
+  context_limit: usize,
+  summary_threshold: usize,
+  accepts_images: bool,
+  up: Option<bool>,
+  latency_ms: Option<u128>,
+  success_rate: Option<f32>,
+  last_error: Option<&'static str>,
+  checked_at: Option<i64>,
+}
+
+impl Status {
+  pub fn report(&self) -> Report<'_> {
+    Report {
+      models: &self.models,
+      context_limit: self.context_limit,
+      summary_threshold: self.summary_threshold,
+      accepts_images: self.accepts_images,
+      up: self.history.back().copied(),
+      latency_ms: self.latency.map(|latency| latency.as_millis()),
+      success_rate: (!self.history.is_empty())
+        .then(|| self.history.iter().filter(|&&ok| ok).count() as f32 / self.history.len() as f32),
+      last_error: self.last_error,
+      checked_at: self.checked_at.map(OffsetDateTime::unix_timestamp),
+    }
+  }
+
+  fn record(&mut self, res: Result<Duration, &ProviderError>) {
+    if self.history.len() == HISTORY_LEN {
+      self.history.pop_front();
+    }
+
+    self.history.push_back(res.is_ok());
+
+    match res {
+      Ok(latency) => self.latency = Some(latency),
+      Err(err) => self.last_error = Some(err.code()),
+    }
+
+    self.checked_at = Some(OffsetDateTime::now_utc());
+  }
+}
+
+/// Sends a canary prompt to every provider each `interval` in the background,
+/// nothing is checked if it's zero. The paid providers are only checked with
+/// `check_paid`, since the prompts are billed to the operator.
+pub fn spawn(
+  providers: Arc<provider::Map>,
+  interval: Duration,
+  check_paid: bool,
+  summary_threshold: usize,
+) -> Arc<Statuses> {
+  let statuses = Arc::new(RwLock::new(
+    providers
+      .iter()
+      .map(|(&name, provider)| {
+        let status = Status {
+          models: provider.models().to_vec(),
+          context_limit: provider.context_limit(None),
+          // leaves room for the messages kept and the reply in small windows
+          summary_threshold: summary_threshold.min(provider.context_limit(None) / 2),
+          accepts_images: provider.accepts_images(None),
+          ..Status::default()
+        };
+
+        (name, status)
+      })
+      .collect::<HashMap<_, _>>(),
+  ));
+
+  if interval.is_zero() {
+    return statuses;
+  }
+
+  for (&name, provider) in providers.iter() {
+    if provider.is_paid() && !check_paid {
+      continue;
+    }
+
+    let providers = providers.clone();
+    let statuses = statuses.clone();
+
+    task::spawn(async move {
+      let mut interval = tokio_time::interval(interval);
+
+      loop {
+        interval.tick().await;
+
+        let res = check(providers[name].as_ref()).await;
+
+        if let Err(err) = res.as_ref() {
+          warn!("health check of provider {name} failed: {err}");
+        }
+
+        statuses
+          .write()
+          .unwrap()
+          .get_mut(name)
+          .unwrap()
+          .record(res.as_ref().copied());
+      }
+    });
+  }
+
+  statuses
+}
+
+async fn check(provider: &dyn provider::Provider) -> Result<Duration, ProviderError> {
+  let start = Instant::now();
+  let ask = async {
+    let (_, body) = provider.ask(CANARY_PROMPT, None, None).await?;
+    let reply = hyper::body::to_bytes(body).await?;
+
+    if reply.is_empty() {
+      Err(ProviderError::MalformedResponse("empty reply".to_owned()))
+    } else {
+      Ok(start.elapsed())
+    }
+  };
+
+  tokio_time::timeout(CANARY_TIMEOUT, ask)
+    .await
+    .unwrap_or(Err(ProviderError::Timeout))
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::{json, Value};
+
+  use super::*;
+
+  fn report(status: &Status) -> Value {
+    serde_json::to_value(status.report()).unwrap()
+  }
+
+  #[test]
+  fn errors_are_reported_by_code() {
+    let mut status = Status::default();
+
+    status.record(Err(&ProviderError::Unreachable(
+      "error trying to connect: 10.0.0.2:8080".to_owned(),
+    )));
+    status.record(Ok(Duration::from_millis(1500)));
+    status.record(Err(&ProviderError::RateLimited));
+
+    let report = report(&status);
+
+    assert_eq!(report["up"], json!(false));
+    assert_eq!(report["latency_ms"], json!(1500));
+    assert_eq!(report["last_error"], json!("upstream_rate_limited"));
+    assert!(!report.to_string().contains("10.0.0.2"));
+  }
+
+  #[test]
+  fn success_rate_is_recent() {
+    let mut status = Status::default();
+
+    for _ in 0..HISTORY_LEN {
+      status.record(Err(&ProviderError::Timeout));
+    }
+
+    for _ in 0..HISTORY_LEN / 2 {
+      status.record(Ok(Duration::from_secs(1)));
+    }
+
+    let report = report(&status);
+
+    assert_eq!(report["up"], json!(true));
+    assert_eq!(report["success_rate"], json!(0.5));
+    assert_eq!(report["last_error"], json!("timeout"));
+  }
+}
@@ -1,36 +1,74 @@
+pub mod attachments;
+pub mod stream;
+pub mod tokens;
 mod ui;
 
+pub use crate::ui::components::{Posted, Shared, SharedProps};
+pub use crate::ui::share::Snapshot;
+
+use std::collections::HashMap;
 use std::iter;
 use std::rc::Rc;
 
-use futures_util::StreamExt;
 use gloo_timers::future::TimeoutFuture;
-use serde::Serialize;
-use wasm_bindgen::JsCast;
-use wasm_streams::ReadableStream;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-  window, Event, HtmlElement, HtmlInputElement, HtmlOptionElement, HtmlSelectElement,
-  HtmlTextAreaElement, TextDecodeOptions, TextDecoder,
+  window, DataTransfer, Event, File, FileList, HtmlElement, HtmlInputElement, HtmlOptionElement,
+  HtmlSelectElement, HtmlTextAreaElement,
 };
-use yew::events::{KeyboardEvent, SubmitEvent};
+use yew::events::{DragEvent, KeyboardEvent, MouseEvent, SubmitEvent};
 use yew::{
   function_component, html, use_effect_with_deps, use_mut_ref, use_node_ref, use_reducer,
-  use_state, Callback, Html, TargetCast,
+  use_state, Callback, Html, Properties, TargetCast,
 };
 
-use crate::ui::components::{Message, ThemeSwitcher};
-use crate::ui::reducers::{Conversations, ConversationsAction};
+use crate::attachments::Attachment;
+use crate::ui::api;
+use crate::ui::components::{
+  attachment_title, Ask, Comparison, Login, Message, PostedField, ShareForm, SyncForm,
+  ThemeSwitcher,
+};
+use crate::ui::images;
+use crate::ui::reducers::{
+  Choice, Comparing, ComparingAction, Conversation, Conversations, ConversationsAction,
+};
+use crate::ui::routing;
 use crate::ui::utils::{close_sidebar as close_sidebar_fn, set_scroll_top_to_scroll_height};
 
 const PROVIDERS: &[(&str, &[&str], bool)] = &[
   ("Ava", &["GPT-3.5-Turbo-0613"], true),
   ("BAI", &["GPT-3.5"], true),
   ("DeepAI", &["GPT-3"], false),
+  ("Mock", &[], false),
   ("You", &[], false),
+  ("Anthropic", &[], false),
+  ("Gemini", &[], false),
+  ("Local", &[], false),
 ];
 
+#[derive(Properties, PartialEq)]
+pub struct AppProps {
+  /// Conversation the URL points at.
+  pub conversation_id: Option<Uuid>,
+  pub posted: Option<Posted>,
+}
+
+impl AppProps {
+  /// The posted conversation is read back from the hidden field of the page.
+  pub fn from_location() -> Self {
+    Self {
+      conversation_id: routing::current(),
+      posted: Posted::from_document(),
+    }
+  }
+}
+
 #[function_component]
-pub fn App() -> Html {
+pub fn App(props: &AppProps) -> Html {
   let submit_ref = use_node_ref();
   let onkeypress = {
     let submit_ref = submit_ref.clone();
@@ -45,23 +83,68 @@ pub fn App() -> Html {
 
   let prompt_ref = use_node_ref();
   let messages_ref = use_node_ref();
+  let prompt_tokens = use_state(|| 0);
   let oninput = {
     let prompt_ref = prompt_ref.clone();
     let messages_ref = messages_ref.clone();
+    let prompt_tokens = prompt_tokens.clone();
 
     Callback::from(move |_| {
-      let prompt_el: HtmlElement = prompt_ref.cast().unwrap();
+      let prompt_el: HtmlTextAreaElement = prompt_ref.cast().unwrap();
       let prompt_style = prompt_el.style();
 
       prompt_style.set_css_text("height: auto; padding: 0;");
       prompt_style.set_css_text(&format!("height: {}px;", prompt_el.scroll_height()));
+      prompt_tokens.set(tokens::count(&prompt_el.value()));
 
       set_scroll_top_to_scroll_height(&messages_ref);
     })
   };
 
-  let conversations =
-    use_reducer(|| Conversations::new(PROVIDERS.iter().find(|p| !p.2).unwrap().0.to_lowercase().as_ref()));
+  let conversations = use_reducer(|| {
+    let mut conversations = Conversations::new(
+      PROVIDERS.iter().find(|p| !p.2).unwrap().0.to_lowercase().as_ref(),
+      props.conversation_id,
+    );
+
+    if let Some(posted) = props.posted.as_ref() {
+      conversations.inner.insert(conversations.current_id, posted.conversation());
+    }
+
+    conversations
+  });
+
+  use_effect_with_deps(|&id| routing::navigate(id), conversations.current_id);
+
+  {
+    let conversations = conversations.clone();
+
+    use_effect_with_deps(
+      move |_| {
+        // the back and forward buttons only change the URL
+        let onpopstate = Closure::<dyn Fn()>::new(move || {
+          if let Some(id) = routing::current() {
+            conversations.dispatch(ConversationsAction::OpenConversation(id));
+          }
+        });
+        let window = window().unwrap();
+
+        window
+          .add_event_listener_with_callback("popstate", onpopstate.as_ref().unchecked_ref())
+          .unwrap();
+
+        move || {
+          drop(
+            window.remove_event_listener_with_callback(
+              "popstate",
+              onpopstate.as_ref().unchecked_ref(),
+            ),
+          );
+        }
+      },
+      (),
+    );
+  }
 
   let mut_conversations = use_mut_ref(|| (conversations.ids(), conversations.current_id));
 
@@ -77,10 +160,219 @@ pub fn App() -> Html {
     );
   }
 
+  let needs_login = use_state(|| false);
+
+  {
+    let needs_login = needs_login.clone();
+
+    use_effect_with_deps(
+      move |_| {
+        let mut url = window().unwrap().location().origin().unwrap();
+        url.push_str("/api/usage");
+
+        wasm_bindgen_futures::spawn_local(async move {
+          if let Ok(res) = gloo_net::http::Request::get(&url).send().await {
+            needs_login.set(res.status() == 401);
+          }
+        });
+      },
+      (),
+    );
+  }
+
+  let statuses = use_state(HashMap::<String, ProviderStatus>::new);
+
+  {
+    let statuses = statuses.clone();
+
+    use_effect_with_deps(
+      move |_| {
+        let mut url = window().unwrap().location().origin().unwrap();
+        url.push_str("/api/status");
+
+        wasm_bindgen_futures::spawn_local(async move {
+          loop {
+            if let Ok(res) = gloo_net::http::Request::get(&url).send().await {
+              if let Some(new_statuses) = res
+                .text()
+                .await
+                .ok()
+                .and_then(|text| serde_json::from_str(&text).ok())
+              {
+                statuses.set(new_statuses);
+              }
+            }
+
+            TimeoutFuture::new(60_000).await;
+          }
+        });
+      },
+      (),
+    );
+  }
+
+  // shares are kept in the store along the synced conversations
+  let can_share = use_state(|| false);
+  let on_sync_available = {
+    let can_share = can_share.clone();
+
+    Callback::from(move |_| can_share.set(true))
+  };
+
+  let on_login = {
+    let needs_login = needs_login.clone();
+
+    Callback::from(move |_| needs_login.set(false))
+  };
+
+  let comparing = use_reducer(Comparing::default);
+
+  // files attached to the next prompt: documents with their extracted text,
+  // and images as `data:` URLs
+  let attached = use_state(Vec::<(Rc<str>, Rc<str>)>::new);
+  let attached_images = use_state(Vec::<(Rc<str>, Rc<str>)>::new);
+  let attaching = use_state(|| false);
+  let attach_error = use_state(|| None::<String>);
+  let add_files = {
+    let conversations = conversations.clone();
+    let needs_login = needs_login.clone();
+    let attached = attached.clone();
+    let attached_images = attached_images.clone();
+    let attaching = attaching.clone();
+    let attach_error = attach_error.clone();
+
+    Callback::from(move |files: Vec<File>| {
+      if files.is_empty() {
+        return;
+      }
+
+      let conv = conversations.current();
+      let provider = conv.provider.clone();
+      let model = conv.model.clone();
+      let needs_login = needs_login.clone();
+      let attached = attached.clone();
+      let attached_images = attached_images.clone();
+      let attaching = attaching.clone();
+      let attach_error = attach_error.clone();
+
+      attaching.set(true);
+      attach_error.set(None);
+
+      wasm_bindgen_futures::spawn_local(async move {
+        let mut new_attached = (*attached).clone();
+        let mut new_attached_images = (*attached_images).clone();
+        let mut error = None;
+
+        for file in files {
+          let name = file.name();
+
+          if file.type_().starts_with("image/") {
+            if new_attached_images.len() >= attachments::MAX_IMAGES {
+              error = Some(format!(
+                "At most {} images can be attached to a prompt.",
+                attachments::MAX_IMAGES
+              ));
+              continue;
+            }
+
+            match images::prepare(&file).await {
+              Ok(url) => new_attached_images.push((name.into(), url.into())),
+              Err(err) => error = Some(format!("{name} {err}.")),
+            }
+
+            continue;
+          }
+
+          if new_attached.len() >= attachments::MAX_COUNT {
+            error = Some(format!(
+              "At most {} files can be attached to a prompt.",
+              attachments::MAX_COUNT
+            ));
+            continue;
+          }
+
+          if file.size() > attachments::MAX_SIZE as f64 {
+            error = Some(format!("{name} is too large."));
+            continue;
+          }
+
+          let Ok(buffer) = JsFuture::from(file.array_buffer()).await else {
+            error = Some(format!("{name} couldn't be read."));
+            continue;
+          };
+          let bytes = js_sys::Uint8Array::new(&buffer).to_vec();
+
+          match api::extract(&provider, model.as_deref(), &bytes).await {
+            Ok(extracted) => new_attached.push((name.into(), extracted.text.into())),
+            Err(err) => {
+              if err.status == 401 {
+                needs_login.set(true);
+              }
+
+              error = Some(format!("{name}: {}", err.message));
+            }
+          }
+        }
+
+        attached.set(new_attached);
+        attached_images.set(new_attached_images);
+        attaching.set(false);
+        attach_error.set(error);
+      });
+    })
+  };
+  let attach = add_files.reform(|e: Event| {
+    let input: HtmlInputElement = e.target_unchecked_into();
+    let files = files(input.files());
+
+    // lets the same files be picked again
+    input.set_value("");
+
+    files
+  });
+  let onpaste = {
+    let add_files = add_files.clone();
+
+    Callback::from(move |e: Event| {
+      // `ClipboardEvent` is behind the unstable APIs of web-sys
+      let files = files(
+        js_sys::Reflect::get(&e, &JsValue::from_str("clipboardData"))
+          .ok()
+          .and_then(|data| data.dyn_into::<DataTransfer>().ok())
+          .and_then(|data| data.files()),
+      );
+
+      // pasted text goes in the prompt as usual
+      if !files.is_empty() {
+        e.prevent_default();
+        add_files.emit(files);
+      }
+    })
+  };
+  // files dropped anywhere on the prompt are attached to it
+  let ondragover = Callback::from(|e: DragEvent| {
+    if e
+      .data_transfer()
+      .is_some_and(|data| data.types().includes(&JsValue::from_str("Files"), 0))
+    {
+      e.prevent_default();
+    }
+  });
+  let ondrop = Callback::from(move |e: DragEvent| {
+    e.prevent_default();
+    add_files.emit(files(e.data_transfer().and_then(|data| data.files())));
+  });
+
   let onsubmit = {
     let prompt_ref = prompt_ref.clone();
     let messages_ref = messages_ref.clone();
     let conversations = conversations.clone();
+    let needs_login = needs_login.clone();
+    let statuses = statuses.clone();
+    let comparing = comparing.clone();
+    let attached = attached.clone();
+    let attached_images = attached_images.clone();
+    let attaching = attaching.clone();
 
     Callback::from(move |e: SubmitEvent| {
       e.prevent_default();
@@ -88,7 +380,70 @@ pub fn App() -> Html {
       let prompt_el: HtmlTextAreaElement = prompt_ref.cast().unwrap();
       let prompt_val = prompt_el.value();
 
-      if prompt_val.is_empty() {
+      if prompt_val.is_empty() && attached.is_empty() && attached_images.is_empty()
+        || comparing.active && comparing.providers.is_empty()
+        || *attaching
+      {
+        return;
+      }
+
+      // the providers get the text of the files along the prompt, and the
+      // images only with this one, the history keeping placeholders
+      let prompt_val = attachments::inline(
+        &prompt_val,
+        attached
+          .iter()
+          .map(|(name, text)| Attachment {
+            name,
+            text: text.as_ref().into(),
+          })
+          .chain(attached_images.iter().map(|(name, _)| Attachment {
+            name,
+            text: attachments::IMAGE_PLACEHOLDER.into(),
+          })),
+      );
+      let image_urls = (!attached_images.is_empty()).then(|| {
+        let urls = attached_images.iter().map(|(_, url)| url.as_ref()).collect::<Vec<_>>();
+
+        serde_json::to_string(&urls).unwrap()
+      });
+      attached.set(Vec::new());
+      attached_images.set(Vec::new());
+
+      if comparing.active {
+        let conv = conversations.current();
+        let asks = comparing
+          .providers
+          .iter()
+          .map(|provider| {
+            let mut conv = conv.clone();
+
+            // the model only applies to the provider of the conversation
+            if conv.provider != *provider {
+              conv.provider = provider.clone();
+              conv.model = None;
+            }
+
+            Ask {
+              provider: provider.clone(),
+              name: provider_name(provider).unwrap(),
+              model: conv.model.clone(),
+              state: (!conv.switched_provider()).then(|| state(&conv)).flatten(),
+              history: conv.switched_provider().then(|| chat(&conv.context())).flatten(),
+            }
+          })
+          .collect();
+
+        comparing.dispatch(ComparingAction::Start {
+          conv_id: conversations.current_id,
+          prompt: prompt_val.into(),
+          images: image_urls.map(Rc::from),
+          asks,
+        });
+        prompt_el.set_value("");
+        prompt_el
+          .dispatch_event(&Event::new("input").unwrap())
+          .unwrap();
         return;
       }
 
@@ -108,98 +463,86 @@ pub fn App() -> Html {
       ));
       set_scroll_top_to_scroll_height(&messages_ref);
 
-      let mut url = window().unwrap().location().origin().unwrap();
-      url.push_str("/api/ask");
-
-      let conv = conversations.get(&task_conv_id);
-      let provider = conv.provider.clone();
-      let state = match provider.as_ref() {
-        "ava" | "deepai" => {
-          if conv.messages.is_empty() {
-            None
-          } else {
-            Some(
-              serde_json::to_string(
-                &conv
-                  .messages
-                  .iter()
-                  .enumerate()
-                  .map(|(i, msg)| DeepAiMessage {
-                    role: if i % 2 == 0 { "user" } else { "assistant" },
-                    content: msg,
-                  })
-                  .collect::<Vec<_>>(),
-              )
-              .unwrap(),
-            )
-          }
-        }
-        "bai" => conv.last_msg_id.clone(),
-        "you" => {
-          if conv.messages.is_empty() {
-            None
-          } else if let Some(last_msg_id) = conv.last_msg_id.as_ref() {
-            let chat = serde_json::to_string(
-              &conv
-                .messages
-                .chunks(2)
-                .map(|chunk| YouMessage {
-                  question: &chunk[0],
-                  answer: &chunk[1],
-                })
-                .collect::<Vec<_>>(),
-            )
-            .unwrap();
-
-            let mut state = String::with_capacity(last_msg_id.len() + chat.len());
-            state.push_str(last_msg_id);
-            state.push_str(&chat);
-
-            Some(state)
-          } else {
-            None
-          }
-        }
-        _ => unreachable!(),
+      let mut conv = conversations.get(&task_conv_id).clone();
+      // BAI keeps the history upstream and the mock provider ignores it
+      let summary_threshold = match conv.provider.as_ref() {
+        "bai" | "mock" => 0,
+        provider => statuses
+          .get(provider)
+          .map_or(0, |status| status.summary_threshold),
       };
 
       let conversations = conversations.clone();
       let mut_conversations = mut_conversations.clone();
       let messages_ref = messages_ref.clone();
+      let needs_login = needs_login.clone();
 
       wasm_bindgen_futures::spawn_local(async move {
-        let mut params = Vec::with_capacity(3);
-        params.push(("provider", provider.as_ref()));
+        let context_tokens = tokens::count_messages(conv.context().iter().map(AsRef::as_ref));
+
+        if summary_threshold > 0 && context_tokens > summary_threshold {
+          // the server trims the conversation instead if it can't be summarized
+          if let Some(summary) = api::summarize(&conv).await {
+            conversations.dispatch(ConversationsAction::SetSummary(task_conv_id, summary.clone()));
+            conv.summary = Some(summary);
+          }
+        }
+
+        let mut params = Vec::with_capacity(5);
+        params.push(("provider", conv.provider.as_ref()));
         params.push(("prompt", prompt_val.as_str()));
 
+        if let Some(images) = image_urls.as_deref() {
+          params.push(("images", images));
+        }
+
+        // the server rebuilds the state of the new provider from the messages
+        let (state, history) = if conv.switched_provider() {
+          (None, chat(&conv.context()))
+        } else {
+          (state(&conv), None)
+        };
+
         if let Some(state) = state.as_deref() {
           params.push(("state", state));
         }
 
-        let res = gloo_net::http::Request::get(&url)
-          .query(params)
-          .send()
-          .await
-          .unwrap();
+        if let Some(history) = history.as_deref() {
+          params.push(("history", history));
+        }
 
-        if res.ok() {
-          if let Some(msg_id) = res.headers().get("msg-id") {
-            conversations.dispatch(ConversationsAction::SetLastMessageId(task_conv_id, msg_id));
-          }
+        if let Some(model) = conv.model.as_deref() {
+          params.push(("model", model));
         }
 
-        let decoder = TextDecoder::new().unwrap();
+        let mut reply = match api::ask(params).await {
+          Ok(reply) => reply,
+          Err(err) => {
+            if err.status == 401 {
+              needs_login.set(true);
+            }
 
-        let mut decode_options = TextDecodeOptions::new();
-        decode_options.stream(true);
+            conversations.dispatch(ConversationsAction::SetError(task_conv_id, err.message));
+            conversations.dispatch(ConversationsAction::SetUpdatingLastMessage(
+              task_conv_id,
+              false,
+            ));
+            return;
+          }
+        };
 
-        let mut stream =
-          ReadableStream::from_raw(res.body().unwrap().dyn_into().unwrap()).into_stream();
+        if let Some(msg_id) = reply.msg_id.take() {
+          conversations.dispatch(ConversationsAction::SetLastMessageId(task_conv_id, msg_id));
+        }
 
-        'outer: while let Some(Ok(chunk)) = stream.next().await {
-          let chunk = decoder
-            .decode_with_buffer_source_and_options(&js_sys::Object::from(chunk), &decode_options)
-            .unwrap();
+        'outer: while let Some(chunk) = reply.next().await {
+          let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+              conversations.dispatch(ConversationsAction::SetError(task_conv_id, err));
+              break;
+            }
+          };
 
           for char in chunk.chars() {
             if !mut_conversations.borrow().0.contains(&task_conv_id) {
@@ -263,6 +606,41 @@ pub fn App() -> Html {
     })
   };
 
+  let set_model = {
+    let conversations = conversations.clone();
+
+    Callback::from(move |e: Event| {
+      let model_el: HtmlSelectElement = e.target_unchecked_into();
+
+      conversations.dispatch(ConversationsAction::SetModel(model_el.value()));
+    })
+  };
+
+  let toggle_comparing = {
+    let comparing = comparing.clone();
+
+    Callback::from(move |_| comparing.dispatch(ComparingAction::Toggle))
+  };
+
+  let on_choose = {
+    let conversations = conversations.clone();
+    let comparing = comparing.clone();
+
+    Callback::from(move |choice: Choice| {
+      if let Some(pending) = comparing.pending.as_ref() {
+        conversations.dispatch(ConversationsAction::ContinueWith(pending.conv_id, choice));
+      }
+
+      comparing.dispatch(ComparingAction::Finish);
+    })
+  };
+
+  let on_close_comparison = {
+    let comparing = comparing.clone();
+
+    Callback::from(move |_| comparing.dispatch(ComparingAction::Close))
+  };
+
   let conversations_ref = use_node_ref();
   let provider_ref = use_node_ref();
   let create_conv = {
@@ -312,11 +690,19 @@ pub fn App() -> Html {
     })
   };
 
+  let sharing = use_state(|| false);
+  let toggle_sharing = {
+    let sharing = sharing.clone();
+
+    Callback::from(move |_| sharing.set(!*sharing))
+  };
+
   {
     let curr_conv_name_ref = curr_conv_name_ref.clone();
     let editing_name = editing_name.clone();
     let messages_ref = messages_ref.clone();
     let provider_ref = provider_ref.clone();
+    let sharing = sharing.clone();
 
     use_effect_with_deps(
       {
@@ -330,6 +716,8 @@ pub fn App() -> Html {
             curr_conv_name_el.set_disabled(true);
           }
 
+          sharing.set(false);
+
           let provider_el: HtmlSelectElement = provider_ref.cast().unwrap();
           let child_nodes = provider_el.child_nodes();
           let mut i = 0;
@@ -351,9 +739,32 @@ pub fn App() -> Html {
   }
 
   let curr_conv = conversations.current();
+  let conv_tokens = tokens::count_messages(curr_conv.context().iter().map(AsRef::as_ref))
+    + *prompt_tokens
+    + attached.iter().map(|(_, text)| tokens::count(text)).sum::<usize>();
+  let context_limit = statuses
+    .get(curr_conv.provider.as_ref())
+    .map_or(0, |status| status.context_limit);
+  let over_limit = context_limit != 0 && conv_tokens > context_limit;
+  // the status only tells about the default model
+  let rejects_images = statuses
+    .get(curr_conv.provider.as_ref())
+    .is_some_and(|status| {
+      !status.accepts_images
+        && (curr_conv.model.is_none()
+          || status.models.first().map(String::as_str) == curr_conv.model.as_deref())
+    });
+  let tokens_label = if context_limit == 0 {
+    format!("~{conv_tokens} tokens")
+  } else {
+    format!("~{conv_tokens} / {context_limit} tokens")
+  };
 
   html! {
     <div class="h-screen flex gap-4 lg:p-4 bg-[#E1E1E1] dark:bg-[#151515] text-[#333333] dark:text-[#F5F5F5]">
+      if *needs_login {
+        <Login {on_login} />
+      }
       <div class="absolute w-full h-full z-20 flex pointer-events-none lg:w-fit lg:relative">
         <div ref={sidebar_ref.clone()} class="p-5 pb-6 rounded-e-xl bg-[#EBEBEB] dark:bg-[#1A1A1A] pointer-events-auto flex flex-col gap-5 -translate-x-full md:w-[37%] lg:pb-5 lg:w-64 lg:translate-x-0 lg:rounded-s-xl">
           <div class="flex gap-3 items-center justify-between">
@@ -370,7 +781,13 @@ pub fn App() -> Html {
                 let overlay_ref = overlay_ref.clone();
                 let invisible_overlay_ref = invisible_overlay_ref.clone();
 
-                Callback::from(move |_| {
+                Callback::from(move |e: MouseEvent| {
+                  // opened in another tab or window by the browser
+                  if e.ctrl_key() || e.meta_key() || e.shift_key() || e.button() != 0 {
+                    return;
+                  }
+
+                  e.prevent_default();
                   conversations.dispatch(ConversationsAction::SetCurrentId(id));
 
                   close_sidebar_fn(&sidebar_ref, &overlay_ref, &invisible_overlay_ref);
@@ -405,10 +822,10 @@ pub fn App() -> Html {
                   class="rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm flex gap-3 justify-between items-center aria-selected:bg-[#FF983F] aria-selected:dark:bg-[#FF7A1F]"
                   aria-selected={(id == conversations.current_id).to_string()}
                 >
-                  <div class="w-full flex pl-2.5 py-2 cursor-pointer overflow-hidden text-ellipsis" {onclick}>
+                  <a href={routing::path(id)} class="w-full flex pl-2.5 py-2 cursor-pointer overflow-hidden text-ellipsis" {onclick}>
                     <span class={hash_class}>{"#"}</span>
                     <span class="whitespace-nowrap overflow-hidden text-ellipsis inline-block">{name}</span>
-                  </div>
+                  </a>
                   <div class="pr-2.5 py-2">
                     <svg viewBox="0 0 24 24" fill="none" stroke-width="2.5px" stroke-linecap="round" stroke-linejoin="round" class={trash_class} onclick={trash_onclick}>
                       <polyline points="3 6 5 6 21 6"></polyline>
@@ -421,6 +838,8 @@ pub fn App() -> Html {
             })}
           </div>
 
+          <SyncForm conversations={conversations.clone()} on_available={on_sync_available} />
+
           <div>
             <button class="px-3 py-2.5 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm flex justify-center cursor-pointer" onclick={create_conv}>{"+ New Conversation"}</button>
           </div>
@@ -450,51 +869,247 @@ pub fn App() -> Html {
               </svg>
             </button>
           </form>
+          if *can_share && !curr_conv.messages.is_empty() {
+            <button type="button" class="ml-auto px-3 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm" onclick={toggle_sharing}>{"Share"}</button>
+          }
         </div>
 
+        if *sharing {
+          <ShareForm key={conversations.current_id.to_string()} conversation={curr_conv.clone()} />
+        }
+
         <div ref={messages_ref} class="flex-1 w-full flex flex-col gap-3 overflow-y-auto lg:gap-4">
-          {for curr_conv.messages.iter().enumerate().map(|(i, msg)| html! {
-            <Message key={i} index={i} content={msg.clone()} />
+          if let Some(summary) = curr_conv.summary.as_ref() {
+            <details class="px-3.5 py-3 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm">
+              <summary class="cursor-pointer text-black/50 dark:text-white/50">{format!("Summary of the first {} messages, sent in their place", summary.len)}</summary>
+              <p class="mt-2 whitespace-pre-wrap">{summary.text.clone()}</p>
+            </details>
+          }
+          {for curr_conv.messages.iter().enumerate().map(|(i, msg)| {
+            let label = (i % 2 == 1)
+              .then(|| curr_conv.reply_providers.get(i / 2))
+              .flatten()
+              .and_then(|provider| provider_name(provider));
+
+            html! {
+              <Message key={i} index={i} content={msg.clone()} {label} />
+            }
           })}
+          if let Some(error) = curr_conv.error.as_ref() {
+            <div class="px-3.5 py-3 rounded-xl bg-red-600/10 text-sm text-red-600 dark:text-red-400">{error.clone()}</div>
+          }
+          if let Some(pending) = comparing.pending.as_ref().filter(|pending| pending.conv_id == conversations.current_id) {
+            <Comparison key={pending.id} prompt={pending.prompt.clone()} images={pending.images.clone()} asks={pending.asks.clone()} {on_choose} on_close={on_close_comparison} />
+          }
         </div>
 
-        <form autocomplete="off" class="w-full flex flex-col gap-3" {onsubmit}>
-          <div class="px-3.5 py-3 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] flex">
-            <textarea ref={prompt_ref} rows="1" placeholder="Ask anything..." autofocus=true class="flex-1 resize-none outline-none bg-transparent text-sm max-h-32 overflow-x-hidden" {onkeypress} {oninput}></textarea>
-            <button ref={submit_ref} type="submit" class="ml-2.5 fill-current disabled:cursor-not-allowed disabled:opacity-50 enabled:hover:fill-[#FF7A1F]" disabled={curr_conv.updating_last_msg}>
+        // posted to the server when JavaScript is disabled
+        <form method="post" action={format!("/api/respond/{}", conversations.current_id)} autocomplete="off" class="w-full flex flex-col gap-3" {onsubmit}>
+          if let Some(posted) = props.posted.clone() {
+            <PostedField {posted} name={curr_conv.name.clone()} />
+          }
+          if !attached.is_empty() || !attached_images.is_empty() || *attaching || attach_error.is_some() {
+            <div class="flex flex-wrap gap-1.5 items-center">
+              {for attached_images.iter().enumerate().map(|(i, (name, url))| {
+                let remove = {
+                  let attached_images = attached_images.clone();
+
+                  Callback::from(move |_| {
+                    let mut new_attached_images = (*attached_images).clone();
+                    new_attached_images.remove(i);
+                    attached_images.set(new_attached_images);
+                  })
+                };
+
+                html! {
+                  <span key={format!("image-{i}")} class="pl-1 pr-1.5 py-1 rounded-lg bg-[#F5F5F5] dark:bg-[#292929] text-xs flex gap-1.5 items-center" title="Only sent along this prompt">
+                    <img src={url.clone()} alt="" class="h-5 rounded" />
+                    {name.clone()}
+                    <button type="button" class="text-black/50 dark:text-white/50 hover:text-red-600" title="Remove" onclick={remove}>{"✕"}</button>
+                  </span>
+                }
+              })}
+              {for attached.iter().enumerate().map(|(i, (name, text))| {
+                let remove = {
+                  let attached = attached.clone();
+
+                  Callback::from(move |_| {
+                    let mut new_attached = (*attached).clone();
+                    new_attached.remove(i);
+                    attached.set(new_attached);
+                  })
+                };
+
+                html! {
+                  <span key={i} class="pl-2 pr-1.5 py-1 rounded-lg bg-[#F5F5F5] dark:bg-[#292929] text-xs flex gap-1.5 items-center" title={attachment_title(&Attachment { name, text: text.as_ref().into() })}>
+                    {"📄 "}{name.clone()}
+                    <button type="button" class="text-black/50 dark:text-white/50 hover:text-red-600" title="Remove" onclick={remove}>{"✕"}</button>
+                  </span>
+                }
+              })}
+              if *attaching {
+                <span class="px-1 text-xs text-black/50 dark:text-white/50">{"Preparing the files..."}</span>
+              }
+              if rejects_images && !attached_images.is_empty() {
+                <span class="px-1 text-xs text-[#FF7A1F]">{"This model doesn't take images, pick a vision model to send them."}</span>
+              }
+              if let Some(error) = attach_error.as_ref() {
+                <span class="px-1 text-xs text-red-600 dark:text-red-400">{error.clone()}</span>
+              }
+            </div>
+          }
+          <div class="px-3.5 py-3 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] flex" {ondragover} {ondrop}>
+            <label class="mr-2.5 fill-current hover:fill-[#FF7A1F] cursor-pointer" title="Attach text files, PDFs or images">
+              <input type="file" multiple={true} class="hidden" onchange={attach} />
+              <svg viewBox="0 0 448 512" class="w-4">
+                <path d="M364.2 83.8c-24.4-24.4-64-24.4-88.4 0l-184 184c-42.1 42.1-42.1 110.3 0 152.4s110.3 42.1 152.4 0l152-152c10.9-10.9 28.7-10.9 39.6 0s10.9 28.7 0 39.6l-152 152c-64 64-167.6 64-231.6 0s-64-167.6 0-231.6l184-184c46.3-46.3 121.3-46.3 167.6 0s46.3 121.3 0 167.6l-176 176c-28.6 28.6-75 28.6-103.6 0s-28.6-75 0-103.6l144-144c10.9-10.9 28.7-10.9 39.6 0s10.9 28.7 0 39.6l-144 144c-6.7 6.7-6.7 17.7 0 24.4s17.7 6.7 24.4 0l176-176c24.4-24.4 24.4-64 0-88.4z"></path>
+              </svg>
+            </label>
+            <textarea ref={prompt_ref} name="prompt" rows="1" placeholder="Ask anything..." autofocus=true class="flex-1 resize-none outline-none bg-transparent text-sm max-h-32 overflow-x-hidden" {onkeypress} {oninput} {onpaste}></textarea>
+            <button ref={submit_ref} type="submit" class="ml-2.5 fill-current disabled:cursor-not-allowed disabled:opacity-50 enabled:hover:fill-[#FF7A1F]" disabled={curr_conv.updating_last_msg || *attaching}>
               <svg viewBox="0 0 512 512" class="w-5">
                 <path d="M440 6.5L24 246.4c-34.4 19.9-31.1 70.8 5.7 85.9L144 379.6V464c0 46.4 59.2 65.5 86.6 28.6l43.8-59.1 111.9 46.2c5.9 2.4 12.1 3.6 18.3 3.6 8.2 0 16.3-2.1 23.6-6.2 12.8-7.2 21.6-20 23.9-34.5l59.4-387.2c6.1-40.1-36.9-68.8-71.5-48.9zM192 464v-64.6l36.6 15.1L192 464zm212.6-28.7l-153.8-63.5L391 169.5c10.7-15.5-9.5-33.5-23.7-21.2L155.8 332.6 48 288 464 48l-59.4 387.3z"></path>
               </svg>
             </button>
           </div>
           <div class="flex gap-3">
-            <select ref={provider_ref} class="px-2.5 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm disabled:text-black/50 dark:disabled:text-white/50" disabled={!curr_conv.messages.is_empty()} onchange={set_provider}>
-              {for PROVIDERS.iter().map(|&(name, _, disabled)| {
+            <select ref={provider_ref} name="provider" class="px-2.5 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm disabled:text-black/50 dark:disabled:text-white/50" onchange={set_provider}>
+              // providers missing from the statuses aren't served, like the mock one
+              {for PROVIDERS.iter().filter(|p| statuses.is_empty() || statuses.contains_key(&p.0.to_lowercase())).map(|&(name, _, disabled)| {
                 let value = Rc::<str>::from(name.to_lowercase());
+                let status = statuses.get(value.as_ref());
+                let label = match status.and_then(ProviderStatus::dot) {
+                  Some(dot) => format!("{dot} {name}"),
+                  None => name.to_owned(),
+                };
 
                 html! {
-                  <option key={value.clone()} value={value.clone()} disabled={disabled} selected={curr_conv.provider == value}>{name}</option>
+                  <option key={value.clone()} value={value.clone()} disabled={disabled} selected={curr_conv.provider == value} title={status.and_then(ProviderStatus::title)}>{label}</option>
                 }
               })}
             </select>
-            <select class="px-2.5 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm disabled:text-black/50 dark:disabled:text-white/50" disabled={true}>
-              if let Some((_, models, _)) = PROVIDERS.iter().find(|p| p.0.to_lowercase().as_str() == curr_conv.provider.as_ref()) { // always evaluates to Some(...), it's needed to declare the models variable and allow reuse
-                if models.is_empty() {
-                  <option>{"N/A"}</option>
-                } else {
-                  {for models.iter().map(|&model| html! {
-                    <option key={model} value={model.to_lowercase()}>{model}</option>
-                  })}
+            // the models served by the server replace the built-in ones
+            if let Some(models) = statuses.get(curr_conv.provider.as_ref()).map(|status| &status.models).filter(|models| !models.is_empty()) {
+              <select class="px-2.5 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm disabled:text-black/50 dark:disabled:text-white/50" name="model" disabled={models.len() < 2} onchange={set_model}>
+                {for models.iter().map(|model| html! {
+                  <option key={model.as_str()} value={model.clone()} selected={curr_conv.model.as_deref() == Some(model.as_str())}>{model}</option>
+                })}
+              </select>
+            } else {
+              <select class="px-2.5 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm disabled:text-black/50 dark:disabled:text-white/50" disabled={true}>
+                if let Some((_, models, _)) = PROVIDERS.iter().find(|p| p.0.to_lowercase().as_str() == curr_conv.provider.as_ref()) { // always evaluates to Some(...), it's needed to declare the models variable and allow reuse
+                  if models.is_empty() {
+                    <option>{"N/A"}</option>
+                  } else {
+                    {for models.iter().map(|&model| html! {
+                      <option key={model} value={model.to_lowercase()}>{model}</option>
+                    })}
+                  }
                 }
-              }
-            </select>
+              </select>
+            }
+            <button type="button" class={if comparing.active { "px-2.5 py-2 rounded-xl bg-[#FF983F] dark:bg-[#FF7A1F] text-sm" } else { "px-2.5 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm" }} title="Send the prompt to several providers side by side" onclick={toggle_comparing}>{"Compare"}</button>
+            // the server leaves out the oldest messages past the limit
+            <span class={if over_limit { "ml-auto self-center text-xs text-[#FF7A1F]" } else { "ml-auto self-center text-xs text-black/50 dark:text-white/50" }} title={over_limit.then_some("The oldest messages are left out")}>{tokens_label}</span>
           </div>
+          if comparing.active {
+            <div class="flex flex-wrap gap-3">
+              {for PROVIDERS.iter().filter(|p| !p.2 && (statuses.is_empty() || statuses.contains_key(&p.0.to_lowercase()))).map(|&(name, _, _)| {
+                let value = Rc::<str>::from(name.to_lowercase());
+                let onchange = {
+                  let comparing = comparing.clone();
+                  let value = value.clone();
+
+                  Callback::from(move |_: Event| comparing.dispatch(ComparingAction::ToggleProvider(value.clone())))
+                };
+
+                html! {
+                  <label key={value.as_ref()} class="px-2.5 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm flex gap-1.5 items-center cursor-pointer">
+                    <input type="checkbox" class="accent-[#FF7A1F]" checked={comparing.providers.contains(&value)} {onchange} />
+                    {name}
+                  </label>
+                }
+              })}
+            </div>
+          }
         </form>
       </div>
     </div>
   }
 }
 
+/// Builds the state sent along a prompt, in the format of the provider.
+fn state(conv: &Conversation) -> Option<String> {
+  let context = conv.context();
+
+  match conv.provider.as_ref() {
+    "anthropic" | "ava" | "deepai" | "gemini" | "local" => chat(&context),
+    "bai" => conv.last_msg_id.clone(),
+    "mock" => None,
+    "you" => {
+      if context.is_empty() {
+        None
+      } else if let Some(last_msg_id) = conv.last_msg_id.as_ref() {
+        let chat = serde_json::to_string(
+          &context
+            .chunks(2)
+            .map(|chunk| YouMessage {
+              question: &chunk[0],
+              answer: &chunk[1],
+            })
+            .collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        let mut state = String::with_capacity(last_msg_id.len() + chat.len());
+        state.push_str(last_msg_id);
+        state.push_str(&chat);
+
+        Some(state)
+      } else {
+        None
+      }
+    }
+    _ => unreachable!(),
+  }
+}
+
+/// Serializes messages into the chat format, which the server also takes as
+/// the history of a conversation switching providers.
+fn chat(messages: &[Rc<str>]) -> Option<String> {
+  if messages.is_empty() {
+    return None;
+  }
+
+  Some(
+    serde_json::to_string(
+      &messages
+        .iter()
+        .enumerate()
+        .map(|(i, msg)| DeepAiMessage {
+          role: if i % 2 == 0 { "user" } else { "assistant" },
+          content: msg,
+        })
+        .collect::<Vec<_>>(),
+    )
+    .unwrap(),
+  )
+}
+
+/// Collects the files of a file list, like the ones picked or dropped.
+fn files(list: Option<FileList>) -> Vec<File> {
+  list.map_or_else(Vec::new, |list| {
+    (0..list.length()).filter_map(|i| list.get(i)).collect()
+  })
+}
+
+fn provider_name(provider: &str) -> Option<&'static str> {
+  PROVIDERS
+    .iter()
+    .find(|p| p.0.to_lowercase().as_str() == provider)
+    .map(|p| p.0)
+}
+
 #[derive(Serialize)]
 struct DeepAiMessage<'m> {
   role: &'m str,
@@ -506,3 +1121,53 @@ struct YouMessage<'m> {
   question: &'m str,
   answer: &'m str,
 }
+
+#[derive(Deserialize)]
+struct ProviderStatus {
+  #[serde(default)]
+  models: Vec<String>,
+  /// Zero if unknown.
+  #[serde(default)]
+  context_limit: usize,
+  /// Tokens of history past which the oldest messages are summarized, zero
+  /// if summaries are disabled.
+  #[serde(default)]
+  summary_threshold: usize,
+  /// Whether the default model takes images.
+  #[serde(default)]
+  accepts_images: bool,
+  up: Option<bool>,
+  latency_ms: Option<u64>,
+  success_rate: Option<f32>,
+  /// Code of the error of the last failed check.
+  last_error: Option<String>,
+}
+
+impl ProviderStatus {
+  fn dot(&self) -> Option<&'static str> {
+    match (self.up?, self.success_rate?) {
+      (false, _) => Some("🔴"),
+      (true, rate) if rate < 0.8 => Some("🟠"),
+      (true, _) => Some("🟢"),
+    }
+  }
+
+  fn title(&self) -> Option<String> {
+    let up = self.up?;
+    let mut title = if up { "Up" } else { "Down" }.to_owned();
+
+    if let (false, Some(code)) = (up, &self.last_error) {
+      title.push_str(&format!(" ({code})"));
+    }
+
+    if let Some(latency_ms) = self.latency_ms {
+      title.push_str(&format!(", replied in {:.1}s", latency_ms as f32 / 1000.0));
+    }
+
+    if let Some(rate) = self.success_rate {
+      title.push_str(&format!(", {:.0}% of recent checks succeeded", rate * 100.0));
+    }
+
+    Some(title)
+  }
+}
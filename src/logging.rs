@@ -0,0 +1,343 @@
+use std::io;
+use std::str::FromStr;
+
+use anyhow::bail;
+use tracing::{Metadata, Subscriber};
+use tracing_subscriber::filter::{filter_fn, EnvFilter};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, Layer};
+
+use crate::config;
+
+/// Spans and events carrying one of these fields are never written, so
+/// conversations can't end up in the logs.
+const SENSITIVE_FIELDS: &[&str] = &[
+  "prompt", "reply", "state", "chat", "messages", "content", "body",
+];
+
+#[derive(Clone, Copy)]
+pub enum Format {
+  Text,
+  Json,
+}
+
+impl FromStr for Format {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(Self::Text),
+      "json" => Ok(Self::Json),
+      _ => bail!("expected 'text' or 'json'"),
+    }
+  }
+}
+
+pub fn init(cfg: &config::Log) {
+  tracing_subscriber::registry()
+    .with(layer(cfg, io::stdout))
+    .init();
+}
+
+/// Writes the events `cfg` lets through to `writer`, without the sensitive
+/// ones.
+fn layer<S, W>(cfg: &config::Log, writer: W) -> impl Layer<S>
+where
+  S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+  W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+  let redact = filter_fn(|metadata| !carries_sensitive_fields(metadata));
+  let layer = match cfg.format {
+    Format::Text => fmt::layer().with_writer(writer).boxed(),
+    Format::Json => fmt::layer()
+      .json()
+      .with_current_span(true)
+      .with_span_list(false)
+      .with_writer(writer)
+      .boxed(),
+  };
+
+  layer
+    .with_filter(EnvFilter::new(&cfg.filter))
+    .with_filter(redact)
+}
+
+fn carries_sensitive_fields(metadata: &Metadata<'_>) -> bool {
+  metadata
+    .fields()
+    .iter()
+    .any(|field| SENSITIVE_FIELDS.contains(&field.name()))
+}
+
+/// Describes a JSON error without the input fragment `serde_json` may quote,
+/// which could be part of a reply.
+pub fn json_error(err: &serde_json::Error) -> String {
+  format!(
+    "{:?} error at line {} column {}",
+    err.classify(),
+    err.line(),
+    err.column()
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+
+  use axum::body::Body;
+  use axum::http::{header, Request};
+  use axum::{routing, Router};
+  use tower::ServiceExt;
+  use tracing::{error, info, info_span};
+
+  use super::*;
+  use crate::config::{Log, Mock};
+  use crate::provider::{self, MockFailure, MockMode, ProviderError};
+  use crate::routes;
+  use crate::store::Store;
+
+  /// Stands for the text of a conversation.
+  const SECRET: &str = "hunter2";
+
+  /// Collects what's logged.
+  #[derive(Clone, Default)]
+  struct Capture(Arc<Mutex<Vec<u8>>>);
+
+  impl io::Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl<'w> MakeWriter<'w> for Capture {
+    type Writer = Self;
+
+    fn make_writer(&'w self) -> Self::Writer {
+      self.clone()
+    }
+  }
+
+  impl Capture {
+    fn logs(&self) -> String {
+      String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+  }
+
+  /// Runs `f` with everything logged in every format, and checks that the
+  /// secret never is.
+  async fn assert_redacted<F, Fut>(f: F)
+  where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+  {
+    for format in [Format::Text, Format::Json] {
+      let capture = Capture::default();
+      let cfg = Log {
+        filter: "trace".to_owned(),
+        format,
+      };
+      let _guard = tracing::subscriber::set_default(
+        tracing_subscriber::registry().with(layer(&cfg, capture.clone())),
+      );
+
+      f().await;
+
+      let logs = capture.logs();
+
+      assert!(!logs.is_empty(), "nothing was logged");
+      assert!(!logs.contains(SECRET), "the secret was logged:\n{logs}");
+    }
+  }
+
+  fn mock(mode: MockMode, failure: Option<MockFailure>) -> Arc<provider::Map> {
+    Arc::new(provider::mock(
+      Mock {
+        mode,
+        fixtures: PathBuf::new(),
+        latency: Duration::ZERO,
+        failure_rate: if failure.is_some() { 1.0 } else { 0.0 },
+        failure: failure.unwrap_or(MockFailure::Timeout),
+      },
+      0,
+    ))
+  }
+
+  async fn send(router: Router, req: Request<Body>) {
+    let res = router.oneshot(req).await.unwrap();
+
+    // interrupted replies fail
+    drop(hyper::body::to_bytes(res.into_body()).await);
+  }
+
+  fn ask(providers: Arc<provider::Map>) -> Router {
+    Router::new()
+      .route("/ask", routing::get(routes::ask).post(routes::ask))
+      .with_state(providers)
+  }
+
+  fn form(uri: &str, form: String) -> Request<Body> {
+    Request::post(uri)
+      .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+      .body(Body::from(form))
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn sensitive_fields_are_dropped() {
+    assert_redacted(|| async {
+      info!("logged");
+      info!(prompt = SECRET, "with a prompt");
+      info!(reply = %SECRET, "with a reply");
+
+      let span = info_span!("ask", state = SECRET);
+      span.in_scope(|| info!(content = SECRET));
+      info_span!("body", body = SECRET).in_scope(|| error!(messages = ?[SECRET]));
+    })
+    .await;
+  }
+
+  #[tokio::test]
+  async fn json_errors_are_described_without_their_input() {
+    assert_redacted(|| async {
+      let quoting = || serde_json::from_str::<u32>(&format!("\"{SECRET}\"")).unwrap_err();
+
+      assert!(quoting().to_string().contains(SECRET));
+
+      error!("{}", json_error(&quoting()));
+      error!("{}", ProviderError::malformed_json(&quoting()));
+      error!("{}", ProviderError::from(quoting()));
+    })
+    .await;
+  }
+
+  #[tokio::test]
+  async fn failed_prompts_are_not_logged() {
+    for failure in [
+      MockFailure::Unreachable,
+      MockFailure::Rejected,
+      MockFailure::RateLimited,
+      MockFailure::MalformedResponse,
+      MockFailure::Timeout,
+      MockFailure::ContentFiltered,
+      MockFailure::Interrupted,
+    ] {
+      assert_redacted(|| async {
+        let providers = mock(MockMode::Lorem, Some(failure));
+
+        send(
+          ask(providers.clone()),
+          Request::get(format!("/ask?provider=mock&prompt={SECRET}"))
+            .body(Body::empty())
+            .unwrap(),
+        )
+        .await;
+        send(
+          ask(providers.clone()),
+          form("/ask", format!("provider=mock&prompt={SECRET}")),
+        )
+        .await;
+        send(
+          Router::new()
+            .route("/summarize", routing::post(routes::summarize))
+            .with_state(providers),
+          Request::post("/summarize?provider=mock")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(
+              "{{\"messages\":[\"{SECRET}\",\"{SECRET}\"]}}"
+            )))
+            .unwrap(),
+        )
+        .await;
+      })
+      .await;
+    }
+  }
+
+  #[tokio::test]
+  async fn interrupted_replies_are_not_logged() {
+    assert_redacted(|| async {
+      send(
+        ask(mock(MockMode::Echo, Some(MockFailure::Interrupted))),
+        Request::get(format!("/ask?provider=mock&prompt=a+b+c+{SECRET}+{SECRET}"))
+          .body(Body::empty())
+          .unwrap(),
+      )
+      .await;
+    })
+    .await;
+  }
+
+  #[tokio::test]
+  async fn invalid_conversations_are_not_logged() {
+    assert_redacted(|| async {
+      let providers = mock(MockMode::Echo, None);
+
+      for state in [
+        format!("[{{\"role\":\"user\",\"content\":\"{SECRET}\"}}]"),
+        format!("[{{\"role\":\"{SECRET}\",\"content\":\"{SECRET}\"}}]"),
+      ] {
+        send(
+          ask(providers.clone()),
+          form(
+            "/ask",
+            format!(
+              "provider=mock&prompt={SECRET}&history={}",
+              urlencode(&state)
+            ),
+          ),
+        )
+        .await;
+      }
+
+      error!("done");
+    })
+    .await;
+  }
+
+  #[tokio::test]
+  async fn unreadable_shares_are_not_logged() {
+    assert_redacted(|| async {
+      let store = Store::open(&crate::config::Store {
+        path: ":memory:".into(),
+        max_conversations: 0,
+        max_shares: 0,
+      })
+      .unwrap();
+      let slug = store
+        .create_share(
+          "client:test",
+          // serde_json quotes the strings of unexpected types
+          format!("{{\"name\":\"\",\"messages\":\"{SECRET}\"}}").into_bytes(),
+          false,
+          None,
+        )
+        .await
+        .unwrap();
+
+      send(
+        Router::new()
+          .route("/s/:slug", routing::get(routes::render_share))
+          .with_state((store, String::new(), String::new())),
+        Request::get(format!("/s/{}", urlencode(&slug)))
+          .body(Body::empty())
+          .unwrap(),
+      )
+      .await;
+    })
+    .await;
+  }
+
+  fn urlencode(s: &str) -> String {
+    url::form_urlencoded::byte_serialize(s.as_bytes()).collect()
+  }
+}
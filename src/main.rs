@@ -1,23 +1,45 @@
 #[cfg(feature = "ssr")]
+mod config;
+#[cfg(feature = "ssr")]
+mod health;
+#[cfg(feature = "ssr")]
+mod logging;
+#[cfg(feature = "ssr")]
+mod metrics;
+#[cfg(feature = "ssr")]
+mod middleware;
+#[cfg(feature = "ssr")]
 mod provider;
 #[cfg(feature = "ssr")]
+mod proxy;
+#[cfg(feature = "ssr")]
 mod routes;
 #[cfg(feature = "ssr")]
+mod store;
+#[cfg(feature = "ssr")]
 mod util;
 
 #[cfg(feature = "hydration")]
 fn main() {
   wasm_logger::init(wasm_logger::Config::new(log::Level::Trace));
-  yew::Renderer::<libregpt::App>::new().hydrate();
+
+  // the server renders shared conversations in place of the app
+  if let Some(props) = libregpt::SharedProps::from_document() {
+    yew::Renderer::<libregpt::Shared>::with_props(props).hydrate();
+  } else {
+    yew::Renderer::<libregpt::App>::with_props(libregpt::AppProps::from_location()).hydrate();
+  }
 }
 
 #[cfg(feature = "ssr")]
 #[tokio::main]
 async fn main() {
+  use std::fs;
+  use std::net::SocketAddr;
   use std::sync::Arc;
-  use std::{env, fs};
 
-  use axum::handler::HandlerWithoutStateExt;
+  use axum::extract::DefaultBodyLimit;
+  use axum::handler::{Handler, HandlerWithoutStateExt};
   use axum::{routing, Router};
   use axum_server::tls_rustls::RustlsConfig;
   use tower::ServiceBuilder;
@@ -25,15 +47,18 @@ async fn main() {
   use tower_http::services::ServeDir;
   use tracing::{error, info};
 
-  tracing_subscriber::fmt::init();
-
-  let tls = env::args().any(|arg| arg == "--tls");
-  let port = match env::var("PORT").map_or(Ok(if tls { 443 } else { 80 }), |p| p.parse()) {
-    Ok(port) => port,
-    Err(err) => return error!("invalid port: {err}"),
+  let cfg = match config::Config::from_env() {
+    Ok(cfg) => cfg,
+    Err(err) => {
+      tracing_subscriber::fmt::init();
+      return error!("invalid config: {err}");
+    }
   };
 
+  logging::init(&cfg.log);
+
   let index_html = fs::read_to_string("dist/index.html").expect("failed to read index.html");
+  let security_headers = middleware::SecurityHeadersLayer::new(&index_html, cfg.tls);
   let (index_html_before, index_html_after) = index_html.split_once("<body>").unwrap();
 
   let mut index_html_before = index_html_before.to_owned();
@@ -47,26 +72,155 @@ async fn main() {
         .not_found_service(routes::default.into_service()),
     );
 
-  let render =
-    routing::get(routes::render).with_state((index_html_before, index_html_after.to_owned()));
+  let render = routing::get(routes::render)
+    .with_state((index_html_before.clone(), index_html_after.to_owned()));
+
+  let providers = Arc::new(provider::s(&cfg));
+  let rate_limit = middleware::RateLimitLayer::new(&cfg.rate_limit, providers.keys().copied());
+  let statuses = health::spawn(
+    providers.clone(),
+    cfg.health_check_interval,
+    cfg.health_check_paid,
+    cfg.summary_threshold,
+  );
+  // prompts are posted along their attachments and images
+  let ask = routing::get(routes::ask)
+    .post(routes::ask.layer(DefaultBodyLimit::max(
+      libregpt::attachments::MAX_PROMPT_SIZE,
+    )))
+    .layer(rate_limit.clone())
+    .with_state(providers.clone());
+  // extracting text doesn't reach the providers, but it's expensive
+  let attachment = routing::post(
+    routes::extract_attachment.layer(DefaultBodyLimit::max(libregpt::attachments::MAX_SIZE)),
+  )
+  .layer(middleware::RateLimitLayer::per_ip(
+    &cfg.rate_limit,
+    cfg.rate_limit.attachments_per_minute,
+  ))
+  .with_state(providers.clone());
+  let summarize = routing::post(routes::summarize)
+    .layer(rate_limit.clone())
+    .with_state(providers.clone());
+  let respond = routing::post(routes::respond)
+    .layer(rate_limit)
+    .with_state((
+      providers,
+      index_html_before.clone(),
+      index_html_after.to_owned(),
+    ));
+  let status = routing::get(routes::status).with_state(statuses);
+
+  let access_tokens = Arc::new(middleware::AccessTokens::new(&cfg.access_tokens));
+  // access tokens would be brute-forced otherwise
+  let login = routing::post(routes::login)
+    .layer(middleware::RateLimitLayer::per_ip(
+      &cfg.rate_limit,
+      cfg.rate_limit.login_per_minute,
+    ))
+    .with_state((access_tokens.clone(), cfg.tls));
+
+  let mut api = Router::new()
+    .route("/ask", ask)
+    .route("/summarize", summarize)
+    .route("/attachments", attachment)
+    .route("/respond/:id", respond)
+    .route("/usage", routing::get(routes::usage));
+  let mut public_api = Router::new()
+    .route("/login", login)
+    .route("/status", status);
+  let mut router = Router::new()
+    .route("/", render.clone())
+    .route("/c/:id", render);
+
+  if let Some(store_cfg) = &cfg.store {
+    let store = match store::Store::open(store_cfg) {
+      Ok(store) => store,
+      Err(err) => return error!("failed to open the store: {err:#}"),
+    };
+
+    let share = routing::get(routes::render_share).with_state((
+      store.clone(),
+      index_html_before,
+      index_html_after.to_owned(),
+    ));
+    // a single budget for every route writing to or reading from the database
+    let store_rate_limit =
+      middleware::RateLimitLayer::per_ip(&cfg.rate_limit, cfg.rate_limit.store_per_minute);
+
+    api = api
+      .route(
+        "/conversations",
+        routing::get(routes::list_conversations)
+          .layer(store_rate_limit.clone())
+          .with_state(store.clone()),
+      )
+      .route(
+        "/conversations/:id",
+        routing::get(routes::get_conversation)
+          .put(routes::put_conversation)
+          .delete(routes::delete_conversation)
+          .layer(store_rate_limit.clone())
+          .with_state(store.clone()),
+      )
+      .route(
+        "/shares",
+        routing::post(routes::create_share)
+          .layer(store_rate_limit.clone())
+          .with_state(store.clone()),
+      );
+    public_api = public_api.route(
+      "/shares/:slug",
+      routing::get(routes::get_share)
+        .layer(store_rate_limit)
+        .with_state(store),
+    );
+    router = router.route("/s/:slug", share);
+  }
 
-  let ask = routing::get(routes::ask).with_state(Arc::new(provider::s()));
+  let api = api
+    .layer(middleware::AuthLayer::new(access_tokens.clone()))
+    .merge(public_api);
 
-  let router = Router::new()
-    .route("/", render)
+  let mut router = router
     .nest_service("/pkg", serve_dist_dir)
-    .route("/api/ask", ask)
-    .fallback(routes::default);
+    .nest("/api", api);
+
+  if let Some(metrics_port) = cfg.metrics_port {
+    let addr = (cfg.metrics_addr, metrics_port).into();
+    let admin_router = Router::new().route("/metrics", routing::get(routes::metrics));
+
+    tokio::spawn(async move {
+      let server = axum::Server::bind(&addr).serve(admin_router.into_make_service());
+
+      info!("serving metrics on {addr}");
+
+      if let Err(err) = server.await {
+        error!("metrics server died: {err}");
+      }
+    });
+  } else if access_tokens.is_enabled() {
+    // the traffic of each provider is only shown to token holders
+    router = router.route(
+      "/metrics",
+      routing::get(routes::metrics).layer(middleware::AuthLayer::new(access_tokens)),
+    );
+  } else {
+    info!("not serving metrics, set METRICS_PORT or ACCESS_TOKENS to serve them");
+  }
+
+  let router = router.fallback(routes::default).layer(security_headers);
 
-  let addr = ([0, 0, 0, 0], port).into();
+  let addr = ([0, 0, 0, 0], cfg.port).into();
 
-  if tls {
+  if cfg.tls {
     let tls_cfg = match RustlsConfig::from_pem_file("cert.pem", "key.pem").await {
       Ok(cfg) => cfg,
       Err(err) => return error!("failed to read 'cert.pem' and/or 'key.pem': {err}"),
     };
 
-    let server = axum_server::bind_rustls(addr, tls_cfg).serve(router.into_make_service());
+    let server = axum_server::bind_rustls(addr, tls_cfg)
+      .serve(router.into_make_service_with_connect_info::<SocketAddr>());
 
     info!("listening on {addr}");
 
@@ -74,7 +228,8 @@ async fn main() {
       error!("server died: {err}");
     }
   } else {
-    let server = axum::Server::bind(&addr).serve(router.into_make_service());
+    let server =
+      axum::Server::bind(&addr).serve(router.into_make_service_with_connect_info::<SocketAddr>());
 
     info!("listening on {addr}");
 
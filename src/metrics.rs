@@ -0,0 +1,196 @@
+use std::io::Error as IoError;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use futures::Stream;
+use hyper::body::Bytes;
+use pin_project::{pin_project, pinned_drop};
+use prometheus::{
+  Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+const LATENCY_BUCKETS: &[f64] = &[
+  0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+pub struct Metrics {
+  registry: Registry,
+  pub requests: IntCounterVec,
+  pub errors: IntCounterVec,
+  pub time_to_first_token: HistogramVec,
+  pub stream_duration: HistogramVec,
+  pub bytes_streamed: IntCounterVec,
+  pub active_streams: IntGaugeVec,
+}
+
+impl Metrics {
+  fn new() -> Self {
+    let requests = IntCounterVec::new(
+      Opts::new("libregpt_requests_total", "Prompts sent to a provider."),
+      &["provider"],
+    )
+    .unwrap();
+    let errors = IntCounterVec::new(
+      Opts::new(
+        "libregpt_errors_total",
+        "Provider failures, before (ask) or while (stream) replying, or summarizing (summarize).",
+      ),
+      &["provider", "stage"],
+    )
+    .unwrap();
+    let time_to_first_token = HistogramVec::new(
+      HistogramOpts::new(
+        "libregpt_time_to_first_token_seconds",
+        "Time between receiving a prompt and streaming the first bytes of the reply.",
+      )
+      .buckets(LATENCY_BUCKETS.to_vec()),
+      &["provider"],
+    )
+    .unwrap();
+    let stream_duration = HistogramVec::new(
+      HistogramOpts::new(
+        "libregpt_stream_duration_seconds",
+        "Time between receiving a prompt and the end of the reply stream.",
+      )
+      .buckets(LATENCY_BUCKETS.to_vec()),
+      &["provider"],
+    )
+    .unwrap();
+    let bytes_streamed = IntCounterVec::new(
+      Opts::new(
+        "libregpt_streamed_bytes_total",
+        "Reply bytes streamed to clients.",
+      ),
+      &["provider"],
+    )
+    .unwrap();
+    let active_streams = IntGaugeVec::new(
+      Opts::new(
+        "libregpt_active_streams",
+        "Replies currently being streamed.",
+      ),
+      &["provider"],
+    )
+    .unwrap();
+
+    let registry = Registry::new();
+    registry.register(Box::new(requests.clone())).unwrap();
+    registry.register(Box::new(errors.clone())).unwrap();
+    registry
+      .register(Box::new(time_to_first_token.clone()))
+      .unwrap();
+    registry
+      .register(Box::new(stream_duration.clone()))
+      .unwrap();
+    registry.register(Box::new(bytes_streamed.clone())).unwrap();
+    registry.register(Box::new(active_streams.clone())).unwrap();
+
+    Self {
+      registry,
+      requests,
+      errors,
+      time_to_first_token,
+      stream_duration,
+      bytes_streamed,
+      active_streams,
+    }
+  }
+
+  /// Renders every metric in the Prometheus text format.
+  pub fn encode(&self) -> String {
+    let mut buf = Vec::new();
+
+    TextEncoder::new()
+      .encode(&self.registry.gather(), &mut buf)
+      .unwrap();
+
+    String::from_utf8(buf).unwrap()
+  }
+
+  pub fn stream_error(&self, provider: &str) {
+    self.errors.with_label_values(&[provider, "stream"]).inc();
+  }
+}
+
+pub fn get() -> &'static Metrics {
+  static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+  METRICS.get_or_init(Metrics::new)
+}
+
+/// Records the timings and size of a reply stream.
+#[pin_project(PinnedDrop)]
+pub struct MeteredStream<S> {
+  #[pin]
+  inner: S,
+  provider: &'static str,
+  started_at: Instant,
+  first_chunk: bool,
+}
+
+impl<S> MeteredStream<S> {
+  /// `started_at` is when the prompt was received.
+  pub fn new(inner: S, provider: &'static str, started_at: Instant) -> Self {
+    get().active_streams.with_label_values(&[provider]).inc();
+
+    Self {
+      inner,
+      provider,
+      started_at,
+      first_chunk: true,
+    }
+  }
+}
+
+impl<S> Stream for MeteredStream<S>
+where
+  S: Stream<Item = Result<Bytes, IoError>>,
+{
+  type Item = S::Item;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    let this = self.project();
+    let item = futures::ready!(this.inner.poll_next(cx));
+
+    match item.as_ref() {
+      Some(Ok(chunk)) => {
+        let metrics = get();
+
+        if *this.first_chunk {
+          *this.first_chunk = false;
+          metrics
+            .time_to_first_token
+            .with_label_values(&[this.provider])
+            .observe(this.started_at.elapsed().as_secs_f64());
+        }
+
+        metrics
+          .bytes_streamed
+          .with_label_values(&[this.provider])
+          .inc_by(chunk.len() as u64);
+      }
+      Some(Err(_)) => get().stream_error(this.provider),
+      None => {}
+    }
+
+    Poll::Ready(item)
+  }
+}
+
+#[pinned_drop]
+impl<S> PinnedDrop for MeteredStream<S> {
+  fn drop(self: Pin<&mut Self>) {
+    let metrics = get();
+
+    metrics
+      .active_streams
+      .with_label_values(&[self.provider])
+      .dec();
+    metrics
+      .stream_duration
+      .with_label_values(&[self.provider])
+      .observe(self.started_at.elapsed().as_secs_f64());
+  }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::async_trait;
+use axum::body::{self, BoxBody, Bytes};
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, Request, Response, StatusCode};
+use axum::response::IntoResponse;
+use futures::Future;
+use hyper::body::HttpBody;
+use pin_project::pin_project;
+use sha2::{Digest, Sha256};
+use tower::{Layer, Service};
+use uuid::Uuid;
+
+pub const TOKEN_COOKIE: &str = "token";
+
+/// Sent by anonymous clients, with the id they generated for themselves.
+pub const CLIENT_ID_HEADER: &str = "client-id";
+
+/// Requires a valid access token, either as a bearer token or in the `token`
+/// cookie, when at least one is configured.
+#[derive(Clone)]
+pub struct AuthLayer {
+  tokens: Arc<AccessTokens>,
+}
+
+impl AuthLayer {
+  pub fn new(tokens: Arc<AccessTokens>) -> Self {
+    Self { tokens }
+  }
+}
+
+impl<S> Layer<S> for AuthLayer {
+  type Service = Auth<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    Auth {
+      inner,
+      tokens: self.tokens.clone(),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct Auth<S> {
+  inner: S,
+  tokens: Arc<AccessTokens>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Auth<S>
+where
+  S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+  ResBody: HttpBody<Data = Bytes> + Send + 'static,
+  ResBody::Error: Into<axum::BoxError>,
+{
+  type Error = S::Error;
+  type Future = ResponseFuture<S::Future>;
+  type Response = Response<BoxBody>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+    if self.tokens.is_enabled() {
+      match request_token(req.headers())
+        .and_then(|token| Some((self.tokens.usage(token)?, Identity::token(token))))
+      {
+        Some((usage, identity)) => {
+          req.extensions_mut().insert(usage);
+          req.extensions_mut().insert(identity);
+        }
+        None => return ResponseFuture::Unauthorized,
+      }
+    }
+
+    ResponseFuture::Authorized {
+      inner: self.inner.call(req),
+    }
+  }
+}
+
+#[pin_project(project = ResponseFutureProj)]
+pub enum ResponseFuture<F> {
+  Unauthorized,
+  Authorized {
+    #[pin]
+    inner: F,
+  },
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+  F: Future<Output = Result<Response<B>, E>>,
+  B: HttpBody<Data = Bytes> + Send + 'static,
+  B::Error: Into<axum::BoxError>,
+{
+  type Output = Result<Response<BoxBody>, E>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    match self.project() {
+      ResponseFutureProj::Unauthorized => Poll::Ready(Ok(unauthorized())),
+      ResponseFutureProj::Authorized { inner } => {
+        let res = futures::ready!(inner.poll(cx))?;
+
+        Poll::Ready(Ok(res.map(body::boxed)))
+      }
+    }
+  }
+}
+
+/// The configured access tokens and how many prompts were sent with each of
+/// them.
+pub struct AccessTokens {
+  /// By the SHA-256 digest of the tokens.
+  inner: HashMap<[u8; 32], KeyUsage>,
+}
+
+impl AccessTokens {
+  pub fn new(tokens: &[String]) -> Self {
+    Self {
+      inner: tokens
+        .iter()
+        .map(|token| (Sha256::digest(token).into(), KeyUsage::default()))
+        .collect(),
+    }
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    !self.inner.is_empty()
+  }
+
+  /// Returns the usage counter of `token` if it's valid.
+  pub fn usage(&self, token: &str) -> Option<KeyUsage> {
+    // compare the digests against every token in constant time to leak
+    // neither valid prefixes nor lengths
+    let digest: [u8; 32] = Sha256::digest(token).into();
+
+    self
+      .inner
+      .iter()
+      .fold(None, |found, (valid, usage)| {
+        if constant_time_eq(valid, &digest) {
+          Some(usage)
+        } else {
+          found
+        }
+      })
+      .cloned()
+  }
+}
+
+#[derive(Clone, Default)]
+pub struct KeyUsage(Arc<AtomicU64>);
+
+impl KeyUsage {
+  pub fn record(&self) {
+    self.0.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn requests(&self) -> u64 {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+/// Who a request comes from, the holder of an access token or else an
+/// anonymous client.
+#[derive(Clone)]
+pub struct Identity(String);
+
+impl Identity {
+  /// Hashed so that the access tokens aren't stored alongside the data they
+  /// give access to.
+  fn token(token: &str) -> Self {
+    Self(format!("token:{}", hex::encode(Sha256::digest(token))))
+  }
+
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Identity
+where
+  S: Send + Sync,
+{
+  type Rejection = (StatusCode, &'static str);
+
+  async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+    // set by the auth middleware when access tokens are required
+    if let Some(identity) = parts.extensions.get::<Self>() {
+      return Ok(identity.clone());
+    }
+
+    parts
+      .headers
+      .get(CLIENT_ID_HEADER)
+      .and_then(|value| value.to_str().ok())
+      .and_then(|value| Uuid::parse_str(value).ok())
+      .map(|id| Self(format!("client:{id}")))
+      .ok_or((StatusCode::BAD_REQUEST, "missing or invalid client id"))
+  }
+}
+
+/// Returns the bearer token, falling back to the `token` cookie.
+fn request_token(headers: &HeaderMap) -> Option<&str> {
+  if let Some(token) = headers
+    .get(header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "))
+  {
+    return Some(token.trim());
+  }
+
+  headers
+    .get_all(header::COOKIE)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(';'))
+    .find_map(|cookie| {
+      let (name, value) = cookie.trim().split_once('=')?;
+
+      (name == TOKEN_COOKIE).then_some(value)
+    })
+}
+
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+  a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn unauthorized() -> Response<BoxBody> {
+  (
+    StatusCode::UNAUTHORIZED,
+    [(header::WWW_AUTHENTICATE, "Bearer")],
+    "missing or invalid access token",
+  )
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+  use axum::body::Body;
+  use axum::{routing, Router};
+  use tower::ServiceExt;
+
+  use super::*;
+
+  const CLIENT_ID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+  fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+    pairs
+      .iter()
+      .map(|(name, value)| (name.clone(), value.parse().unwrap()))
+      .collect()
+  }
+
+  /// Replies with the identity of the request.
+  fn router(tokens: &[&str]) -> Router {
+    let tokens = tokens
+      .iter()
+      .map(|&token| token.to_owned())
+      .collect::<Vec<_>>();
+
+    Router::new()
+      .route(
+        "/",
+        routing::get(|identity: Identity| async move { identity.as_str().to_owned() }),
+      )
+      .layer(AuthLayer::new(Arc::new(AccessTokens::new(&tokens))))
+  }
+
+  async fn get(router: &Router, pairs: &[(header::HeaderName, &str)]) -> (StatusCode, String) {
+    let mut req = Request::get("/").body(Body::empty()).unwrap();
+    *req.headers_mut() = headers(pairs);
+
+    let res = router.clone().oneshot(req).await.unwrap();
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+    (status, String::from_utf8(body.to_vec()).unwrap())
+  }
+
+  #[test]
+  fn tokens_are_read_from_bearer_or_cookie() {
+    assert_eq!(
+      request_token(&headers(&[(header::AUTHORIZATION, "Bearer secret ")])),
+      Some("secret")
+    );
+    assert_eq!(
+      request_token(&headers(&[(header::COOKIE, "theme=dark; token=secret")])),
+      Some("secret")
+    );
+    assert_eq!(
+      request_token(&headers(&[
+        (header::AUTHORIZATION, "Bearer first"),
+        (header::COOKIE, "token=second"),
+      ])),
+      Some("first")
+    );
+    assert_eq!(
+      request_token(&headers(&[
+        (header::AUTHORIZATION, "Basic c2VjcmV0"),
+        (header::COOKIE, "mytoken=secret"),
+      ])),
+      None
+    );
+  }
+
+  #[tokio::test]
+  async fn invalid_tokens_are_unauthorized() {
+    let router = router(&["secret"]);
+
+    // the client asks for a token when it gets a 401
+    for pairs in [
+      &[][..],
+      &[(header::AUTHORIZATION, "Bearer wrong")],
+      &[(header::COOKIE, "token=secre")],
+    ] {
+      let mut req = Request::get("/").body(Body::empty()).unwrap();
+      *req.headers_mut() = headers(pairs);
+      let res = router.clone().oneshot(req).await.unwrap();
+
+      assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+      assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    }
+  }
+
+  #[tokio::test]
+  async fn token_holders_are_identified_by_digest() {
+    let router = router(&["secret", "other"]);
+    let expected = format!("token:{}", hex::encode(Sha256::digest("secret")));
+
+    assert_eq!(
+      get(&router, &[(header::AUTHORIZATION, "Bearer secret")]).await,
+      (StatusCode::OK, expected.clone())
+    );
+    // the client id can't stand for a token holder
+    assert_eq!(
+      get(
+        &router,
+        &[
+          (header::COOKIE, "token=secret"),
+          (header::HeaderName::from_static(CLIENT_ID_HEADER), CLIENT_ID),
+        ]
+      )
+      .await,
+      (StatusCode::OK, expected)
+    );
+  }
+
+  #[tokio::test]
+  async fn anonymous_clients_are_identified_by_id() {
+    let router = router(&[]);
+
+    assert_eq!(
+      get(
+        &router,
+        &[(header::HeaderName::from_static(CLIENT_ID_HEADER), CLIENT_ID)]
+      )
+      .await,
+      (StatusCode::OK, format!("client:{CLIENT_ID}"))
+    );
+
+    for pairs in [
+      &[][..],
+      &[(
+        header::HeaderName::from_static(CLIENT_ID_HEADER),
+        "not-a-uuid",
+      )],
+      &[(header::AUTHORIZATION, "Bearer secret")],
+    ] {
+      assert_eq!(get(&router, pairs).await.0, StatusCode::BAD_REQUEST);
+    }
+  }
+
+  #[tokio::test]
+  async fn usage_is_counted_per_token() {
+    let tokens = AccessTokens::new(&["secret".to_owned()]);
+
+    tokens.usage("secret").unwrap().record();
+    tokens.usage("secret").unwrap().record();
+
+    assert_eq!(tokens.usage("secret").unwrap().requests(), 2);
+    assert!(tokens.usage("secret ").is_none());
+  }
+}
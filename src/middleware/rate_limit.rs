@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::body::{self, Body, BoxBody, Bytes};
+use axum::extract::ConnectInfo;
+use axum::http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use axum::response::IntoResponse;
+use futures::future::BoxFuture;
+use futures::Future;
+use hyper::body::HttpBody;
+use libregpt::attachments::MAX_PROMPT_SIZE;
+use pin_project::pin_project;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tower::{Layer, Service};
+use url::form_urlencoded;
+
+use crate::config;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits the requests made by each client IP with a token bucket and caps the
+/// number of concurrent requests, globally and per provider. The provider is
+/// read from the `provider` param of the query, or of the body of posted forms.
+///
+/// Concurrency permits are held until the response body has been fully
+/// streamed.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+  state: Arc<State>,
+}
+
+struct State {
+  buckets: Option<Buckets>,
+  trusted_proxies: Vec<IpAddr>,
+  global: Option<Arc<Semaphore>>,
+  per_provider: HashMap<&'static str, Arc<Semaphore>>,
+}
+
+struct Buckets {
+  capacity: f64,
+  /// Tokens per second.
+  refill_rate: f64,
+  inner: Mutex<(HashMap<IpAddr, Bucket>, Instant)>,
+}
+
+struct Bucket {
+  tokens: f64,
+  updated_at: Instant,
+}
+
+impl RateLimitLayer {
+  pub fn new(cfg: &config::RateLimit, providers: impl Iterator<Item = &'static str>) -> Self {
+    Self {
+      state: Arc::new(State {
+        buckets: Buckets::new(cfg.burst, cfg.per_minute),
+        trusted_proxies: cfg.trusted_proxies.clone(),
+        global: (cfg.max_concurrent > 0).then(|| Arc::new(Semaphore::new(cfg.max_concurrent))),
+        per_provider: if cfg.max_concurrent_per_provider > 0 {
+          providers
+            .map(|name| {
+              (
+                name,
+                Arc::new(Semaphore::new(cfg.max_concurrent_per_provider)),
+              )
+            })
+            .collect()
+        } else {
+          HashMap::new()
+        },
+      }),
+    }
+  }
+
+  /// Only limits the requests made by each client IP, `per_minute` of them at
+  /// most and in a row.
+  pub fn per_ip(cfg: &config::RateLimit, per_minute: u32) -> Self {
+    Self {
+      state: Arc::new(State {
+        buckets: Buckets::new(per_minute, per_minute),
+        trusted_proxies: cfg.trusted_proxies.clone(),
+        global: None,
+        per_provider: HashMap::new(),
+      }),
+    }
+  }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+  type Service = RateLimit<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    RateLimit {
+      inner,
+      state: self.state.clone(),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+  inner: S,
+  state: Arc<State>,
+}
+
+impl<S, ResBody> Service<Request<Body>> for RateLimit<S>
+where
+  S: Service<Request<Body>, Response = Response<ResBody>> + Clone + Send + 'static,
+  S::Future: Send,
+  ResBody: HttpBody<Data = Bytes> + Send + 'static,
+  ResBody::Error: Into<axum::BoxError>,
+{
+  type Error = S::Error;
+  type Future = ResponseFuture<S::Future, S::Error>;
+  type Response = Response<BoxBody>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: Request<Body>) -> Self::Future {
+    if let Some(buckets) = self.state.buckets.as_ref() {
+      let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| client_ip(info.0.ip(), req.headers(), &self.state.trusted_proxies));
+
+      if let Some(Err(retry_after)) = ip.map(|ip| buckets.take(ip)) {
+        return ResponseFuture::Limited(Some(too_many_requests(retry_after)));
+      }
+    }
+
+    // the provider of a posted form is in its body, which is read first
+    if !self.state.per_provider.is_empty() && is_form(&req) {
+      let state = self.state.clone();
+      let clone = self.inner.clone();
+      let mut inner = mem::replace(&mut self.inner, clone);
+
+      return ResponseFuture::Buffered(Box::pin(async move {
+        let (parts, body) = req.into_parts();
+        let form = match read_form(body).await {
+          Ok(form) => form,
+          Err(res) => return Ok(res),
+        };
+        let Some(permits) = state.permits(provider_param(&form).as_deref()) else {
+          return Ok(too_many_requests(1));
+        };
+        let res = inner
+          .call(Request::from_parts(parts, Body::from(form)))
+          .await?;
+
+        Ok(res.map(|body| {
+          body::boxed(PermitBody {
+            body,
+            _permits: permits,
+          })
+        }))
+      }));
+    }
+
+    let query = req.uri().query().unwrap_or_default().as_bytes();
+
+    match self.state.permits(provider_param(query).as_deref()) {
+      Some(permits) => ResponseFuture::Allowed {
+        inner: self.inner.call(req),
+        permits: Some(permits),
+      },
+      None => ResponseFuture::Limited(Some(too_many_requests(1))),
+    }
+  }
+}
+
+impl State {
+  /// Takes a permit of the global cap and one of the cap of `provider`,
+  /// `None` if either is reached.
+  fn permits(&self, provider: Option<&str>) -> Option<Vec<OwnedSemaphorePermit>> {
+    let mut permits = Vec::with_capacity(2);
+
+    if let Some(global) = self.global.as_ref() {
+      permits.push(global.clone().try_acquire_owned().ok()?);
+    }
+
+    if let Some(semaphore) = provider.and_then(|provider| self.per_provider.get(provider)) {
+      permits.push(semaphore.clone().try_acquire_owned().ok()?);
+    }
+
+    Some(permits)
+  }
+}
+
+#[pin_project(project = ResponseFutureProj)]
+pub enum ResponseFuture<F, E> {
+  Limited(Option<Response<BoxBody>>),
+  Allowed {
+    #[pin]
+    inner: F,
+    permits: Option<Vec<OwnedSemaphorePermit>>,
+  },
+  Buffered(BoxFuture<'static, Result<Response<BoxBody>, E>>),
+}
+
+impl<F, B, E> Future for ResponseFuture<F, E>
+where
+  F: Future<Output = Result<Response<B>, E>>,
+  B: HttpBody<Data = Bytes> + Send + 'static,
+  B::Error: Into<axum::BoxError>,
+{
+  type Output = Result<Response<BoxBody>, E>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    match self.project() {
+      ResponseFutureProj::Limited(res) => Poll::Ready(Ok(res.take().unwrap())),
+      ResponseFutureProj::Allowed { inner, permits } => {
+        let res = futures::ready!(inner.poll(cx))?;
+        let permits = permits.take().unwrap();
+
+        Poll::Ready(Ok(res.map(|body| {
+          body::boxed(PermitBody {
+            body,
+            _permits: permits,
+          })
+        })))
+      }
+      ResponseFutureProj::Buffered(future) => future.as_mut().poll(cx),
+    }
+  }
+}
+
+/// Holds the concurrency permits while the body is being streamed.
+#[pin_project]
+struct PermitBody<B> {
+  #[pin]
+  body: B,
+  _permits: Vec<OwnedSemaphorePermit>,
+}
+
+impl<B: HttpBody> HttpBody for PermitBody<B> {
+  type Data = B::Data;
+  type Error = B::Error;
+
+  fn poll_data(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+    self.project().body.poll_data(cx)
+  }
+
+  fn poll_trailers(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+    self.project().body.poll_trailers(cx)
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.body.is_end_stream()
+  }
+
+  fn size_hint(&self) -> hyper::body::SizeHint {
+    self.body.size_hint()
+  }
+}
+
+impl Buckets {
+  /// `None` if either is 0.
+  fn new(burst: u32, per_minute: u32) -> Option<Self> {
+    (burst > 0 && per_minute > 0).then(|| Self {
+      capacity: burst as f64,
+      refill_rate: per_minute as f64 / 60.0,
+      inner: Mutex::new((HashMap::new(), Instant::now())),
+    })
+  }
+
+  /// Takes a token from the bucket of `ip`, returns the number of seconds to
+  /// wait before retrying if it's empty.
+  fn take(&self, ip: IpAddr) -> Result<(), u64> {
+    let now = Instant::now();
+    let mut inner = self.inner.lock().unwrap();
+    let (buckets, pruned_at) = &mut *inner;
+
+    if now.duration_since(*pruned_at) >= PRUNE_INTERVAL {
+      buckets.retain(|_, bucket| self.refill(bucket, now) < self.capacity);
+      *pruned_at = now;
+    }
+
+    let bucket = buckets.entry(ip).or_insert(Bucket {
+      tokens: self.capacity,
+      updated_at: now,
+    });
+
+    if self.refill(bucket, now) >= 1.0 {
+      bucket.tokens -= 1.0;
+      Ok(())
+    } else {
+      Err(((1.0 - bucket.tokens) / self.refill_rate).ceil() as u64)
+    }
+  }
+
+  fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+    let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+
+    bucket.tokens = (bucket.tokens + elapsed * self.refill_rate).min(self.capacity);
+    bucket.updated_at = now;
+
+    bucket.tokens
+  }
+}
+
+/// Returns the right-most `X-Forwarded-For` address that isn't a trusted proxy
+/// when the peer is one, the peer address otherwise.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+  if !trusted_proxies.contains(&peer) {
+    return peer;
+  }
+
+  headers
+    .get_all("x-forwarded-for")
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .rev()
+    .filter_map(|addr| addr.trim().parse::<IpAddr>().ok())
+    .find(|addr| !trusted_proxies.contains(addr))
+    .unwrap_or(peer)
+}
+
+fn provider_param(form: &[u8]) -> Option<String> {
+  form_urlencoded::parse(form)
+    .find(|(key, _)| key == "provider")
+    .map(|(_, value)| value.into_owned())
+}
+
+fn is_form(req: &Request<Body>) -> bool {
+  req.method() == Method::POST
+    && req
+      .headers()
+      .get(header::CONTENT_TYPE)
+      .and_then(|value| value.to_str().ok())
+      .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"))
+}
+
+/// Reads a posted form, up to the size of the largest prompt.
+async fn read_form(mut body: Body) -> Result<Bytes, Response<BoxBody>> {
+  let mut form = Vec::new();
+
+  while let Some(chunk) = body.data().await {
+    let chunk =
+      chunk.map_err(|_| (StatusCode::BAD_REQUEST, "failed to read the form").into_response())?;
+
+    if form.len() + chunk.len() > MAX_PROMPT_SIZE {
+      return Err((StatusCode::PAYLOAD_TOO_LARGE, "form too large").into_response());
+    }
+
+    form.extend_from_slice(&chunk);
+  }
+
+  Ok(form.into())
+}
+
+fn too_many_requests(retry_after: u64) -> Response<BoxBody> {
+  let mut buf = itoa::Buffer::new();
+
+  (
+    StatusCode::TOO_MANY_REQUESTS,
+    [(
+      header::RETRY_AFTER,
+      HeaderValue::from_str(buf.format(retry_after.max(1))).unwrap(),
+    )],
+    "too many requests",
+  )
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::convert::Infallible;
+
+  use axum::body::StreamBody;
+  use axum::{routing, Form, Router};
+  use futures::future;
+  use futures::stream::{self, StreamExt};
+  use serde::Deserialize;
+  use tower::ServiceExt;
+
+  use super::*;
+
+  #[derive(Deserialize)]
+  struct Params {
+    provider: String,
+  }
+
+  /// Echoes the provider in a reply that's never done streaming, so that its
+  /// permits are held.
+  async fn ask(Form(params): Form<Params>) -> impl IntoResponse {
+    StreamBody::new(
+      stream::once(future::ready(Ok::<_, Infallible>(params.provider))).chain(stream::pending()),
+    )
+  }
+
+  fn router() -> Router {
+    let cfg = config::RateLimit {
+      burst: 0,
+      per_minute: 0,
+      trusted_proxies: Vec::new(),
+      max_concurrent: 0,
+      max_concurrent_per_provider: 1,
+      login_per_minute: 0,
+      store_per_minute: 0,
+      attachments_per_minute: 0,
+    };
+
+    Router::new().route(
+      "/ask",
+      routing::get(ask)
+        .post(ask)
+        .layer(RateLimitLayer::new(&cfg, ["a", "b"].into_iter())),
+    )
+  }
+
+  fn post(provider: &str) -> Request<Body> {
+    // the query is ignored, as by the handlers reading the form
+    Request::post("/ask?provider=b")
+      .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+      .body(Body::from(format!("provider={provider}")))
+      .unwrap()
+  }
+
+  fn get(provider: &str) -> Request<Body> {
+    Request::get(format!("/ask?provider={provider}"))
+      .body(Body::empty())
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn posted_providers_are_capped() {
+    let router = router();
+    let mut first = router.clone().oneshot(post("a")).await.unwrap();
+
+    assert_eq!(first.status(), StatusCode::OK);
+    // the form is passed on once read
+    assert_eq!(first.body_mut().data().await.unwrap().unwrap(), "a");
+
+    for req in [post("a"), get("a")] {
+      let res = router.clone().oneshot(req).await.unwrap();
+
+      assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let res = router.clone().oneshot(post("b")).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    drop(first);
+
+    let res = router.oneshot(post("a")).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn queried_providers_are_capped() {
+    let router = router();
+    let first = router.clone().oneshot(get("a")).await.unwrap();
+
+    assert_eq!(first.status(), StatusCode::OK);
+
+    for req in [get("a"), post("a")] {
+      let res = router.clone().oneshot(req).await.unwrap();
+
+      assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+  }
+}
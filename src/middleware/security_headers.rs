@@ -0,0 +1,263 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::http::{header, HeaderMap, HeaderValue, Request, Response};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::Future;
+use pin_project::pin_project;
+use sha2::{Digest, Sha256};
+use tower::{Layer, Service};
+
+/// Serves the Inter font imported by `tailwind.css`.
+const FONT_ORIGIN: &str = "https://fontlay.com";
+
+const PERMISSIONS_POLICY: &str = "accelerometer=(), browsing-topics=(), camera=(), display-capture=(), geolocation=(), gyroscope=(), interest-cohort=(), magnetometer=(), microphone=(), payment=(), usb=()";
+
+const STRICT_TRANSPORT_SECURITY: &str = "max-age=63072000; includeSubDomains";
+
+/// Adds a strict CSP and privacy-hardening headers to every response.
+#[derive(Clone)]
+pub struct SecurityHeadersLayer {
+  headers: Arc<HeaderMap>,
+}
+
+impl SecurityHeadersLayer {
+  /// The inline scripts of `index_html` are whitelisted in the CSP by their
+  /// SHA-256 hash, HSTS is only sent when `tls` is on.
+  pub fn new(index_html: &str, tls: bool) -> Self {
+    let mut headers = HeaderMap::with_capacity(5);
+
+    headers.insert(
+      header::CONTENT_SECURITY_POLICY,
+      HeaderValue::try_from(content_security_policy(index_html)).unwrap(),
+    );
+    headers.insert(
+      header::REFERRER_POLICY,
+      HeaderValue::from_static("no-referrer"),
+    );
+    headers.insert(
+      "permissions-policy",
+      HeaderValue::from_static(PERMISSIONS_POLICY),
+    );
+    headers.insert(
+      header::X_CONTENT_TYPE_OPTIONS,
+      HeaderValue::from_static("nosniff"),
+    );
+
+    if tls {
+      headers.insert(
+        header::STRICT_TRANSPORT_SECURITY,
+        HeaderValue::from_static(STRICT_TRANSPORT_SECURITY),
+      );
+    }
+
+    Self {
+      headers: Arc::new(headers),
+    }
+  }
+}
+
+impl<S> Layer<S> for SecurityHeadersLayer {
+  type Service = SecurityHeaders<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    SecurityHeaders {
+      inner,
+      headers: self.headers.clone(),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct SecurityHeaders<S> {
+  inner: S,
+  headers: Arc<HeaderMap>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for SecurityHeaders<S>
+where
+  S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+{
+  type Error = S::Error;
+  type Future = ResponseFuture<S::Future>;
+  type Response = S::Response;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+    ResponseFuture {
+      inner: self.inner.call(req),
+      headers: self.headers.clone(),
+    }
+  }
+}
+
+#[pin_project]
+pub struct ResponseFuture<F> {
+  #[pin]
+  inner: F,
+  headers: Arc<HeaderMap>,
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+  F: Future<Output = Result<Response<B>, E>>,
+{
+  type Output = F::Output;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    let this = self.project();
+    let mut res = futures::ready!(this.inner.poll(cx))?;
+    let res_headers = res.headers_mut();
+
+    for (name, value) in this.headers.iter() {
+      res_headers.insert(name, value.clone());
+    }
+
+    Poll::Ready(Ok(res))
+  }
+}
+
+fn content_security_policy(index_html: &str) -> String {
+  let mut script_src = String::from("'self' 'wasm-unsafe-eval'");
+
+  for script in inline_scripts(index_html) {
+    script_src.push_str(" 'sha256-");
+    script_src.push_str(&BASE64.encode(Sha256::digest(script)));
+    script_src.push('\'');
+  }
+
+  format!(
+    "default-src 'none'; script-src {script_src}; style-src 'self' {FONT_ORIGIN}; font-src {FONT_ORIGIN}; img-src 'self' blob: data:; connect-src 'self'; base-uri 'none'; form-action 'self'; frame-ancestors 'none'"
+  )
+}
+
+/// Returns the content of every `<script>` tag without a `src` attribute.
+fn inline_scripts(html: &str) -> impl Iterator<Item = &str> {
+  let mut rest = html;
+
+  std::iter::from_fn(move || loop {
+    let start = rest.find("<script")?;
+    let attrs_end = start + rest[start..].find('>')?;
+    let attrs = &rest[start + 7..attrs_end];
+    let content_end = attrs_end + rest[attrs_end..].find("</script>")?;
+    let content = &rest[attrs_end + 1..content_end];
+
+    rest = &rest[content_end + 9..];
+
+    if !attrs.contains("src=") {
+      return Some(content);
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::Path;
+  use std::sync::Arc;
+
+  use axum::body::Body;
+  use axum::http::{Request, StatusCode};
+  use axum::{routing, Router};
+  use tower::ServiceExt;
+  use tower_http::services::ServeDir;
+
+  use super::*;
+  use crate::util::temp_dir;
+  use crate::{provider, routes};
+
+  const INDEX_HTML: &str = "<html><head><script src=\"/pkg/app.js\"></script><script>setTheme()</script></head><body></body></html>";
+
+  fn router(tls: bool, dist: &Path) -> Router {
+    Router::new()
+      .route(
+        "/",
+        routing::get(routes::render)
+          .with_state(("<html><body>".to_owned(), "</body></html>".to_owned())),
+      )
+      .nest_service("/pkg", ServeDir::new(dist))
+      .route(
+        "/api/ask",
+        routing::get(routes::ask).with_state(Arc::new(provider::Map::new())),
+      )
+      .layer(SecurityHeadersLayer::new(INDEX_HTML, tls))
+  }
+
+  async fn headers(router: Router, uri: &str, status: StatusCode) -> HeaderMap {
+    let res = router
+      .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+      .await
+      .unwrap();
+
+    assert_eq!(res.status(), status, "{uri}");
+
+    res.headers().clone()
+  }
+
+  #[tokio::test]
+  async fn headers_are_set_on_every_route() {
+    let script_hash = BASE64.encode(Sha256::digest("setTheme()"));
+    let dist = temp_dir(&[("app.js", "")]);
+
+    for (uri, status) in [
+      ("/", StatusCode::OK),
+      ("/pkg/app.js", StatusCode::OK),
+      // errors too
+      ("/api/ask?provider=none&prompt=hi", StatusCode::BAD_REQUEST),
+    ] {
+      let headers = headers(router(false, dist.path()), uri, status).await;
+      let csp = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+
+      assert!(csp.starts_with("default-src 'none'; "), "{uri}: {csp}");
+      assert!(
+        csp.contains(&format!(
+          "script-src 'self' 'wasm-unsafe-eval' 'sha256-{script_hash}';"
+        )),
+        "{uri}: {csp}"
+      );
+      assert!(csp.contains("frame-ancestors 'none'"), "{uri}: {csp}");
+      // attached images are loaded from blobs and previewed as data URLs
+      assert!(csp.contains("img-src 'self' blob: data:;"), "{uri}: {csp}");
+      assert_eq!(headers[header::REFERRER_POLICY], "no-referrer", "{uri}");
+      assert_eq!(headers["permissions-policy"], PERMISSIONS_POLICY, "{uri}");
+      assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff", "{uri}");
+      assert!(
+        !headers.contains_key(header::STRICT_TRANSPORT_SECURITY),
+        "{uri}"
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn hsts_is_only_sent_with_tls() {
+    let dist = temp_dir(&[("app.js", "")]);
+
+    for (uri, status) in [
+      ("/", StatusCode::OK),
+      ("/pkg/app.js", StatusCode::OK),
+      ("/api/ask?provider=none&prompt=hi", StatusCode::BAD_REQUEST),
+    ] {
+      let headers = headers(router(true, dist.path()), uri, status).await;
+
+      assert_eq!(
+        headers[header::STRICT_TRANSPORT_SECURITY],
+        STRICT_TRANSPORT_SECURITY,
+        "{uri}"
+      );
+    }
+  }
+
+  #[test]
+  fn only_inline_scripts_are_hashed() {
+    let scripts = inline_scripts(
+      "<script>a()</script><script src=\"b.js\"></script><script type=\"module\">c()</script>",
+    )
+    .collect::<Vec<_>>();
+
+    assert_eq!(scripts, ["a()", "c()"]);
+  }
+}
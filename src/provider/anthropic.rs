@@ -0,0 +1,489 @@
+use async_trait::async_trait;
+use hyper::{header, Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use libregpt::tokens;
+use serde::{Deserialize, Serialize};
+
+use super::history::{self, Exchange};
+use super::{interrupt, send, text_chunk, Image, Lines, ProviderError, IMAGE_TOKENS};
+use crate::config::{Anthropic, Timeouts, Upstream};
+use crate::proxy::ProxyConnector;
+use crate::util::{self, new_rustls_connector};
+
+const URL: &str = "https://api.anthropic.com/v1/messages";
+const API_VERSION: &str = "2023-06-01";
+/// Shared by all the Claude models.
+const CONTEXT_LIMIT: usize = 200_000;
+
+pub struct Provider {
+  url: String,
+  client: Client<HttpsConnector<ProxyConnector>>,
+  timeouts: Timeouts,
+  max_retries: u32,
+  cfg: Anthropic,
+}
+
+impl Provider {
+  pub fn new(upstream: Upstream, cfg: Anthropic) -> Self {
+    let connector = new_rustls_connector(upstream.proxies, upstream.timeouts.connect);
+    let client = Client::builder().build(connector);
+
+    Self {
+      url: upstream.url.map_or_else(|| URL.to_owned(), String::from),
+      client,
+      timeouts: upstream.timeouts,
+      max_retries: upstream.max_retries,
+      cfg,
+    }
+  }
+}
+
+#[async_trait]
+impl super::Provider for Provider {
+  async fn ask<'a>(
+    &self,
+    prompt: &str,
+    state: Option<&str>,
+    model: Option<&str>,
+  ) -> Result<(Option<String>, Body), ProviderError> {
+    self.ask_with_images(prompt, &[], state, model).await
+  }
+
+  async fn ask_with_images<'a>(
+    &self,
+    prompt: &str,
+    images: &[Image],
+    state: Option<&str>,
+    model: Option<&str>,
+  ) -> Result<(Option<String>, Body), ProviderError> {
+    let system = self.cfg.system_prompt.as_deref();
+    let mut exchanges = history::exchanges(state)?;
+
+    history::trim(
+      &mut exchanges,
+      prompt,
+      CONTEXT_LIMIT,
+      system.map_or(0, tokens::count) + images.len() * IMAGE_TOKENS + self.cfg.max_tokens as usize,
+    )?;
+
+    let body = serde_json::to_string(&MessagesRequest {
+      model: model.unwrap_or(&self.cfg.models[0]),
+      max_tokens: self.cfg.max_tokens,
+      stream: true,
+      system,
+      messages: conversation(exchanges, prompt, images),
+    })?;
+
+    let res = send(&self.client, self.timeouts, self.max_retries, || {
+      Ok(
+        Request::builder()
+          .method(Method::POST)
+          .uri(&self.url)
+          .header(header::CONTENT_TYPE, "application/json")
+          .header("x-api-key", &self.cfg.api_key)
+          .header("anthropic-version", API_VERSION)
+          .body(Body::from(body.clone()))?,
+      )
+    })
+    .await?;
+    let (mut tx, rx) = Body::channel();
+
+    util::spawn_in_current_span(async move {
+      let mut lines = Lines::new(res);
+
+      loop {
+        let line = match lines.next().await {
+          Ok(Some(line)) => parse_line(line),
+          Ok(None) => Err(ProviderError::MalformedResponse(
+            "reply ended before message_stop".to_owned(),
+          )),
+          Err(err) => Err(err),
+        };
+
+        match line {
+          Ok(Some(Event::ContentBlockDelta {
+            delta: Delta::TextDelta { text },
+          })) => drop(tx.send_data(text_chunk(text)).await),
+          Ok(Some(Event::MessageDelta { delta }))
+            if delta.stop_reason.as_deref() == Some("refusal") =>
+          {
+            interrupt(tx, ProviderError::ContentFiltered).await;
+            break;
+          }
+          Ok(Some(Event::MessageStop)) => break,
+          Ok(Some(Event::Error { error })) => {
+            interrupt(tx, error.into()).await;
+            break;
+          }
+          Ok(_) => {}
+          Err(err) => {
+            interrupt(tx, err).await;
+            break;
+          }
+        }
+      }
+    });
+
+    Ok((None, rx))
+  }
+
+  fn models(&self) -> &[String] {
+    &self.cfg.models
+  }
+
+  fn context_limit(&self, _model: Option<&str>) -> usize {
+    CONTEXT_LIMIT
+  }
+
+  fn accepts_images(&self, model: Option<&str>) -> bool {
+    let model = model.unwrap_or(&self.cfg.models[0]);
+
+    self.cfg.vision_models.iter().any(|m| m == model)
+  }
+
+  fn is_paid(&self) -> bool {
+    true
+  }
+}
+
+/// Appends the prompt to the conversation sent by the client, the images
+/// going before its text as the API recommends.
+fn conversation<'a>(
+  exchanges: Vec<Exchange>,
+  prompt: &str,
+  images: &'a [Image],
+) -> Vec<Message<'a>> {
+  let mut messages = Vec::with_capacity(exchanges.len() * 2 + 1);
+
+  for Exchange { prompt, reply } in exchanges {
+    messages.push(Message {
+      role: Role::User,
+      content: Content::Text(prompt),
+    });
+    messages.push(Message {
+      role: Role::Assistant,
+      content: Content::Text(reply),
+    });
+  }
+
+  let content = if images.is_empty() {
+    Content::Text(prompt.to_owned())
+  } else {
+    Content::Blocks(
+      images
+        .iter()
+        .map(|image| Block::Image {
+          source: ImageSource {
+            kind: "base64",
+            media_type: image.media_type,
+            data: &image.data,
+          },
+        })
+        .chain([Block::Text {
+          text: prompt.to_owned(),
+        }])
+        .collect(),
+    )
+  };
+
+  messages.push(Message {
+    role: Role::User,
+    content,
+  });
+
+  messages
+}
+
+/// Parses a line of the server-sent events of a reply, `event:` lines are
+/// skipped since data lines carry their type too.
+fn parse_line(line: &str) -> Result<Option<Event>, ProviderError> {
+  if line.is_empty() || line.starts_with(':') || line.starts_with("event:") {
+    return Ok(None);
+  }
+
+  let Some(data) = line.strip_prefix("data: ") else {
+    return Err(ProviderError::MalformedResponse(
+      "expected a data line".to_owned(),
+    ));
+  };
+
+  serde_json::from_str(data)
+    .map(Some)
+    .map_err(|err| ProviderError::malformed_json(&err))
+}
+
+#[derive(Serialize)]
+struct MessagesRequest<'a> {
+  model: &'a str,
+  max_tokens: u32,
+  stream: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  system: Option<&'a str>,
+  messages: Vec<Message<'a>>,
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+  role: Role,
+  content: Content<'a>,
+}
+
+/// A plain text message, or content blocks for a prompt with images.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Content<'a> {
+  Text(String),
+  Blocks(Vec<Block<'a>>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Block<'a> {
+  Image { source: ImageSource<'a> },
+  Text { text: String },
+}
+
+#[derive(Serialize)]
+struct ImageSource<'a> {
+  #[serde(rename = "type")]
+  kind: &'static str,
+  media_type: &'static str,
+  data: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+  User,
+  Assistant,
+}
+
+/// `message_start`, `ping` and the content block boundaries are ignored.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+  ContentBlockDelta {
+    delta: Delta,
+  },
+  MessageDelta {
+    delta: MessageDelta,
+  },
+  MessageStop,
+  Error {
+    error: ApiError,
+  },
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Delta {
+  TextDelta {
+    text: String,
+  },
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Deserialize)]
+struct MessageDelta {
+  stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+  #[serde(rename = "type")]
+  kind: String,
+}
+
+impl From<ApiError> for ProviderError {
+  fn from(err: ApiError) -> Self {
+    match err.kind.as_str() {
+      "rate_limit_error" => Self::RateLimited,
+      "timeout_error" => Self::Timeout,
+      _ => Self::Unreachable(err.kind),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::stub::{self, Reply, Stub, TEXT};
+  use super::super::Provider as _;
+  use super::*;
+
+  const REPLY: &[u8] = include_bytes!("fixtures/anthropic/reply.txt");
+  const REFUSAL: &[u8] = include_bytes!("fixtures/anthropic/refusal.txt");
+
+  fn new(upstream: Upstream) -> Provider {
+    Provider::new(
+      upstream,
+      Anthropic {
+        api_key: "key".to_owned(),
+        models: vec!["claude-3-5-sonnet-latest".to_owned()],
+        vision_models: Vec::new(),
+        system_prompt: None,
+        max_tokens: 1024,
+      },
+    )
+  }
+
+  async fn ask(reply: Reply) -> Result<(String, Option<String>), ProviderError> {
+    stub::ask(new, 0, vec![reply]).await.0
+  }
+
+  fn error(code: &str) -> Option<String> {
+    Some(code.to_owned())
+  }
+
+  /// Serializes the messages sent for `prompt` after the conversation in
+  /// `state`.
+  fn messages(state: &str, prompt: &str) -> serde_json::Value {
+    let exchanges = history::exchanges(Some(state)).unwrap();
+
+    serde_json::to_value(conversation(exchanges, prompt, &[])).unwrap()
+  }
+
+  #[tokio::test]
+  async fn replies_are_streamed() {
+    // a byte per chunk splits every character that isn't ASCII
+    for size in [REPLY.len(), 100, 1] {
+      assert_eq!(
+        ask(Reply::recorded(REPLY, size)).await.unwrap(),
+        (TEXT.to_owned(), None)
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn replies_end_at_message_stop() {
+    let after_stop = [REPLY, b"data: {\n\n"].concat();
+
+    assert_eq!(
+      ask(Reply::recorded(&after_stop, REPLY.len()))
+        .await
+        .unwrap(),
+      (TEXT.to_owned(), None)
+    );
+
+    let without_stop = &REPLY[..REPLY.len() - b"data: {\"type\":\"message_stop\"}\n\n".len()];
+
+    assert_eq!(
+      ask(Reply::recorded(without_stop, REPLY.len()))
+        .await
+        .unwrap(),
+      (TEXT.to_owned(), error("malformed_response"))
+    );
+  }
+
+  #[tokio::test]
+  async fn malformed_lines_interrupt_replies() {
+    // after the first text delta
+    for line in [
+      &b"data: {\"type\":\"content_block_delta\","[..],
+      b"retry: 1000",
+      b"data: \xff",
+    ] {
+      assert_eq!(
+        ask(Reply::with_line(REPLY, 11, line)).await.unwrap(),
+        ("Bonjour ! Voilà".to_owned(), error("malformed_response"))
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn refusals_are_interrupted() {
+    assert_eq!(
+      ask(Reply::recorded(REFUSAL, REFUSAL.len())).await.unwrap(),
+      ("I can't".to_owned(), error("content_filtered"))
+    );
+  }
+
+  #[tokio::test]
+  async fn api_errors_are_mapped() {
+    for (kind, expected) in [
+      ("rate_limit_error", "upstream_rate_limited"),
+      ("timeout_error", "timeout"),
+      ("overloaded_error", "upstream_unreachable"),
+    ] {
+      let line = format!(
+        "data: {{\"type\":\"error\",\"error\":{{\"type\":\"{kind}\",\"message\":\"...\"}}}}"
+      );
+      let (text, record) = ask(Reply::with_line(REPLY, 11, line.as_bytes()))
+        .await
+        .unwrap();
+
+      assert_eq!(
+        (text.as_str(), record),
+        ("Bonjour ! Voilà", error(expected)),
+        "{kind}"
+      );
+    }
+  }
+
+  #[test]
+  fn conversations_alternate_roles() {
+    let state = r#"[
+      {"role": "user", "content": "Salut"},
+      {"role": "assistant", "content": "Salut !"},
+      {"role": "user", "content": "Ça va ?"},
+      {"role": "assistant", "content": " "},
+      {"role": "user", "content": "Tu es là ?"},
+      {"role": "assistant", "content": "Oui."}
+    ]"#;
+
+    // the exchange without a reply is left out
+    assert_eq!(
+      messages(state, "Bonjour"),
+      serde_json::json!([
+        {"role": "user", "content": "Salut"},
+        {"role": "assistant", "content": "Salut !"},
+        {"role": "user", "content": "Tu es là ?"},
+        {"role": "assistant", "content": "Oui."},
+        {"role": "user", "content": "Bonjour"},
+      ])
+    );
+  }
+
+  #[test]
+  fn images_go_before_the_text() {
+    let images = [Image {
+      media_type: "image/png",
+      data: "iVBORw0KGgo=".to_owned(),
+    }];
+
+    assert_eq!(
+      serde_json::to_value(conversation(Vec::new(), "Qu'est-ce ?", &images)).unwrap(),
+      serde_json::json!([{
+        "role": "user",
+        "content": [
+          {
+            "type": "image",
+            "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo="}
+          },
+          {"type": "text", "text": "Qu'est-ce ?"},
+        ]
+      }])
+    );
+  }
+
+  #[tokio::test]
+  async fn conversations_out_of_turn_are_refused() {
+    let stub = Stub::start(vec![Reply::recorded(REPLY, REPLY.len())]).await;
+    let provider = new(stub.upstream(0));
+
+    for state in [
+      r#"[{"role": "user", "content": "Salut"}, {"role": "user", "content": "Salut"}]"#,
+      r#"[{"role": "assistant", "content": "Salut"}, {"role": "user", "content": "Salut"}]"#,
+      r#"[{"role": "user", "content": "Salut"}]"#,
+      r#"{"role": "user"}"#,
+    ] {
+      let res = provider.ask("Bonjour", Some(state), None).await;
+
+      assert!(matches!(res, Err(ProviderError::InvalidState)), "{state}");
+    }
+
+    assert_eq!(stub.requests(), 0);
+  }
+}
@@ -1,26 +1,34 @@
 use async_trait::async_trait;
-use hyper::client::HttpConnector;
 use hyper::{header, Body, Client, Method, Request};
 use hyper_rustls::HttpsConnector;
 use rand_user_agent::UserAgent;
 use serde::Deserialize;
-use tokio::io::AsyncBufReadExt;
-use tokio::task;
-use tokio_util::io::StreamReader;
-use tracing::error;
 
-use crate::util::{new_rustls_connector, BodyStream};
+use super::{history, interrupt, send, text_chunk, Lines, ProviderError, DEFAULT_CONTEXT_LIMIT};
+use crate::config::{Timeouts, Upstream};
+use crate::proxy::ProxyConnector;
+use crate::util::{self, new_rustls_connector};
+
+const URL: &str = "https://ava-alpha-api.codelink.io/api/chat";
 
 pub struct Provider {
-  client: Client<HttpsConnector<HttpConnector>>,
+  url: String,
+  client: Client<HttpsConnector<ProxyConnector>>,
+  timeouts: Timeouts,
+  max_retries: u32,
 }
 
 impl Provider {
-  pub fn new() -> Self {
-    let connector = new_rustls_connector();
+  pub fn new(upstream: Upstream) -> Self {
+    let connector = new_rustls_connector(upstream.proxies, upstream.timeouts.connect);
     let client = Client::builder().build(connector);
 
-    Self { client }
+    Self {
+      url: upstream.url.map_or_else(|| URL.to_owned(), String::from),
+      client,
+      timeouts: upstream.timeouts,
+      max_retries: upstream.max_retries,
+    }
   }
 }
 
@@ -30,58 +38,53 @@ impl super::Provider for Provider {
     &self,
     prompt: &str,
     state: Option<&str>,
-  ) -> anyhow::Result<(Option<String>, Body)> {
-    let prompt = serde_json::to_string(prompt)?;
-    let chat_len = state.map_or(2, |chat| chat.len() + 1) + 26 + prompt.len();
-    let mut body = String::with_capacity(12 + chat_len + 1);
-
-    body.push_str("{\"messages\":");
-
-    if let Some(chat) = state {
-      body.push_str(&chat[..chat.len() - 1]);
-      body.push(',');
-    } else {
-      body.push('[');
-    };
-
-    body.push_str("{\"role\":\"user\",\"content\":");
-    body.push_str(&prompt);
-    body.push_str("}]}");
-
-    let req = Request::builder()
-      .method(Method::POST)
-      .uri("https://ava-alpha-api.codelink.io/api/chat")
-      .header(header::CONTENT_TYPE, "application/json")
-      .header(header::USER_AGENT, &UserAgent::random().to_string())
-      .body(Body::from(body))?;
-
-    let res = self.client.request(req).await?;
+    _model: Option<&str>,
+  ) -> Result<(Option<String>, Body), ProviderError> {
+    let mut exchanges = history::exchanges(state)?;
+
+    history::trim(
+      &mut exchanges,
+      prompt,
+      DEFAULT_CONTEXT_LIMIT,
+      history::REPLY_TOKENS,
+    )?;
+
+    let body = format!(
+      "{{\"messages\":{}}}",
+      serde_json::to_string(&history::messages(&exchanges, prompt))?
+    );
+
+    let res = send(&self.client, self.timeouts, self.max_retries, || {
+      Ok(
+        Request::builder()
+          .method(Method::POST)
+          .uri(&self.url)
+          .header(header::CONTENT_TYPE, "application/json")
+          .header(header::USER_AGENT, &UserAgent::random().to_string())
+          .body(Body::from(body.clone()))?,
+      )
+    })
+    .await?;
     let (mut tx, rx) = Body::channel();
 
-    task::spawn(async move {
-      let mut reader = StreamReader::new(BodyStream::from(res.into_body()));
-      let mut line = String::with_capacity(256);
+    util::spawn_in_current_span(async move {
+      let mut lines = Lines::new(res);
 
       loop {
-        match reader.read_line(&mut line).await {
-          Ok(0) => break,
-          Ok(_) => {
-            match line.as_str() {
-              "\n" => {}
-              "data: [DONE]\n" => break,
-              _ => match serde_json::from_str::<Data>(&line[6..]) {
-                Ok(mut data) => {
-                  if let Some(content) = data.choices.swap_remove(0).delta.content {
-                    drop(tx.send_data(content.into()).await)
-                  }
-                }
-                Err(err) => error!("failed to deserialize data line: {err}"),
-              },
-            }
-            line.clear();
-          }
+        let line = match lines.next().await {
+          Ok(Some(line)) => parse_line(line),
+          Ok(None) => Err(ProviderError::MalformedResponse(
+            "reply ended before [DONE]".to_owned(),
+          )),
+          Err(err) => Err(err),
+        };
+
+        match line {
+          Ok(Line::Text(text)) => drop(tx.send_data(text_chunk(text)).await),
+          Ok(Line::Skip) => {}
+          Ok(Line::Done) => break,
           Err(err) => {
-            error!("failed to read line: {err}");
+            interrupt(tx, err).await;
             break;
           }
         }
@@ -92,6 +95,41 @@ impl super::Provider for Provider {
   }
 }
 
+enum Line {
+  Text(String),
+  Skip,
+  Done,
+}
+
+/// Parses a line of the server-sent events of a reply.
+fn parse_line(line: &str) -> Result<Line, ProviderError> {
+  if line.is_empty() || line.starts_with(':') {
+    return Ok(Line::Skip);
+  }
+
+  let Some(data) = line.strip_prefix("data: ") else {
+    return Err(ProviderError::MalformedResponse(
+      "expected a data line".to_owned(),
+    ));
+  };
+
+  if data == "[DONE]" {
+    return Ok(Line::Done);
+  }
+
+  let data =
+    serde_json::from_str::<Data>(data).map_err(|err| ProviderError::malformed_json(&err))?;
+  let Some(choice) = data.choices.into_iter().next() else {
+    return Ok(Line::Skip);
+  };
+
+  if choice.finish_reason.as_deref() == Some("content_filter") {
+    return Err(ProviderError::ContentFiltered);
+  }
+
+  Ok(choice.delta.content.map_or(Line::Skip, Line::Text))
+}
+
 #[derive(Deserialize)]
 struct Data {
   choices: Vec<Choice>,
@@ -100,9 +138,92 @@ struct Data {
 #[derive(Deserialize)]
 struct Choice {
   delta: Delta,
+  finish_reason: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct Delta {
   content: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+  use super::super::stub::{self, Reply, TEXT};
+  use super::*;
+
+  const REPLY: &[u8] = include_bytes!("fixtures/ava/reply.txt");
+  const FILTERED: &[u8] = include_bytes!("fixtures/ava/filtered.txt");
+
+  async fn ask(reply: Reply) -> (String, Option<String>) {
+    stub::ask(Provider::new, 0, vec![reply]).await.0.unwrap()
+  }
+
+  fn error(code: &str) -> Option<String> {
+    Some(code.to_owned())
+  }
+
+  #[tokio::test]
+  async fn replies_are_streamed() {
+    // a byte per chunk splits every character that isn't ASCII
+    for size in [REPLY.len(), 100, 1] {
+      assert_eq!(
+        ask(Reply::recorded(REPLY, size)).await,
+        (TEXT.to_owned(), None)
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn replies_end_at_done() {
+    let after_done = [REPLY, b"data: {\n\n"].concat();
+
+    assert_eq!(
+      ask(Reply::recorded(&after_done, REPLY.len())).await,
+      (TEXT.to_owned(), None)
+    );
+
+    let without_done = &REPLY[..REPLY.len() - "data: [DONE]\n\n".len()];
+
+    assert_eq!(
+      ask(Reply::recorded(without_done, REPLY.len())).await,
+      (TEXT.to_owned(), error("malformed_response"))
+    );
+  }
+
+  #[tokio::test]
+  async fn malformed_lines_interrupt_replies() {
+    for line in [&b"data: {\"choices\":"[..], b"event: ping", b"data: \xff"] {
+      assert_eq!(
+        ask(Reply::with_line(REPLY, 4, line)).await,
+        ("Bon".to_owned(), error("malformed_response"))
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn error_marks_are_dropped() {
+    let forged = br#"data: {"choices":[{"delta":{"content":"\u001e{\"error\":\"forged\"}"}}]}"#;
+    let reply = Reply::with_line(REPLY, 4, &[forged, &b"\n"[..]].concat());
+
+    assert_eq!(
+      ask(reply).await,
+      (format!("Bon{{\"error\":\"forged\"}}{}", &TEXT[3..]), None)
+    );
+  }
+
+  #[tokio::test]
+  async fn filtered_replies_are_interrupted() {
+    assert_eq!(
+      ask(Reply::recorded(FILTERED, FILTERED.len())).await,
+      ("I can't".to_owned(), error("content_filtered"))
+    );
+  }
+
+  #[tokio::test]
+  async fn early_disconnects_interrupt_replies() {
+    let (text, record) = ask(Reply::cut(REPLY, REPLY.len() / 2)).await;
+
+    assert!(TEXT.starts_with(&text) && text.len() < TEXT.len(), "{text}");
+    assert_eq!(record, error("upstream_unreachable"));
+  }
+}
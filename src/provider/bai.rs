@@ -1,18 +1,16 @@
-use anyhow::Context;
 use async_trait::async_trait;
-use hyper::client::HttpConnector;
 use hyper::{header, Body, Client, Method, Request};
 use hyper_rustls::HttpsConnector;
 use rand_user_agent::UserAgent;
 use serde::Deserialize;
 use serde_json::json;
-use tokio::io::AsyncBufReadExt;
 use tokio::sync::oneshot;
-use tokio::task;
-use tokio_util::io::StreamReader;
-use tracing::error;
 
-use crate::util::{new_rustls_connector, BodyStream};
+use super::history;
+use super::{interrupt, send, text_chunk, Lines, ProviderError, DEFAULT_CONTEXT_LIMIT};
+use crate::config::{Timeouts, Upstream};
+use crate::proxy::ProxyConnector;
+use crate::util::{self, new_rustls_connector};
 
 #[derive(Deserialize)]
 struct Message {
@@ -20,16 +18,34 @@ struct Message {
   id: String,
 }
 
+const URL: &str = "https://beta.theb.ai/api/chat-process";
+
+/// Starts the prompt of a thread continuing a conversation held by another
+/// provider.
+const PREAMBLE: &str = "Continue the conversation below by replying to its last message, \
+                        without mentioning that it was replayed.\n\n";
+
+/// Upper bound of the tokens taken by the preamble and the role prefixes.
+const PREAMBLE_TOKENS: usize = 64;
+
 pub struct Provider {
-  client: Client<HttpsConnector<HttpConnector>>,
+  url: String,
+  client: Client<HttpsConnector<ProxyConnector>>,
+  timeouts: Timeouts,
+  max_retries: u32,
 }
 
 impl Provider {
-  pub fn new() -> Self {
-    let connector = new_rustls_connector();
+  pub fn new(upstream: Upstream) -> Self {
+    let connector = new_rustls_connector(upstream.proxies, upstream.timeouts.connect);
     let client = Client::builder().build(connector);
 
-    Self { client }
+    Self {
+      url: upstream.url.map_or_else(|| URL.to_owned(), String::from),
+      client,
+      timeouts: upstream.timeouts,
+      max_retries: upstream.max_retries,
+    }
   }
 }
 
@@ -39,7 +55,8 @@ impl super::Provider for Provider {
     &self,
     prompt: &str,
     state: Option<&str>,
-  ) -> anyhow::Result<(Option<String>, Body)> {
+    _model: Option<&str>,
+  ) -> Result<(Option<String>, Body), ProviderError> {
     let body = if let Some(parent_msg_id) = state {
       json!({
         "prompt": prompt,
@@ -51,47 +68,150 @@ impl super::Provider for Provider {
       json!({ "prompt": prompt })
     };
 
-    let req = Request::builder()
-      .method(Method::POST)
-      .uri("https://beta.theb.ai/api/chat-process")
-      .header(header::CONTENT_TYPE, "application/json")
-      .header(header::USER_AGENT, UserAgent::random().to_string())
-      .body(Body::from(serde_json::to_string(&body)?))?;
-
-    let res = self.client.request(req).await?;
+    let body = serde_json::to_string(&body)?;
+    let res = send(&self.client, self.timeouts, self.max_retries, || {
+      Ok(
+        Request::builder()
+          .method(Method::POST)
+          .uri(&self.url)
+          .header(header::CONTENT_TYPE, "application/json")
+          .header(header::USER_AGENT, UserAgent::random().to_string())
+          .body(Body::from(body.clone()))?,
+      )
+    })
+    .await?;
     let (mut tx, rx) = Body::channel();
     let (msg_id_tx, msg_id_rx) = oneshot::channel();
     let mut msg_id_tx = Some(msg_id_tx);
 
-    task::spawn(async move {
-      let mut reader = StreamReader::new(BodyStream::from(res.into_body()));
-      let mut line = String::with_capacity(1 << 14);
+    util::spawn_in_current_span(async move {
+      let mut lines = Lines::new(res);
 
       loop {
-        match reader.read_line(&mut line).await {
-          Ok(0) => break,
-          Ok(_) => {
-            match serde_json::from_str::<Message>(&line) {
-              Ok(msg) => {
-                if let Some(msg_id_tx) = msg_id_tx.take() {
-                  drop(msg_id_tx.send(msg.id));
-                }
-                drop(tx.send_data(msg.delta.into()).await);
-              }
-              Err(err) => error!("failed to deserialize line: {err}"),
+        let line = match lines.next().await {
+          Ok(Some(line)) => parse_line(line),
+          Ok(None) => break,
+          Err(err) => Err(err),
+        };
+        let err = match line {
+          Ok(None) => continue,
+          Ok(Some(msg)) => {
+            if let Some(msg_id_tx) = msg_id_tx.take() {
+              drop(msg_id_tx.send(Ok(msg.id)));
             }
-            line.clear();
-          }
-          Err(err) => {
-            error!("failed to read line: {err}");
-            break;
+            drop(tx.send_data(text_chunk(msg.delta)).await);
+            continue;
           }
+          Err(err) => err,
+        };
+
+        // the reply hasn't started if the message id hasn't been sent yet
+        match msg_id_tx.take() {
+          Some(msg_id_tx) => drop(msg_id_tx.send(Err(err))),
+          None => interrupt(tx, err).await,
         }
+        break;
       }
     });
 
-    let msg_id = msg_id_rx.await.context("failed to receive msg id")?;
+    // the sender is dropped without a message id if the reply ended before
+    // its first message
+    let msg_id = msg_id_rx
+      .await
+      .map_err(|_| ProviderError::MalformedResponse("no message received".to_owned()))??;
 
     Ok((Some(msg_id), rx))
   }
+
+  /// The history is kept upstream, so the conversation is replayed at the
+  /// beginning of the prompt of a new thread.
+  fn adopt(&self, history: &str, prompt: &str) -> Result<(String, Option<String>), ProviderError> {
+    let mut exchanges = history::exchanges(Some(history))?;
+
+    if exchanges.is_empty() {
+      return Ok((prompt.to_owned(), None));
+    }
+
+    history::trim(
+      &mut exchanges,
+      prompt,
+      DEFAULT_CONTEXT_LIMIT,
+      history::REPLY_TOKENS + PREAMBLE_TOKENS,
+    )?;
+
+    let mut preamble = PREAMBLE.to_owned();
+
+    for exchange in &exchanges {
+      preamble.push_str("User: ");
+      preamble.push_str(exchange.prompt.trim());
+      preamble.push_str("\n\nAssistant: ");
+      preamble.push_str(exchange.reply.trim());
+      preamble.push_str("\n\n");
+    }
+
+    preamble.push_str("User: ");
+    preamble.push_str(prompt);
+
+    Ok((preamble, None))
+  }
+}
+
+/// Parses a line of the newline-delimited JSON of a reply.
+fn parse_line(line: &str) -> Result<Option<Message>, ProviderError> {
+  if line.is_empty() {
+    return Ok(None);
+  }
+
+  serde_json::from_str(line)
+    .map(Some)
+    .map_err(|err| ProviderError::malformed_json(&err))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::stub::{self, Reply, TEXT};
+  use super::*;
+
+  const REPLY: &[u8] = include_bytes!("fixtures/bai/reply.txt");
+
+  async fn ask(reply: Reply) -> Result<(String, Option<String>), ProviderError> {
+    stub::ask(Provider::new, 0, vec![reply]).await.0
+  }
+
+  #[tokio::test]
+  async fn replies_are_streamed() {
+    // a byte per chunk splits every character that isn't ASCII
+    for size in [REPLY.len(), 100, 1] {
+      assert_eq!(
+        ask(Reply::recorded(REPLY, size)).await.unwrap(),
+        (TEXT.to_owned(), None)
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn malformed_lines_interrupt_replies() {
+    for line in [&b"{\"delta\":"[..], b"data: [DONE]", b"\xff"] {
+      assert_eq!(
+        ask(Reply::with_line(REPLY, 2, line)).await.unwrap(),
+        ("Bonjour".to_owned(), Some("malformed_response".to_owned()))
+      );
+      assert!(matches!(
+        ask(Reply::with_line(REPLY, 0, line)).await,
+        Err(ProviderError::MalformedResponse(_))
+      ));
+    }
+  }
+
+  #[tokio::test]
+  async fn early_disconnects_interrupt_replies() {
+    let (text, record) = ask(Reply::cut(REPLY, REPLY.len() / 2)).await.unwrap();
+
+    assert!(TEXT.starts_with(&text) && text.len() < TEXT.len(), "{text}");
+    assert_eq!(record.as_deref(), Some("upstream_unreachable"));
+    assert!(matches!(
+      ask(Reply::cut(REPLY, 16)).await,
+      Err(ProviderError::Unreachable(_))
+    ));
+  }
 }
@@ -1,22 +1,35 @@
 use async_trait::async_trait;
-use hyper::client::HttpConnector;
+use futures::TryStreamExt;
 use hyper::{header, Body, Client, Method, Request};
 use hyper_rustls::HttpsConnector;
 use rand::Rng;
 use rand_user_agent::UserAgent;
 
+use super::{history, send, text_chunk, ProviderError, DEFAULT_CONTEXT_LIMIT};
+use crate::config::{Timeouts, Upstream};
+use crate::proxy::ProxyConnector;
 use crate::util::new_rustls_connector;
 
+const URL: &str = "https://api.deepai.org/hacking_is_a_crime";
+
 pub struct Provider {
-  client: Client<HttpsConnector<HttpConnector>>,
+  url: String,
+  client: Client<HttpsConnector<ProxyConnector>>,
+  timeouts: Timeouts,
+  max_retries: u32,
 }
 
 impl Provider {
-  pub fn new() -> Self {
-    let connector = new_rustls_connector();
+  pub fn new(upstream: Upstream) -> Self {
+    let connector = new_rustls_connector(upstream.proxies, upstream.timeouts.connect);
     let client = Client::builder().build(connector);
 
-    Self { client }
+    Self {
+      url: upstream.url.map_or_else(|| URL.to_owned(), String::from),
+      client,
+      timeouts: upstream.timeouts,
+      max_retries: upstream.max_retries,
+    }
   }
 }
 
@@ -26,7 +39,8 @@ impl super::Provider for Provider {
     &self,
     prompt: &str,
     state: Option<&str>,
-  ) -> anyhow::Result<(Option<String>, Body)> {
+    _model: Option<&str>,
+  ) -> Result<(Option<String>, Body), ProviderError> {
     let user_agent = UserAgent::random().to_string();
     let api_key = generate_api_key(&user_agent);
     let boundary = String::from_iter(
@@ -40,40 +54,42 @@ impl super::Provider for Provider {
     content_type.push_str("multipart/form-data; boundary=");
     content_type.push_str(&boundary);
 
-    let prompt = serde_json::to_string(prompt)?;
-    let chat_len = state.map_or(2, |chat| chat.len() + 1) + 26 + prompt.len();
-    let mut body = String::with_capacity(2 + boundary.len() * 3 + 61 + 54 + chat_len + 4 + 4);
+    let mut exchanges = history::exchanges(state)?;
+
+    history::trim(
+      &mut exchanges,
+      prompt,
+      DEFAULT_CONTEXT_LIMIT,
+      history::REPLY_TOKENS,
+    )?;
+
+    let chat = serde_json::to_string(&history::messages(&exchanges, prompt))?;
+    let mut body = String::with_capacity(2 + boundary.len() * 3 + 61 + 54 + chat.len() + 4 + 4);
 
     body.push_str("--");
     body.push_str(&boundary);
     body.push_str("Content-Disposition: form-data; name=\"chat_style\"\r\n\r\nchat\r\n--");
     body.push_str(&boundary);
     body.push_str("Content-Disposition: form-data; name=\"chatHistory\"\r\n\r\n");
-
-    if let Some(chat) = state {
-      body.push_str(&chat[..chat.len() - 1]);
-      body.push(',');
-    } else {
-      body.push('[');
-    };
-
-    body.push_str("{\"role\":\"user\",\"content\":");
-    body.push_str(&prompt);
-    body.push_str("}]\r\n--");
+    body.push_str(&chat);
+    body.push_str("\r\n--");
     body.push_str(&boundary);
     body.push_str("--\r\n");
 
-    let req = Request::builder()
-      .method(Method::POST)
-      .uri("https://api.deepai.org/hacking_is_a_crime")
-      .header(header::USER_AGENT, user_agent)
-      .header("api-key", api_key)
-      .header(header::CONTENT_TYPE, content_type)
-      .body(Body::from(body))?;
-
-    let res = self.client.request(req).await?;
-
-    Ok((None, if res.status().is_success() { res.into_body() } else { Body::from("error") }))
+    let res = send(&self.client, self.timeouts, self.max_retries, || {
+      Ok(
+        Request::builder()
+          .method(Method::POST)
+          .uri(&self.url)
+          .header(header::USER_AGENT, &user_agent)
+          .header("api-key", &api_key)
+          .header(header::CONTENT_TYPE, &content_type)
+          .body(Body::from(body.clone()))?,
+      )
+    })
+    .await?;
+
+    Ok((None, Body::wrap_stream(res.map_ok(text_chunk))))
   }
 }
 
@@ -110,3 +126,71 @@ fn generate_api_key(user_agent: &str) -> String {
 
   api_key
 }
+
+#[cfg(test)]
+mod tests {
+  use hyper::StatusCode;
+
+  use super::super::stub::{self, Reply, TEXT};
+  use super::*;
+
+  const REPLY: &[u8] = include_bytes!("fixtures/deepai/reply.txt");
+
+  async fn ask(reply: Reply) -> Result<(String, Option<String>), ProviderError> {
+    stub::ask(Provider::new, 0, vec![reply]).await.0
+  }
+
+  #[tokio::test]
+  async fn replies_are_streamed() {
+    // a byte per chunk splits every character that isn't ASCII
+    for size in [REPLY.len(), 10, 1] {
+      assert_eq!(
+        ask(Reply::recorded(REPLY, size)).await.unwrap(),
+        (TEXT.to_owned(), None)
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn error_marks_are_dropped() {
+    let reply = [&b"Bonjour\x1e"[..], &REPLY[7..]].concat();
+
+    assert_eq!(
+      ask(Reply::recorded(&reply, 4)).await.unwrap(),
+      (TEXT.to_owned(), None)
+    );
+  }
+
+  #[tokio::test]
+  async fn failed_requests_are_mapped() {
+    for (status, expected) in [
+      (StatusCode::TOO_MANY_REQUESTS, "upstream_rate_limited"),
+      (StatusCode::UNAUTHORIZED, "upstream_rejected"),
+      (StatusCode::BAD_GATEWAY, "upstream_unreachable"),
+    ] {
+      let res = ask(Reply::Status(status)).await;
+
+      assert_eq!(res.unwrap_err().code(), expected, "{status}");
+    }
+  }
+
+  #[tokio::test]
+  async fn early_disconnects_cut_replies() {
+    let (text, _) = ask(Reply::cut(REPLY, 16)).await.unwrap();
+
+    assert_eq!(text, "Bonjour ! Voilà");
+  }
+
+  #[test]
+  fn api_keys_look_like_the_site_ones() {
+    let api_key = generate_api_key("Mozilla/5.0");
+    let (n, digest) = api_key
+      .strip_prefix("tryit-")
+      .and_then(|rest| rest.split_once('-'))
+      .unwrap();
+
+    assert!(n.parse::<u64>().unwrap() < u64::pow(10, 11), "{api_key}");
+    assert_eq!(digest.len(), 32, "{api_key}");
+    assert!(digest.bytes().all(|b| b.is_ascii_hexdigit()), "{api_key}");
+  }
+}
@@ -0,0 +1,179 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+use anyhow::anyhow;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hyper::Body;
+use libregpt::stream::ERROR_MARK;
+use serde_json::json;
+
+use crate::logging;
+
+#[derive(Debug)]
+pub enum ProviderError {
+  /// The upstream couldn't be reached, closed the connection or failed.
+  Unreachable(String),
+  /// The upstream refused the request, usually because it blocked this server.
+  Rejected(StatusCode),
+  RateLimited,
+  /// Describes what's wrong without quoting the response, which could contain
+  /// part of a reply.
+  MalformedResponse(String),
+  Timeout,
+  ContentFiltered,
+  /// The conversation sent by the client can't be used.
+  InvalidState,
+  /// The prompt alone doesn't fit in the context window of the model.
+  PromptTooLong,
+  /// Images were sent to a model that only takes text.
+  ImagesUnsupported,
+  Internal(anyhow::Error),
+}
+
+impl ProviderError {
+  pub fn malformed_json(err: &serde_json::Error) -> Self {
+    Self::MalformedResponse(logging::json_error(err))
+  }
+
+  pub fn code(&self) -> &'static str {
+    match self {
+      Self::Unreachable(_) => "upstream_unreachable",
+      Self::Rejected(_) => "upstream_rejected",
+      Self::RateLimited => "upstream_rate_limited",
+      Self::MalformedResponse(_) => "malformed_response",
+      Self::Timeout => "timeout",
+      Self::ContentFiltered => "content_filtered",
+      Self::InvalidState => "invalid_state",
+      Self::PromptTooLong => "prompt_too_long",
+      Self::ImagesUnsupported => "images_unsupported",
+      Self::Internal(_) => "internal",
+    }
+  }
+
+  /// Describes the error to users, internal errors could leak details about
+  /// the server.
+  pub fn message(&self) -> String {
+    match self {
+      Self::Internal(_) => "unexpected error".to_owned(),
+      _ => self.to_string(),
+    }
+  }
+
+  /// What clients get, as the body of a failed request or at the end of an
+  /// interrupted reply.
+  pub fn body(&self) -> serde_json::Value {
+    json!({ "error": self.code(), "message": self.message() })
+  }
+
+  /// Ends the stream of an interrupted reply.
+  pub fn record(&self) -> String {
+    format!("{ERROR_MARK}{}", self.body())
+  }
+
+  pub fn status(&self) -> StatusCode {
+    match self {
+      Self::Unreachable(_) => StatusCode::BAD_GATEWAY,
+      Self::Rejected(_) => StatusCode::SERVICE_UNAVAILABLE,
+      Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+      Self::MalformedResponse(_) | Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+      Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+      Self::ContentFiltered => StatusCode::UNPROCESSABLE_ENTITY,
+      Self::InvalidState | Self::ImagesUnsupported => StatusCode::BAD_REQUEST,
+      Self::PromptTooLong => StatusCode::PAYLOAD_TOO_LARGE,
+    }
+  }
+}
+
+impl Display for ProviderError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Unreachable(err) => write!(f, "provider is unreachable: {err}"),
+      Self::Rejected(status) => write!(f, "provider rejected the request with status {status}"),
+      Self::RateLimited => f.write_str("provider is rate limiting this server"),
+      Self::MalformedResponse(err) => write!(f, "provider sent a malformed response: {err}"),
+      Self::Timeout => f.write_str("provider took too long to reply"),
+      Self::ContentFiltered => {
+        f.write_str("provider refused to reply because of its content filter")
+      }
+      Self::InvalidState => f.write_str("conversation state is invalid"),
+      Self::PromptTooLong => f.write_str("prompt is too long for the model"),
+      Self::ImagesUnsupported => f.write_str("model doesn't accept images"),
+      Self::Internal(err) => write!(f, "unexpected error: {err}"),
+    }
+  }
+}
+
+impl Error for ProviderError {}
+
+impl IntoResponse for ProviderError {
+  fn into_response(self) -> Response {
+    (self.status(), Json(self.body())).into_response()
+  }
+}
+
+impl From<hyper::Error> for ProviderError {
+  fn from(err: hyper::Error) -> Self {
+    if err.is_timeout() || is_timed_out(&err) {
+      Self::Timeout
+    } else {
+      Self::Unreachable(err.to_string())
+    }
+  }
+}
+
+impl From<IoError> for ProviderError {
+  fn from(err: IoError) -> Self {
+    if is_timed_out(&err) {
+      Self::Timeout
+    } else {
+      Self::Unreachable(err.to_string())
+    }
+  }
+}
+
+impl From<hyper::http::Error> for ProviderError {
+  fn from(err: hyper::http::Error) -> Self {
+    Self::Internal(err.into())
+  }
+}
+
+impl From<serde_json::Error> for ProviderError {
+  fn from(err: serde_json::Error) -> Self {
+    Self::Internal(anyhow!(logging::json_error(&err)))
+  }
+}
+
+/// Turns an unsuccessful upstream response into an error.
+pub fn check_status(res: hyper::Response<Body>) -> Result<hyper::Response<Body>, ProviderError> {
+  let status = res.status();
+
+  if status.is_success() {
+    Ok(res)
+  } else if status == StatusCode::TOO_MANY_REQUESTS {
+    Err(ProviderError::RateLimited)
+  } else if status.is_client_error() {
+    Err(ProviderError::Rejected(status))
+  } else {
+    Err(ProviderError::Unreachable(format!("status {status}")))
+  }
+}
+
+/// Looks for a timeout from the connector or `IdleTimeout` among the causes of
+/// an error.
+fn is_timed_out(err: &(dyn Error + 'static)) -> bool {
+  let mut source = Some(err);
+
+  while let Some(err) = source {
+    source = match err.downcast_ref::<IoError>() {
+      Some(err) if err.kind() == IoErrorKind::TimedOut => return true,
+      // `source` skips the error wrapped by an `io::Error`
+      Some(err) => err.get_ref().map(|err| err as &(dyn Error + 'static)),
+      None => err.source(),
+    };
+  }
+
+  false
+}
@@ -0,0 +1,464 @@
+use async_trait::async_trait;
+use hyper::{header, Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use libregpt::tokens;
+use serde::{Deserialize, Serialize};
+
+use super::history::{self, Exchange};
+use super::{interrupt, send, text_chunk, Lines, ProviderError, DEFAULT_CONTEXT_LIMIT};
+use crate::config::{Gemini, Timeouts, Upstream};
+use crate::proxy::ProxyConnector;
+use crate::util::{self, new_rustls_connector};
+
+/// Followed by `/{model}:streamGenerateContent`.
+const URL: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+
+/// Context windows by model name prefix, the first match wins.
+const CONTEXT_LIMITS: &[(&str, usize)] = &[
+  ("gemini-1.5-pro", 2_097_152),
+  ("gemini-1.5", 1_048_576),
+  ("gemini-2", 1_048_576),
+  ("gemini-1.0", 32_760),
+];
+
+/// Finish reasons of a reply stopped by a safety filter.
+const BLOCKED: &[&str] = &[
+  "SAFETY",
+  "RECITATION",
+  "BLOCKLIST",
+  "PROHIBITED_CONTENT",
+  "SPII",
+];
+
+pub struct Provider {
+  url: String,
+  client: Client<HttpsConnector<ProxyConnector>>,
+  timeouts: Timeouts,
+  max_retries: u32,
+  cfg: Gemini,
+}
+
+impl Provider {
+  pub fn new(upstream: Upstream, cfg: Gemini) -> Self {
+    let connector = new_rustls_connector(upstream.proxies, upstream.timeouts.connect);
+    let client = Client::builder().build(connector);
+
+    Self {
+      url: upstream.url.map_or_else(|| URL.to_owned(), String::from),
+      client,
+      timeouts: upstream.timeouts,
+      max_retries: upstream.max_retries,
+      cfg,
+    }
+  }
+}
+
+#[async_trait]
+impl super::Provider for Provider {
+  async fn ask<'a>(
+    &self,
+    prompt: &str,
+    state: Option<&str>,
+    model: Option<&str>,
+  ) -> Result<(Option<String>, Body), ProviderError> {
+    let model = model.unwrap_or(&self.cfg.models[0]);
+    let system = self.cfg.system_prompt.as_deref();
+    let mut exchanges = history::exchanges(state)?;
+
+    history::trim(
+      &mut exchanges,
+      prompt,
+      context_limit(model),
+      system.map_or(0, tokens::count) + self.cfg.max_tokens as usize,
+    )?;
+
+    let body = serde_json::to_string(&GenerateRequest {
+      contents: contents(exchanges, prompt),
+      system_instruction: system.map(|text| Instruction {
+        parts: [TextPart { text }],
+      }),
+      generation_config: GenerationConfig {
+        max_output_tokens: self.cfg.max_tokens,
+      },
+    })?;
+    // without `alt=sse` the reply is a JSON array that can't be parsed line by
+    // line
+    let uri = format!(
+      "{}/{}:streamGenerateContent?alt=sse",
+      self.url.trim_end_matches('/'),
+      model,
+    );
+
+    let res = send(&self.client, self.timeouts, self.max_retries, || {
+      Ok(
+        Request::builder()
+          .method(Method::POST)
+          .uri(&uri)
+          .header(header::CONTENT_TYPE, "application/json")
+          .header("x-goog-api-key", &self.cfg.api_key)
+          .body(Body::from(body.clone()))?,
+      )
+    })
+    .await?;
+    let mut lines = Lines::new(res);
+    // a blocked prompt is reported before anything is streamed so that the
+    // client can tell it apart from an empty reply
+    let mut chunk = next_chunk(&mut lines).await?;
+    let (mut tx, rx) = Body::channel();
+
+    util::spawn_in_current_span(async move {
+      loop {
+        if !chunk.text.is_empty() {
+          drop(tx.send_data(text_chunk(chunk.text)).await);
+        }
+
+        if chunk.done {
+          break;
+        }
+
+        chunk = match next_chunk(&mut lines).await {
+          Ok(chunk) => chunk,
+          Err(err) => {
+            interrupt(tx, err).await;
+            break;
+          }
+        };
+      }
+    });
+
+    Ok((None, rx))
+  }
+
+  fn models(&self) -> &[String] {
+    &self.cfg.models
+  }
+
+  fn context_limit(&self, model: Option<&str>) -> usize {
+    context_limit(model.unwrap_or(&self.cfg.models[0]))
+  }
+
+  fn is_paid(&self) -> bool {
+    true
+  }
+}
+
+/// Appends the prompt to the conversation sent by the client.
+fn contents(exchanges: Vec<Exchange>, prompt: &str) -> Vec<Content> {
+  let mut contents = Vec::with_capacity(exchanges.len() * 2 + 1);
+
+  for Exchange { prompt, reply } in exchanges {
+    contents.push(Content::new(Role::User, prompt));
+    contents.push(Content::new(Role::Model, reply));
+  }
+
+  contents.push(Content::new(Role::User, prompt.to_owned()));
+
+  contents
+}
+
+fn context_limit(model: &str) -> usize {
+  CONTEXT_LIMITS
+    .iter()
+    .find(|(prefix, _)| model.starts_with(prefix))
+    .map_or(DEFAULT_CONTEXT_LIMIT, |&(_, limit)| limit)
+}
+
+async fn next_chunk(lines: &mut Lines) -> Result<Chunk, ProviderError> {
+  loop {
+    let Some(line) = lines.next().await? else {
+      return Err(ProviderError::MalformedResponse(
+        "reply ended before a finish reason".to_owned(),
+      ));
+    };
+
+    if let Some(chunk) = parse_line(line)? {
+      return Ok(chunk);
+    }
+  }
+}
+
+/// Parses a line of the server-sent events of a reply, each of which carries
+/// a whole response object.
+fn parse_line(line: &str) -> Result<Option<Chunk>, ProviderError> {
+  if line.is_empty() || line.starts_with(':') {
+    return Ok(None);
+  }
+
+  let Some(data) = line.strip_prefix("data: ") else {
+    return Err(ProviderError::MalformedResponse(
+      "expected a data line".to_owned(),
+    ));
+  };
+  let res = serde_json::from_str::<GenerateResponse>(data)
+    .map_err(|err| ProviderError::malformed_json(&err))?;
+
+  if let Some(error) = res.error {
+    return Err(error.into());
+  }
+
+  if res
+    .prompt_feedback
+    .and_then(|feedback| feedback.block_reason)
+    .is_some()
+  {
+    return Err(ProviderError::ContentFiltered);
+  }
+
+  let Some(candidate) = res.candidates.into_iter().next() else {
+    return Ok(None);
+  };
+
+  if let Some(reason) = &candidate.finish_reason {
+    if BLOCKED.contains(&reason.as_str()) {
+      return Err(ProviderError::ContentFiltered);
+    }
+  }
+
+  let text = candidate
+    .content
+    .map(|content| {
+      content
+        .parts
+        .into_iter()
+        .filter_map(|part| part.text)
+        .collect()
+    })
+    .unwrap_or_default();
+
+  Ok(Some(Chunk {
+    text,
+    done: candidate.finish_reason.is_some(),
+  }))
+}
+
+/// Text of a streamed response, the last one of a reply is `done`.
+struct Chunk {
+  text: String,
+  done: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateRequest<'a> {
+  contents: Vec<Content>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  system_instruction: Option<Instruction<'a>>,
+  generation_config: GenerationConfig,
+}
+
+#[derive(Serialize)]
+struct Content {
+  role: Role,
+  parts: [Part; 1],
+}
+
+impl Content {
+  fn new(role: Role, text: String) -> Self {
+    Self {
+      role,
+      parts: [Part { text: Some(text) }],
+    }
+  }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+  User,
+  Model,
+}
+
+#[derive(Serialize)]
+struct Instruction<'a> {
+  parts: [TextPart<'a>; 1],
+}
+
+#[derive(Serialize)]
+struct TextPart<'a> {
+  text: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+  max_output_tokens: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateResponse {
+  #[serde(default)]
+  candidates: Vec<Candidate>,
+  prompt_feedback: Option<PromptFeedback>,
+  error: Option<ApiError>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+  /// Missing from a blocked reply.
+  content: Option<CandidateContent>,
+  finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CandidateContent {
+  #[serde(default)]
+  parts: Vec<Part>,
+}
+
+/// Parts other than text are ignored.
+#[derive(Deserialize, Serialize)]
+struct Part {
+  text: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+  block_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+  status: String,
+}
+
+impl From<ApiError> for ProviderError {
+  fn from(err: ApiError) -> Self {
+    match err.status.as_str() {
+      "RESOURCE_EXHAUSTED" => Self::RateLimited,
+      "DEADLINE_EXCEEDED" => Self::Timeout,
+      _ => Self::Unreachable(err.status),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::stub::{self, Reply, TEXT};
+  use super::*;
+
+  const REPLY: &[u8] = include_bytes!("fixtures/gemini/reply.txt");
+  const BLOCKED_PROMPT: &[u8] = include_bytes!("fixtures/gemini/blocked_prompt.txt");
+  const BLOCKED_REPLY: &[u8] = include_bytes!("fixtures/gemini/blocked_reply.txt");
+
+  fn new(upstream: Upstream) -> Provider {
+    Provider::new(
+      upstream,
+      Gemini {
+        api_key: "key".to_owned(),
+        models: vec!["gemini-1.5-flash".to_owned()],
+        system_prompt: None,
+        max_tokens: 1024,
+      },
+    )
+  }
+
+  async fn ask(reply: Reply) -> Result<(String, Option<String>), ProviderError> {
+    stub::ask(new, 0, vec![reply]).await.0
+  }
+
+  fn error(code: &str) -> Option<String> {
+    Some(code.to_owned())
+  }
+
+  #[tokio::test]
+  async fn replies_are_streamed() {
+    // a byte per chunk splits every character that isn't ASCII
+    for size in [REPLY.len(), 100, 1] {
+      assert_eq!(
+        ask(Reply::recorded(REPLY, size)).await.unwrap(),
+        (TEXT.to_owned(), None)
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn replies_end_at_finish_reason() {
+    let after_finish = [REPLY, b"data: {\r\n\r\n"].concat();
+
+    assert_eq!(
+      ask(Reply::recorded(&after_finish, REPLY.len()))
+        .await
+        .unwrap(),
+      (TEXT.to_owned(), None)
+    );
+
+    // the last event carries the finish reason
+    let last = REPLY[..REPLY.len() - 4]
+      .windows(4)
+      .rposition(|window| window == b"\r\n\r\n")
+      .unwrap()
+      + 4;
+
+    assert_eq!(
+      ask(Reply::recorded(&REPLY[..last], REPLY.len()))
+        .await
+        .unwrap(),
+      (
+        "Bonjour ! Voilà un café ☕ — 日本".to_owned(),
+        error("malformed_response")
+      )
+    );
+  }
+
+  #[tokio::test]
+  async fn malformed_lines_interrupt_replies() {
+    for line in [
+      &b"data: {\"candidates\":"[..],
+      b"event: ping",
+      b"data: \xff",
+    ] {
+      assert_eq!(
+        ask(Reply::with_line(REPLY, 2, line)).await.unwrap(),
+        ("Bonjour ! Voilà".to_owned(), error("malformed_response"))
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn blocked_prompts_are_filtered() {
+    // reported before the reply, as a failed request
+    let res = ask(Reply::recorded(BLOCKED_PROMPT, BLOCKED_PROMPT.len())).await;
+
+    assert!(matches!(res, Err(ProviderError::ContentFiltered)));
+  }
+
+  #[tokio::test]
+  async fn blocked_replies_are_interrupted() {
+    assert_eq!(
+      ask(Reply::recorded(BLOCKED_REPLY, BLOCKED_REPLY.len()))
+        .await
+        .unwrap(),
+      ("Here is how".to_owned(), error("content_filtered"))
+    );
+  }
+
+  #[tokio::test]
+  async fn api_errors_are_mapped() {
+    for (status, expected) in [
+      ("RESOURCE_EXHAUSTED", "upstream_rate_limited"),
+      ("DEADLINE_EXCEEDED", "timeout"),
+      ("PERMISSION_DENIED", "upstream_unreachable"),
+    ] {
+      let line = format!(
+        "data: {{\"error\": {{\"code\": 400, \"message\": \"...\", \"status\": \"{status}\"}}}}\r\n\r\n"
+      );
+      let res = ask(Reply::recorded(line.as_bytes(), line.len())).await;
+
+      assert_eq!(res.unwrap_err().code(), expected, "{status}");
+
+      // and in the middle of a reply
+      let (text, record) = ask(Reply::with_line(REPLY, 2, line.trim_end().as_bytes()))
+        .await
+        .unwrap();
+
+      assert_eq!(
+        (text.as_str(), record),
+        ("Bonjour ! Voilà", error(expected)),
+        "{status}"
+      );
+    }
+  }
+}
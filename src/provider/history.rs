@@ -0,0 +1,251 @@
+use libregpt::tokens::{self, MESSAGE_OVERHEAD};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::ProviderError;
+
+/// Tokens kept for the reply of the providers that don't let it be capped.
+pub const REPLY_TOKENS: usize = 512;
+
+/// A prompt of the conversation sent by the client and its reply.
+pub struct Exchange {
+  pub prompt: String,
+  pub reply: String,
+}
+
+impl Exchange {
+  fn tokens(&self) -> usize {
+    tokens::count(&self.prompt) + tokens::count(&self.reply) + 2 * MESSAGE_OVERHEAD
+  }
+}
+
+#[derive(Deserialize)]
+struct Message {
+  role: Role,
+  content: String,
+}
+
+/// A message of the chat format most APIs share.
+#[derive(Serialize)]
+pub struct ChatMessage<'a> {
+  role: Role,
+  content: &'a str,
+}
+
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+  User,
+  Assistant,
+}
+
+/// Parses the conversation sent by the client, a JSON array of alternating
+/// user and assistant messages.
+pub fn exchanges(state: Option<&str>) -> Result<Vec<Exchange>, ProviderError> {
+  let Some(state) = state else {
+    return Ok(Vec::new());
+  };
+  let messages =
+    serde_json::from_str::<Vec<Message>>(state).map_err(|_| ProviderError::InvalidState)?;
+  let mut exchanges = Vec::with_capacity(messages.len() / 2);
+  let mut messages = messages.into_iter();
+
+  while let Some(prompt) = messages.next() {
+    let Some(reply) = messages.next() else {
+      return Err(ProviderError::InvalidState);
+    };
+
+    if prompt.role != Role::User || reply.role != Role::Assistant {
+      return Err(ProviderError::InvalidState);
+    }
+
+    // a failed reply leaves an empty message behind, which APIs reject, its
+    // prompt goes with it so that roles keep alternating
+    if reply.content.trim().is_empty() {
+      continue;
+    }
+
+    exchanges.push(Exchange {
+      prompt: prompt.content,
+      reply: reply.content,
+    });
+  }
+
+  Ok(exchanges)
+}
+
+/// Drops the oldest exchanges until the conversation and the prompt fit in
+/// `limit` tokens, `reserved` of which are kept for the system prompt and the
+/// reply.
+pub fn trim(
+  exchanges: &mut Vec<Exchange>,
+  prompt: &str,
+  limit: usize,
+  reserved: usize,
+) -> Result<(), ProviderError> {
+  let Some(budget) = limit.checked_sub(tokens::count(prompt) + MESSAGE_OVERHEAD + reserved) else {
+    return Err(ProviderError::PromptTooLong);
+  };
+  let mut total = exchanges.iter().map(Exchange::tokens).sum::<usize>();
+  let dropped = exchanges
+    .iter()
+    .take_while(|exchange| {
+      let over = total > budget;
+
+      if over {
+        total -= exchange.tokens();
+      }
+
+      over
+    })
+    .count();
+
+  if dropped > 0 {
+    debug!("left out the {dropped} oldest exchanges to fit in the context window");
+    exchanges.drain(..dropped);
+  }
+
+  Ok(())
+}
+
+/// Serializes the conversation into the chat messages clients send as state.
+pub fn chat_state(exchanges: &[Exchange]) -> Result<String, ProviderError> {
+  let messages = exchanges
+    .iter()
+    .flat_map(|exchange| {
+      [
+        ChatMessage {
+          role: Role::User,
+          content: &exchange.prompt,
+        },
+        ChatMessage {
+          role: Role::Assistant,
+          content: &exchange.reply,
+        },
+      ]
+    })
+    .collect::<Vec<_>>();
+
+  Ok(serde_json::to_string(&messages)?)
+}
+
+/// Turns the conversation into chat messages ending with the prompt.
+pub fn messages<'a>(exchanges: &'a [Exchange], prompt: &'a str) -> Vec<ChatMessage<'a>> {
+  exchanges
+    .iter()
+    .flat_map(|exchange| {
+      [
+        ChatMessage {
+          role: Role::User,
+          content: &exchange.prompt,
+        },
+        ChatMessage {
+          role: Role::Assistant,
+          content: &exchange.reply,
+        },
+      ]
+    })
+    .chain([ChatMessage {
+      role: Role::User,
+      content: prompt,
+    }])
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Exchanges of 12 tokens each.
+  fn conversation(len: usize) -> Vec<Exchange> {
+    (0..len)
+      .map(|i| Exchange {
+        prompt: format!("Salut {i}"),
+        reply: "Oui".to_owned(),
+      })
+      .collect()
+  }
+
+  fn prompts(exchanges: &[Exchange]) -> Vec<&str> {
+    exchanges
+      .iter()
+      .map(|exchange| exchange.prompt.as_str())
+      .collect()
+  }
+
+  #[test]
+  fn oldest_exchanges_are_trimmed() {
+    // "Bonjour" and its overhead take 6 tokens
+    let mut exchanges = conversation(3);
+    trim(&mut exchanges, "Bonjour", 6 + 36 + 10, 10).unwrap();
+
+    assert_eq!(prompts(&exchanges), ["Salut 0", "Salut 1", "Salut 2"]);
+
+    let mut exchanges = conversation(3);
+    trim(&mut exchanges, "Bonjour", 6 + 35 + 10, 10).unwrap();
+
+    assert_eq!(prompts(&exchanges), ["Salut 1", "Salut 2"]);
+
+    let mut exchanges = conversation(3);
+    trim(&mut exchanges, "Bonjour", 6 + 10, 10).unwrap();
+
+    assert!(exchanges.is_empty());
+  }
+
+  #[test]
+  fn prompts_over_the_limit_are_refused() {
+    let mut exchanges = conversation(1);
+
+    assert!(matches!(
+      trim(&mut exchanges, "Bonjour", 6 + 10 - 1, 10),
+      Err(ProviderError::PromptTooLong)
+    ));
+  }
+
+  #[test]
+  fn exchanges_without_a_reply_are_dropped() {
+    let exchanges = exchanges(Some(
+      r#"[
+        {"role": "user", "content": "Salut"},
+        {"role": "assistant", "content": ""},
+        {"role": "user", "content": "Ça va ?"},
+        {"role": "assistant", "content": "Oui."}
+      ]"#,
+    ))
+    .unwrap();
+
+    assert_eq!(prompts(&exchanges), ["Ça va ?"]);
+    assert_eq!(exchanges[0].reply, "Oui.");
+  }
+
+  #[test]
+  fn states_out_of_turn_are_invalid() {
+    for state in [
+      "",
+      r#"[{"role": "user", "content": "Salut"}]"#,
+      r#"[{"role": "assistant", "content": "Salut"}, {"role": "user", "content": "Salut"}]"#,
+      r#"[{"role": "system", "content": "Salut"}, {"role": "assistant", "content": "Salut"}]"#,
+    ] {
+      assert!(
+        matches!(exchanges(Some(state)), Err(ProviderError::InvalidState)),
+        "{state}"
+      );
+    }
+
+    assert!(exchanges(None).unwrap().is_empty());
+  }
+
+  #[test]
+  fn conversations_are_chat_messages() {
+    let exchanges = conversation(1);
+
+    assert_eq!(
+      chat_state(&exchanges).unwrap(),
+      r#"[{"role":"user","content":"Salut 0"},{"role":"assistant","content":"Oui"}]"#
+    );
+    assert_eq!(
+      serde_json::to_string(&messages(&exchanges, "Bonjour")).unwrap(),
+      r#"[{"role":"user","content":"Salut 0"},{"role":"assistant","content":"Oui"},{"role":"user","content":"Bonjour"}]"#
+    );
+  }
+}
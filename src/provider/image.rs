@@ -0,0 +1,35 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+/// Media types the vision APIs take.
+const MEDIA_TYPES: &[&str] = &["image/gif", "image/jpeg", "image/png", "image/webp"];
+
+/// Tokens an image roughly takes at the size clients scale them down to.
+pub const IMAGE_TOKENS: usize = 1600;
+
+/// An image sent along a prompt.
+pub struct Image {
+  pub media_type: &'static str,
+  /// Base64-encoded.
+  pub data: String,
+}
+
+impl Image {
+  /// Parses a base64 `data:` URL, `None` if it isn't one of an image type the
+  /// APIs take.
+  pub fn from_data_url(url: &str) -> Option<Self> {
+    let (media_type, data) = url.strip_prefix("data:")?.split_once(";base64,")?;
+    let media_type = MEDIA_TYPES.iter().find(|&&ty| ty == media_type)?;
+
+    BASE64.decode(data).ok()?;
+
+    Some(Self {
+      media_type,
+      data: data.to_owned(),
+    })
+  }
+
+  pub fn data_url(&self) -> String {
+    format!("data:{};base64,{}", self.media_type, self.data)
+  }
+}
@@ -0,0 +1,234 @@
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use hyper::Body;
+use rand::Rng;
+use tokio::{fs, time};
+
+use super::{interrupt, retry, text_chunk, Failure, ProviderError};
+use crate::config::Mock;
+use crate::util;
+
+const LOREM: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod \
+                     tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, \
+                     quis nostrud exercitation ullamco laboris nisi ut aliquip ex ea commodo \
+                     consequat.\n\nDuis aute irure dolor in reprehenderit in voluptate velit esse \
+                     cillum dolore eu fugiat nulla pariatur. Excepteur sint occaecat cupidatat non \
+                     proident, sunt in culpa qui officia deserunt mollit anim id est laborum.";
+
+#[derive(Clone, Copy)]
+pub enum MockMode {
+  /// Replies with the prompt.
+  Echo,
+  Lorem,
+  /// Replies with the content of the fixture files in turn.
+  Replay,
+}
+
+impl FromStr for MockMode {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "echo" => Ok(Self::Echo),
+      "lorem" => Ok(Self::Lorem),
+      "replay" => Ok(Self::Replay),
+      _ => bail!("expected 'echo', 'lorem' or 'replay'"),
+    }
+  }
+}
+
+/// Parsed from the error codes sent to clients, `interrupted` cuts a reply in
+/// the middle.
+#[derive(Clone, Copy)]
+pub enum MockFailure {
+  Unreachable,
+  Rejected,
+  RateLimited,
+  MalformedResponse,
+  Timeout,
+  ContentFiltered,
+  Interrupted,
+}
+
+impl FromStr for MockFailure {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "upstream_unreachable" => Ok(Self::Unreachable),
+      "upstream_rejected" => Ok(Self::Rejected),
+      "upstream_rate_limited" => Ok(Self::RateLimited),
+      "malformed_response" => Ok(Self::MalformedResponse),
+      "timeout" => Ok(Self::Timeout),
+      "content_filtered" => Ok(Self::ContentFiltered),
+      "interrupted" => Ok(Self::Interrupted),
+      _ => bail!("unknown failure '{s}'"),
+    }
+  }
+}
+
+impl MockFailure {
+  fn error(self) -> ProviderError {
+    match self {
+      Self::Unreachable => ProviderError::Unreachable("mock failure".to_owned()),
+      Self::Rejected => ProviderError::Rejected(hyper::StatusCode::FORBIDDEN),
+      Self::RateLimited => ProviderError::RateLimited,
+      Self::MalformedResponse => ProviderError::MalformedResponse("mock failure".to_owned()),
+      Self::Timeout | Self::Interrupted => ProviderError::Timeout,
+      Self::ContentFiltered => ProviderError::ContentFiltered,
+    }
+  }
+
+  /// Fails like a real provider would, unreachable upstreams never got the
+  /// prompt.
+  fn failure(self) -> Failure {
+    Failure {
+      undelivered: matches!(self, Self::Unreachable),
+      err: self.error(),
+    }
+  }
+}
+
+/// A reply, cut halfway by `interrupted` failures.
+struct Reply {
+  text: String,
+  interrupted: bool,
+}
+
+/// Streams canned replies without any network access.
+pub struct Provider {
+  cfg: Mock,
+  max_retries: u32,
+  next_fixture: AtomicUsize,
+}
+
+impl Provider {
+  pub fn new(cfg: Mock, max_retries: u32) -> Self {
+    Self {
+      cfg,
+      max_retries,
+      next_fixture: AtomicUsize::new(0),
+    }
+  }
+
+  /// Fails at random with the configured failure, or as scripted by a fixture.
+  async fn attempt(&self, prompt: &str) -> Result<Reply, Failure> {
+    let fails = rand::thread_rng().gen_bool(self.cfg.failure_rate);
+
+    time::sleep(self.cfg.latency).await;
+
+    if fails && !matches!(self.cfg.failure, MockFailure::Interrupted) {
+      return Err(self.cfg.failure.failure());
+    }
+
+    let text = match self.cfg.mode {
+      MockMode::Echo => prompt.to_owned(),
+      MockMode::Lorem => LOREM.to_owned(),
+      MockMode::Replay => {
+        let reply = self.replay().await?;
+
+        return Ok(Reply {
+          interrupted: reply.interrupted || fails,
+          ..reply
+        });
+      }
+    };
+
+    Ok(Reply {
+      text,
+      interrupted: fails,
+    })
+  }
+
+  /// Reads the next fixture, `.fail` ones hold an error code and, for
+  /// `interrupted`, the reply it cuts on the following lines.
+  async fn replay(&self) -> Result<Reply, Failure> {
+    let fixtures = self.fixtures().await.map_err(ProviderError::Internal)?;
+    let path = &fixtures[self.next_fixture.fetch_add(1, Ordering::Relaxed) % fixtures.len()];
+    let text = fs::read_to_string(path)
+      .await
+      .map_err(|err| ProviderError::Internal(err.into()))?;
+
+    if path.extension() != Some(OsStr::new("fail")) {
+      return Ok(Reply {
+        text,
+        interrupted: false,
+      });
+    }
+
+    let (code, text) = text.split_once('\n').unwrap_or((&text, ""));
+
+    match code.trim().parse().map_err(ProviderError::Internal)? {
+      MockFailure::Interrupted => Ok(Reply {
+        text: text.to_owned(),
+        interrupted: true,
+      }),
+      failure => Err(failure.failure()),
+    }
+  }
+
+  /// Lists the fixtures on every prompt so that they can be edited while the
+  /// server runs.
+  async fn fixtures(&self) -> anyhow::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(&self.cfg.fixtures).await?;
+    let mut fixtures = Vec::new();
+
+    while let Some(entry) = entries.next_entry().await? {
+      if entry.file_type().await?.is_file() {
+        fixtures.push(entry.path());
+      }
+    }
+
+    if fixtures.is_empty() {
+      return Err(anyhow!("no fixture in {}", self.cfg.fixtures.display()));
+    }
+
+    fixtures.sort();
+
+    Ok(fixtures)
+  }
+}
+
+#[async_trait]
+impl super::Provider for Provider {
+  async fn ask<'a>(
+    &self,
+    prompt: &str,
+    _state: Option<&str>,
+    _model: Option<&str>,
+  ) -> Result<(Option<String>, Body), ProviderError> {
+    // failures go through the retries of real providers
+    let reply = retry(self.max_retries, || self.attempt(prompt)).await?;
+    let latency = self.cfg.latency;
+    let (mut tx, rx) = Body::channel();
+
+    util::spawn_in_current_span(async move {
+      let chunks = reply.text.split_inclusive(' ').collect::<Vec<_>>();
+      // interrupted replies stop halfway
+      let len = if reply.interrupted {
+        chunks.len() / 2
+      } else {
+        chunks.len()
+      };
+
+      for chunk in &chunks[..len] {
+        if tx.send_data(text_chunk(chunk.to_string())).await.is_err() {
+          return;
+        }
+
+        time::sleep(latency).await;
+      }
+
+      if reply.interrupted {
+        interrupt(tx, MockFailure::Interrupted.error()).await;
+      }
+    });
+
+    Ok((None, rx))
+  }
+}
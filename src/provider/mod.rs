@@ -1,34 +1,428 @@
+mod anthropic;
 mod ava;
 mod bai;
 mod deepai;
+mod error;
+mod gemini;
+mod history;
+mod image;
+mod local;
+mod mock;
+#[cfg(test)]
+mod stub;
 mod you;
 
 use std::collections::HashMap;
+use std::future::Future;
+use std::io::ErrorKind as IoErrorKind;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use hyper::Body;
+use futures::stream::{self, StreamExt};
+use hyper::body::{Bytes, HttpBody, Sender};
+use hyper::client::connect::Connect;
+use hyper::{Body, Client, Request};
+use libregpt::stream::ERROR_MARK;
+use rand::Rng;
+use tokio::io::AsyncBufReadExt;
+use tokio::time;
+use tokio_util::io::StreamReader;
+use tracing::{error, warn};
+
+use crate::config::{Config, Timeouts};
+use crate::util::{BodyStream, IdleTimeout};
+
+pub use error::*;
+pub use image::{Image, IMAGE_TOKENS};
+pub use local::{ChatTemplate, LocalApi};
+pub use mock::{MockFailure, MockMode};
+
+/// Context window of the models whose limit isn't known.
+const DEFAULT_CONTEXT_LIMIT: usize = 4096;
+
+/// Delay before the first retry, doubled for each following one.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Longest delay between two retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 #[async_trait]
 pub trait Provider: Send + Sync {
+  /// `model` is one of `models`, or `None` for the default one.
   async fn ask<'a>(
     &self,
     prompt: &str,
     state: Option<&str>,
-  ) -> anyhow::Result<(Option<String>, Body)>;
+    model: Option<&str>,
+  ) -> Result<(Option<String>, Body), ProviderError>;
+
+  /// Like `ask`, with images attached to the prompt. Only the models that
+  /// `accepts_images` take them.
+  async fn ask_with_images<'a>(
+    &self,
+    _prompt: &str,
+    _images: &[Image],
+    _state: Option<&str>,
+    _model: Option<&str>,
+  ) -> Result<(Option<String>, Body), ProviderError> {
+    Err(ProviderError::ImagesUnsupported)
+  }
+
+  /// Rebuilds the state of a conversation held by another provider from its
+  /// `history`, and returns it along with the prompt to send.
+  fn adopt(&self, history: &str, prompt: &str) -> Result<(String, Option<String>), ProviderError> {
+    let exchanges = history::exchanges(Some(history))?;
+
+    if exchanges.is_empty() {
+      return Ok((prompt.to_owned(), None));
+    }
+
+    Ok((prompt.to_owned(), Some(history::chat_state(&exchanges)?)))
+  }
+
+  /// Models a client can pick from, the first one being the default.
+  fn models(&self) -> &[String] {
+    &[]
+  }
+
+  /// Tokens `model` can take in, prompt and reply included.
+  fn context_limit(&self, _model: Option<&str>) -> usize {
+    DEFAULT_CONTEXT_LIMIT
+  }
+
+  /// Whether `model` takes images along the prompt.
+  fn accepts_images(&self, _model: Option<&str>) -> bool {
+    false
+  }
+
+  /// Whether the prompts are billed to the operator.
+  fn is_paid(&self) -> bool {
+    false
+  }
 }
 
+pub const NAMES: &[&str] = &[
+  "anthropic",
+  "ava",
+  "bai",
+  "deepai",
+  "gemini",
+  "local",
+  "mock",
+  "you",
+];
+
 pub type Map = HashMap<&'static str, Box<dyn Provider>>;
 
-pub fn s() -> Map {
+pub fn s(cfg: &Config) -> Map {
   let mut providers = HashMap::new();
 
-  providers.insert("ava", Box::new(ava::Provider::new()) as Box<dyn Provider>);
-  providers.insert("bai", Box::new(bai::Provider::new()) as Box<dyn Provider>);
+  providers.insert(
+    "ava",
+    Box::new(ava::Provider::new(cfg.upstream("ava"))) as Box<dyn Provider>,
+  );
+  providers.insert(
+    "bai",
+    Box::new(bai::Provider::new(cfg.upstream("bai"))) as Box<dyn Provider>,
+  );
   providers.insert(
     "deepai",
-    Box::new(deepai::Provider::new()) as Box<dyn Provider>,
+    Box::new(deepai::Provider::new(cfg.upstream("deepai"))) as Box<dyn Provider>,
   );
-  providers.insert("you", Box::new(you::Provider::new()) as Box<dyn Provider>);
+  providers.insert(
+    "you",
+    Box::new(you::Provider::new(cfg.upstream("you"))) as Box<dyn Provider>,
+  );
+
+  if let Some(anthropic) = cfg.anthropic.clone() {
+    providers.insert(
+      "anthropic",
+      Box::new(anthropic::Provider::new(
+        cfg.upstream("anthropic"),
+        anthropic,
+      )) as Box<dyn Provider>,
+    );
+  }
+
+  if let Some(gemini) = cfg.gemini.clone() {
+    providers.insert(
+      "gemini",
+      Box::new(gemini::Provider::new(cfg.upstream("gemini"), gemini)) as Box<dyn Provider>,
+    );
+  }
+
+  if let Some(local) = cfg.local.clone() {
+    providers.insert(
+      "local",
+      Box::new(local::Provider::new(cfg.upstream("local"), local)) as Box<dyn Provider>,
+    );
+  }
+
+  if let Some(mock) = cfg.mock.clone() {
+    providers.insert(
+      "mock",
+      Box::new(mock::Provider::new(mock, cfg.max_retries)) as Box<dyn Provider>,
+    );
+  }
 
   providers
 }
+
+/// Serves the `mock` provider alone.
+#[cfg(test)]
+pub fn mock(cfg: crate::config::Mock, max_retries: u32) -> Map {
+  HashMap::from([(
+    "mock",
+    Box::new(mock::Provider::new(cfg, max_retries)) as Box<dyn Provider>,
+  )])
+}
+
+/// Sends the request made by `build` and waits for the first bytes of the
+/// reply, the rest of which fails if it stalls for longer than the inter-token
+/// timeout.
+async fn send<C>(
+  client: &Client<C>,
+  timeouts: Timeouts,
+  max_retries: u32,
+  build: impl Fn() -> Result<Request<Body>, ProviderError>,
+) -> Result<Body, ProviderError>
+where
+  C: Connect + Clone + Send + Sync + 'static,
+{
+  retry(max_retries, || async {
+    try_send(client, timeouts, build()?).await
+  })
+  .await
+}
+
+/// A failed attempt at sending a prompt.
+struct Failure {
+  err: ProviderError,
+  /// Whether the upstream didn't get the prompt, because the connection to it
+  /// failed.
+  undelivered: bool,
+}
+
+impl Failure {
+  /// Retrying an attempt the upstream got could bill the prompt or post it in
+  /// a thread twice, unless it was rate limited.
+  fn is_retryable(&self) -> bool {
+    self.undelivered || matches!(self.err, ProviderError::RateLimited)
+  }
+}
+
+impl From<ProviderError> for Failure {
+  fn from(err: ProviderError) -> Self {
+    Self {
+      err,
+      undelivered: false,
+    }
+  }
+}
+
+/// Makes attempts at sending a prompt until one succeeds, or fails in a way
+/// that isn't retryable or for the `max_retries`th time. Nothing has been
+/// streamed to the client yet, and attempts are spaced by a jittered
+/// exponential backoff.
+async fn retry<T, F, Fut>(max_retries: u32, mut attempt: F) -> Result<T, ProviderError>
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<T, Failure>>,
+{
+  let mut retries = 0;
+
+  loop {
+    match attempt().await {
+      Ok(res) => return Ok(res),
+      Err(failure) if failure.is_retryable() && retries < max_retries => {
+        let backoff = backoff(retries);
+        let delay = rand::thread_rng().gen_range(backoff / 2..=backoff);
+
+        retries += 1;
+        warn!(
+          "attempt {retries} failed, retrying in {delay:?}: {}",
+          failure.err
+        );
+        time::sleep(delay).await;
+      }
+      Err(failure) => return Err(failure.err),
+    }
+  }
+}
+
+/// Delay before a retry, once `retries` were made, before the jitter.
+fn backoff(retries: u32) -> Duration {
+  2u32
+    .checked_pow(retries)
+    .and_then(|factor| RETRY_BACKOFF.checked_mul(factor))
+    .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF))
+}
+
+async fn try_send<C>(
+  client: &Client<C>,
+  timeouts: Timeouts,
+  req: Request<Body>,
+) -> Result<Body, Failure>
+where
+  C: Connect + Clone + Send + Sync + 'static,
+{
+  let first_chunk = async {
+    let res = client.request(req).await.map_err(|err| Failure {
+      undelivered: err.is_connect(),
+      err: err.into(),
+    })?;
+    let mut body = check_status(res)?.into_body();
+    let chunk = body.data().await.transpose().map_err(ProviderError::from)?;
+
+    Ok::<_, Failure>((chunk, body))
+  };
+  let (chunk, body) = time::timeout(timeouts.first_token, first_chunk)
+    .await
+    .map_err(|_| ProviderError::Timeout)??;
+
+  Ok(Body::wrap_stream(stream::iter(chunk.map(Ok)).chain(
+    IdleTimeout::new(BodyStream::from(body), timeouts.inter_token),
+  )))
+}
+
+/// Reads a reply line by line.
+struct Lines {
+  reader: StreamReader<BodyStream, Bytes>,
+  line: String,
+}
+
+impl Lines {
+  fn new(body: Body) -> Self {
+    Self {
+      reader: StreamReader::new(BodyStream::from(body)),
+      line: String::with_capacity(1 << 10),
+    }
+  }
+
+  /// Returns the next line without its line break, or `None` once the reply
+  /// ended. Characters split between two chunks are put back together, the
+  /// last line is returned even if the upstream disconnected in its middle.
+  async fn next(&mut self) -> Result<Option<&str>, ProviderError> {
+    self.line.clear();
+
+    match self.reader.read_line(&mut self.line).await {
+      Ok(0) => Ok(None),
+      Ok(_) => Ok(Some(self.line.trim_end_matches(['\r', '\n']))),
+      Err(err) if err.kind() == IoErrorKind::InvalidData => {
+        Err(ProviderError::MalformedResponse("invalid UTF-8".to_owned()))
+      }
+      Err(err) => Err(err.into()),
+    }
+  }
+}
+
+/// Makes a chunk of a reply stream out of text sent by an upstream, dropping
+/// the control character the error records start with.
+fn text_chunk(text: impl Into<Bytes>) -> Bytes {
+  let text = text.into();
+
+  if !text.contains(&(ERROR_MARK as u8)) {
+    return text;
+  }
+
+  text
+    .iter()
+    .copied()
+    .filter(|&byte| byte != ERROR_MARK as u8)
+    .collect::<Vec<_>>()
+    .into()
+}
+
+/// Ends a reply stream early so that the client can tell it's incomplete,
+/// after sending it the error.
+async fn interrupt(mut tx: Sender, err: ProviderError) {
+  error!("reply interrupted: {err}");
+
+  drop(tx.send_data(err.record().into()).await);
+  // the data is sent through the same channel, so it goes first
+  tx.abort();
+}
+
+#[cfg(test)]
+mod tests {
+  use hyper::StatusCode;
+
+  use super::stub::{self, Reply, Stub, TEXT};
+  use super::*;
+
+  const REPLY: &[u8] = include_bytes!("fixtures/ava/reply.txt");
+
+  fn reply() -> Reply {
+    Reply::recorded(REPLY, REPLY.len())
+  }
+
+  #[test]
+  fn error_marks_are_dropped_from_text() {
+    assert_eq!(text_chunk("Bonjour ☕"), "Bonjour ☕");
+    assert_eq!(
+      text_chunk("\u{1e}{\"error\":\"x\"}\u{1e}"),
+      "{\"error\":\"x\"}"
+    );
+  }
+
+  #[test]
+  fn backoff_is_capped() {
+    assert_eq!(backoff(0), RETRY_BACKOFF);
+    assert_eq!(backoff(1), RETRY_BACKOFF * 2);
+    assert_eq!(backoff(5), Duration::from_secs(16));
+    assert_eq!(backoff(6), MAX_BACKOFF);
+    assert_eq!(backoff(32), MAX_BACKOFF);
+    assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+  }
+
+  #[tokio::test]
+  async fn rate_limited_prompts_are_retried() {
+    let (res, requests) = stub::ask(
+      ava::Provider::new,
+      1,
+      vec![Reply::Status(StatusCode::TOO_MANY_REQUESTS), reply()],
+    )
+    .await;
+
+    assert_eq!(res.unwrap(), (TEXT.to_owned(), None));
+    assert_eq!(requests, 2);
+  }
+
+  #[tokio::test]
+  async fn retries_are_bounded() {
+    let (res, requests) = stub::ask(
+      ava::Provider::new,
+      2,
+      vec![Reply::Status(StatusCode::TOO_MANY_REQUESTS)],
+    )
+    .await;
+
+    assert!(matches!(res, Err(ProviderError::RateLimited)));
+    assert_eq!(requests, 3);
+  }
+
+  #[tokio::test]
+  async fn delivered_prompts_are_not_retried() {
+    for status in [
+      StatusCode::BAD_REQUEST,
+      StatusCode::INTERNAL_SERVER_ERROR,
+      StatusCode::BAD_GATEWAY,
+      StatusCode::SERVICE_UNAVAILABLE,
+    ] {
+      let (res, requests) =
+        stub::ask(ava::Provider::new, 2, vec![Reply::Status(status), reply()]).await;
+
+      assert!(res.is_err(), "{status}");
+      assert_eq!(requests, 1, "{status}");
+    }
+  }
+
+  #[tokio::test]
+  async fn refused_connections_are_retried() {
+    let stub = Stub::start_late(Duration::from_millis(100), vec![reply()]).await;
+    let res = stub::ask_stub(ava::Provider::new(stub.upstream(2))).await;
+
+    assert_eq!(res.unwrap(), (TEXT.to_owned(), None));
+    assert_eq!(stub.requests(), 1);
+  }
+}
@@ -0,0 +1,243 @@
+//! A local upstream replaying recorded replies, so that the providers can be
+//! tested without network access.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::body::HttpBody;
+use hyper::{Body, StatusCode};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+use super::{Provider, ProviderError};
+use crate::config::{Timeouts, Upstream};
+use libregpt::stream::ERROR_MARK;
+
+/// The text of the recorded replies, chosen for its characters of two, three
+/// and four bytes.
+pub const TEXT: &str = "Bonjour ! Voilà un café ☕ — 日本語 too.";
+
+/// How the stub answers a request.
+#[derive(Clone)]
+pub enum Reply {
+  /// Fails with an empty body.
+  Status(StatusCode),
+  /// Streams a reply in chunks, then disconnects in the middle of the reply
+  /// unless it's `complete`.
+  Chunks {
+    chunks: Vec<Vec<u8>>,
+    complete: bool,
+  },
+}
+
+impl Reply {
+  /// Streams a recorded reply in chunks of `size` bytes, which split the
+  /// characters longer than that.
+  pub fn recorded(reply: &[u8], size: usize) -> Self {
+    Self::Chunks {
+      chunks: reply.chunks(size).map(<[u8]>::to_vec).collect(),
+      complete: true,
+    }
+  }
+
+  /// Streams a recorded reply with `line` inserted after its first `after`
+  /// lines.
+  pub fn with_line(reply: &[u8], after: usize, line: &[u8]) -> Self {
+    let at = reply
+      .iter()
+      .enumerate()
+      .filter(|(_, &byte)| byte == b'\n')
+      .take(after)
+      .last()
+      .map_or(0, |(i, _)| i + 1);
+    let reply = [&reply[..at], line, b"\n", &reply[at..]].concat();
+
+    Self::recorded(&reply, reply.len())
+  }
+
+  /// Streams the first `len` bytes of a recorded reply before disconnecting.
+  pub fn cut(reply: &[u8], len: usize) -> Self {
+    Self::Chunks {
+      chunks: vec![reply[..len].to_vec()],
+      complete: false,
+    }
+  }
+}
+
+/// Answers the requests with `replies` in turn, the last one repeating.
+pub struct Stub {
+  addr: SocketAddr,
+  requests: Arc<AtomicUsize>,
+}
+
+impl Stub {
+  pub async fn start(replies: Vec<Reply>) -> Self {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stub = Self {
+      addr: listener.local_addr().unwrap(),
+      requests: Arc::new(AtomicUsize::new(0)),
+    };
+
+    tokio::spawn(serve(listener, replies, stub.requests.clone()));
+
+    stub
+  }
+
+  /// Starts after `delay` on a port refusing connections until then.
+  pub async fn start_late(delay: Duration, replies: Vec<Reply>) -> Self {
+    let addr = TcpListener::bind("127.0.0.1:0")
+      .await
+      .unwrap()
+      .local_addr()
+      .unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let stub = Self {
+      addr,
+      requests: requests.clone(),
+    };
+
+    tokio::spawn(async move {
+      tokio::time::sleep(delay).await;
+      serve(TcpListener::bind(addr).await.unwrap(), replies, requests).await;
+    });
+
+    stub
+  }
+
+  /// Requests answered so far.
+  pub fn requests(&self) -> usize {
+    self.requests.load(Ordering::SeqCst)
+  }
+
+  pub fn upstream(&self, max_retries: u32) -> Upstream {
+    Upstream {
+      url: Some(Url::parse(&format!("http://{}/", self.addr)).unwrap()),
+      proxies: Vec::new(),
+      timeouts: Timeouts {
+        connect: Duration::from_secs(1),
+        first_token: Duration::from_secs(1),
+        inter_token: Duration::from_secs(1),
+      },
+      max_retries,
+    }
+  }
+}
+
+async fn serve(listener: TcpListener, replies: Vec<Reply>, requests: Arc<AtomicUsize>) {
+  loop {
+    let (stream, _) = listener.accept().await.unwrap();
+    let n = requests.fetch_add(1, Ordering::SeqCst);
+    let reply = replies[n.min(replies.len() - 1)].clone();
+
+    tokio::spawn(answer(stream, reply));
+  }
+}
+
+async fn answer(stream: TcpStream, reply: Reply) {
+  let mut stream = BufReader::new(stream);
+  let mut content_length = 0;
+  let mut line = String::new();
+
+  // the request is read before answering, as upstreams do
+  loop {
+    line.clear();
+    stream.read_line(&mut line).await.unwrap();
+
+    let Some((name, value)) = line.trim_end().split_once(": ") else {
+      if line.trim_end().is_empty() {
+        break;
+      }
+      continue;
+    };
+
+    if name.eq_ignore_ascii_case("content-length") {
+      content_length = value.parse().unwrap();
+    }
+  }
+
+  stream
+    .read_exact(&mut vec![0; content_length])
+    .await
+    .unwrap();
+
+  let (chunks, complete) = match reply {
+    Reply::Status(status) => {
+      let head = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+      stream.write_all(head.as_bytes()).await.unwrap();
+      return;
+    }
+    Reply::Chunks { chunks, complete } => (chunks, complete),
+  };
+
+  stream
+    .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n")
+    .await
+    .unwrap();
+
+  for chunk in chunks {
+    let mut encoded = format!("{:x}\r\n", chunk.len()).into_bytes();
+    encoded.extend_from_slice(&chunk);
+    encoded.extend_from_slice(b"\r\n");
+
+    // the client may be gone after a malformed line
+    if stream.write_all(&encoded).await.is_err() || stream.flush().await.is_err() {
+      return;
+    }
+  }
+
+  if complete {
+    drop(stream.write_all(b"0\r\n\r\n").await);
+  }
+}
+
+/// Asks a provider reaching the stub for a reply, and returns it along with
+/// the number of requests the stub got.
+pub async fn ask<P>(
+  new: fn(Upstream) -> P,
+  max_retries: u32,
+  replies: Vec<Reply>,
+) -> (Result<(String, Option<String>), ProviderError>, usize)
+where
+  P: Provider,
+{
+  let stub = Stub::start(replies).await;
+  let reply = ask_stub(new(stub.upstream(max_retries))).await;
+
+  (reply, stub.requests())
+}
+
+pub async fn ask_stub<P>(provider: P) -> Result<(String, Option<String>), ProviderError>
+where
+  P: Provider,
+{
+  let (_, body) = provider.ask("bonjour", None, None).await?;
+
+  Ok(read(body).await)
+}
+
+/// Reads a reply streamed by a provider, and the error code of the record
+/// ending it if it was interrupted.
+async fn read(mut body: Body) -> (String, Option<String>) {
+  let mut reply = Vec::new();
+
+  while let Some(Ok(chunk)) = body.data().await {
+    reply.extend_from_slice(&chunk);
+  }
+
+  let reply = String::from_utf8(reply).unwrap();
+
+  match reply.split_once(ERROR_MARK) {
+    Some((text, record)) => {
+      let record = serde_json::from_str::<serde_json::Value>(record).unwrap();
+
+      (
+        text.to_owned(),
+        Some(record["error"].as_str().unwrap().to_owned()),
+      )
+    }
+    None => (reply, None),
+  }
+}
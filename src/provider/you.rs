@@ -2,18 +2,17 @@ use std::borrow::Cow;
 
 use async_trait::async_trait;
 use boring::ssl::{SslConnector, SslMethod, SslVersion};
-use hyper::client::HttpConnector;
 use hyper::{header, Body, Client, Method, Request};
 use hyper_boring::HttpsConnector;
-use serde::Deserialize;
-use tokio::io::AsyncBufReadExt;
-use tokio::task;
-use tokio_util::io::StreamReader;
-use tracing::error;
+use serde::{Deserialize, Serialize};
 use url::Url;
 use uuid::Uuid;
 
-use crate::util::BodyStream;
+use super::history::{self, Exchange};
+use super::{interrupt, send, text_chunk, Lines, ProviderError, DEFAULT_CONTEXT_LIMIT};
+use crate::config::{Timeouts, Upstream};
+use crate::proxy::ProxyConnector;
+use crate::util;
 
 const CONNECTOR_CIPHER_LIST: &[&str] = &[
   "TLS_AES_128_GCM_SHA256",
@@ -44,21 +43,29 @@ const CONNECTOR_SIGNATURE_ALGORITHMS: &[&str] = &[
   "rsa_pkcs1_sha512",
 ];
 
+#[derive(Deserialize, Serialize)]
+struct ChatExchange {
+  question: String,
+  answer: String,
+}
+
 #[derive(Deserialize)]
 struct Data {
   #[serde(rename = "youChatToken")]
   token: String,
 }
 
+const URL: &str = "https://you.com/api/streamingSearch";
+
 pub struct Provider {
-  client: Client<HttpsConnector<HttpConnector>>,
+  url: Url,
+  client: Client<HttpsConnector<ProxyConnector>>,
+  timeouts: Timeouts,
+  max_retries: u32,
 }
 
 impl Provider {
-  pub fn new() -> Self {
-    let mut connector = HttpConnector::new();
-    connector.enforce_http(false);
-
+  pub fn new(upstream: Upstream) -> Self {
     // https://github.com/4JX/reqwest-impersonate/blob/fa96a507f4163ee8875db38129e363384105b0d0/src/browser/chrome/ver/v108.rs
 
     let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
@@ -79,10 +86,19 @@ impl Provider {
       .set_sigalgs_list(&CONNECTOR_SIGNATURE_ALGORITHMS.join(":"))
       .unwrap();
 
-    let connector = HttpsConnector::with_connector(connector, builder).unwrap();
+    let connector = HttpsConnector::with_connector(
+      ProxyConnector::new(upstream.proxies, upstream.timeouts.connect),
+      builder,
+    )
+    .unwrap();
     let client = Client::builder().build(connector);
 
-    Self { client }
+    Self {
+      url: upstream.url.unwrap_or_else(|| Url::parse(URL).unwrap()),
+      client,
+      timeouts: upstream.timeouts,
+      max_retries: upstream.max_retries,
+    }
   }
 }
 
@@ -92,8 +108,9 @@ impl super::Provider for Provider {
     &self,
     prompt: &str,
     state: Option<&str>,
-  ) -> anyhow::Result<(Option<String>, Body)> {
-    let mut url = Url::parse("https://you.com/api/streamingSearch").unwrap();
+    _model: Option<&str>,
+  ) -> Result<(Option<String>, Body), ProviderError> {
+    let mut url = self.url.clone();
     let (chat_id, chat) = state
       .and_then(|state| {
         if state.len() < 38 {
@@ -103,6 +120,31 @@ impl super::Provider for Provider {
         }
       })
       .unwrap_or_else(|| (Uuid::new_v4().to_string().into(), "[]"));
+    let mut exchanges = serde_json::from_str::<Vec<ChatExchange>>(chat)
+      .map_err(|_| ProviderError::InvalidState)?
+      .into_iter()
+      .map(|exchange| Exchange {
+        prompt: exchange.question,
+        reply: exchange.answer,
+      })
+      .collect();
+
+    history::trim(
+      &mut exchanges,
+      prompt,
+      DEFAULT_CONTEXT_LIMIT,
+      history::REPLY_TOKENS,
+    )?;
+
+    let chat = serde_json::to_string(
+      &exchanges
+        .into_iter()
+        .map(|exchange| ChatExchange {
+          question: exchange.prompt,
+          answer: exchange.reply,
+        })
+        .collect::<Vec<_>>(),
+    )?;
 
     {
       let mut query = url.query_pairs_mut();
@@ -118,47 +160,47 @@ impl super::Provider for Provider {
       );
       query.append_pair("queryTraceId", &chat_id);
       query.append_pair("domain", "youchat");
-      query.append_pair("chat", chat);
+      query.append_pair("chat", &chat);
       query.append_pair("chatId", &chat_id);
     }
 
-    let req = Request::builder()
-      .method(Method::GET)
-      .uri(url.as_str())
-      .header(header::USER_AGENT, "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/108.0.0.0 Safari/537.36")
-      .header(header::ACCEPT, "text/event-stream")
-      .header("referer", "https://you.com/search?q=who+are+you&tbm=youchat")
-      .header("sec-ch-ua", r#""Not_A Brand";v="8", "Chromium";v="108", "Google Chrome";v="108""#)
-      .header("sec-ch-ua-mobile", "?0")
-      .header("sec-ch-ua-platform", r#""Windows""#)
-      .header("sec-fetch-dest", "document")
-      .header("sec-fetch-mode", "navigate")
-      .header("sec-fetch-site", "none")
-      .header("sec-fetch-user", "?1")
-      .header(header::COOKIE, format!("safesearch_guest=Moderate; uuid_guest={}", Uuid::new_v4().to_string()))
-      .body(Body::empty())?;
-
-    let res = self.client.request(req).await?;
+    let res = send(&self.client, self.timeouts, self.max_retries, || {
+      Ok(
+        Request::builder()
+          .method(Method::GET)
+          .uri(url.as_str())
+          .header(header::USER_AGENT, "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/108.0.0.0 Safari/537.36")
+          .header(header::ACCEPT, "text/event-stream")
+          .header("referer", "https://you.com/search?q=who+are+you&tbm=youchat")
+          .header("sec-ch-ua", r#""Not_A Brand";v="8", "Chromium";v="108", "Google Chrome";v="108""#)
+          .header("sec-ch-ua-mobile", "?0")
+          .header("sec-ch-ua-platform", r#""Windows""#)
+          .header("sec-fetch-dest", "document")
+          .header("sec-fetch-mode", "navigate")
+          .header("sec-fetch-site", "none")
+          .header("sec-fetch-user", "?1")
+          .header(header::COOKIE, format!("safesearch_guest=Moderate; uuid_guest={}", Uuid::new_v4().to_string()))
+          .body(Body::empty())?,
+      )
+    })
+    .await?;
     let (mut tx, rx) = Body::channel();
 
-    task::spawn(async move {
-      let mut reader = StreamReader::new(BodyStream::from(res.into_body()));
-      let mut line = String::with_capacity(1 << 14);
+    util::spawn_in_current_span(async move {
+      let mut lines = Lines::new(res);
 
       loop {
-        match reader.read_line(&mut line).await {
-          Ok(0) => break,
-          Ok(_) => {
-            if line.starts_with(r#"data: {"youChatToken"#) {
-              match serde_json::from_str::<Data>(&line[6..]) {
-                Ok(data) => drop(tx.send_data(data.token.into()).await),
-                Err(err) => error!("failed to deserialize data line: {err}"),
-              }
-            }
-            line.clear();
-          }
+        let line = match lines.next().await {
+          Ok(Some(line)) => parse_line(line),
+          Ok(None) => break,
+          Err(err) => Err(err),
+        };
+
+        match line {
+          Ok(Some(token)) => drop(tx.send_data(text_chunk(token)).await),
+          Ok(None) => {}
           Err(err) => {
-            error!("failed to read line: {err}");
+            interrupt(tx, err).await;
             break;
           }
         }
@@ -167,4 +209,78 @@ impl super::Provider for Provider {
 
     Ok((Some(chat_id.into_owned()), rx))
   }
+
+  fn adopt(&self, history: &str, prompt: &str) -> Result<(String, Option<String>), ProviderError> {
+    let exchanges = history::exchanges(Some(history))?;
+
+    if exchanges.is_empty() {
+      return Ok((prompt.to_owned(), None));
+    }
+
+    let chat = serde_json::to_string(
+      &exchanges
+        .into_iter()
+        .map(|exchange| ChatExchange {
+          question: exchange.prompt,
+          answer: exchange.reply,
+        })
+        .collect::<Vec<_>>(),
+    )?;
+
+    // a new chat id, the one of the previous provider doesn't apply
+    Ok((prompt.to_owned(), Some(format!("{}{chat}", Uuid::new_v4()))))
+  }
+}
+
+/// Parses a line of the server-sent events of a reply, only the tokens of the
+/// answer are kept.
+fn parse_line(line: &str) -> Result<Option<String>, ProviderError> {
+  match line.strip_prefix("data: ") {
+    Some(data) if data.starts_with(r#"{"youChatToken""#) => serde_json::from_str::<Data>(data)
+      .map(|data| Some(data.token))
+      .map_err(|err| ProviderError::malformed_json(&err)),
+    _ => Ok(None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::stub::{self, Reply, TEXT};
+  use super::*;
+
+  const REPLY: &[u8] = include_bytes!("fixtures/you/reply.txt");
+
+  async fn ask(reply: Reply) -> (String, Option<String>) {
+    stub::ask(Provider::new, 0, vec![reply]).await.0.unwrap()
+  }
+
+  #[tokio::test]
+  async fn replies_are_streamed() {
+    // a byte per chunk splits every character that isn't ASCII, the other
+    // events are skipped
+    for size in [REPLY.len(), 100, 1] {
+      assert_eq!(
+        ask(Reply::recorded(REPLY, size)).await,
+        (TEXT.to_owned(), None)
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn malformed_lines_interrupt_replies() {
+    for line in [&b"data: {\"youChatToken\": 3}"[..], b"\xff"] {
+      assert_eq!(
+        ask(Reply::with_line(REPLY, 11, line)).await,
+        ("Bonjour".to_owned(), Some("malformed_response".to_owned()))
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn early_disconnects_interrupt_replies() {
+    let (text, record) = ask(Reply::cut(REPLY, REPLY.len() / 2)).await;
+
+    assert!(TEXT.starts_with(&text) && text.len() < TEXT.len(), "{text}");
+    assert_eq!(record.as_deref(), Some("upstream_unreachable"));
+  }
 }
@@ -1,29 +1,76 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::io::Error as IoError;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use axum::body::StreamBody;
-use axum::extract::{Query, State};
-use axum::http::{header, StatusCode};
+use axum::body::{self, Bytes, StreamBody};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
-use futures::stream::{self, StreamExt};
+use axum::{Extension, Form, Json};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
 use hyper::Body;
+use libregpt::stream::ERROR_MARK;
+use libregpt::{attachments, tokens, App, AppProps, Posted, Shared, SharedProps, Snapshot};
 use serde::Deserialize;
-use tracing::error;
-use yew::ServerRenderer;
+use serde_json::json;
+use tokio::sync::Semaphore;
+use tracing::{error, info_span, Instrument};
+use uuid::Uuid;
+use yew::{BaseComponent, ServerRenderer};
 
-use crate::provider;
+use crate::health::Statuses;
+use crate::logging;
+use crate::metrics::{self, MeteredStream};
+use crate::middleware::{AccessTokens, Identity, KeyUsage, TOKEN_COOKIE};
+use crate::provider::{self, Image, Provider, ProviderError};
+use crate::store::{self, Store};
+use crate::util::BodyStream;
 
+/// Renders the app, with the conversation in the path selected.
 pub async fn render(
   State((index_html_before, index_html_after)): State<(String, String)>,
-) -> impl IntoResponse {
-  let renderer = ServerRenderer::<libregpt::App>::new();
+  id: Option<Path<String>>,
+) -> Response {
+  let conversation_id = match id {
+    Some(Path(id)) => match Uuid::parse_str(&id) {
+      Ok(id) => Some(id),
+      Err(_) => return default().await.into_response(),
+    },
+    None => None,
+  };
 
+  page(
+    ServerRenderer::<App>::with_props(move || AppProps {
+      conversation_id,
+      posted: None,
+    }),
+    index_html_before,
+    index_html_after,
+  )
+}
+
+/// Streams a page with a component rendered in its body.
+fn page<C>(
+  renderer: ServerRenderer<C>,
+  index_html_before: String,
+  index_html_after: String,
+) -> Response
+where
+  C: BaseComponent,
+  C::Properties: Send,
+{
   StreamBody::new(
     stream::once(async move { index_html_before })
       .chain(renderer.render_stream())
       .chain(stream::once(async move { index_html_after }))
       .map(Result::<_, Infallible>::Ok),
   )
+  .into_response()
 }
 
 pub async fn default() -> (StatusCode, &'static str) {
@@ -35,36 +82,1090 @@ pub struct AskParams {
   provider: Box<str>,
   prompt: Box<str>,
   state: Option<Box<str>>,
+  /// Sent in place of `state` by a client switching from another provider.
+  history: Option<Box<str>>,
+  model: Option<Box<str>>,
+  /// JSON array of the `data:` URLs of the images attached to the prompt.
+  images: Option<Box<str>>,
 }
 
+/// Sends a prompt to a provider and streams its reply, which ends with an
+/// error record if the provider fails in its middle. The params are read from
+/// the query or, for long prompts, from a posted form.
 pub async fn ask(
   State(providers): State<Arc<provider::Map>>,
-  Query(params): Query<AskParams>,
-) -> Response<Body> {
-  let Some(provider) = providers.get(params.provider.as_ref()) else {
-    return Response::builder()
-      .status(StatusCode::BAD_REQUEST)
-      .body(Body::from("invalid provider param"))
-      .unwrap();
+  usage: Option<Extension<KeyUsage>>,
+  Form(params): Form<AskParams>,
+) -> Response {
+  let started_at = Instant::now();
+  let (name, provider) = match find_provider(&providers, &params.provider, params.model.as_deref())
+  {
+    Ok(provider) => provider,
+    Err(err) => return err.into_response(),
+  };
+  let model = params.model.as_deref();
+  let images = match params.images.as_deref().map(parse_images).transpose() {
+    Ok(images) => images.unwrap_or_default(),
+    Err(err) => return err.into_response(),
+  };
+
+  if !images.is_empty() && !provider.accepts_images(model) {
+    return ProviderError::ImagesUnsupported.into_response();
+  }
+
+  if let Some(Extension(usage)) = usage {
+    usage.record();
+  }
+
+  let metrics = metrics::get();
+  metrics.requests.with_label_values(&[name]).inc();
+
+  // the provider tasks inherit this span, never add the prompt or the state to
+  // it
+  let request_id = Uuid::new_v4();
+  let span = info_span!("ask", %request_id, provider = name);
+
+  let reply = async {
+    let (prompt, state) = match params.history.as_deref() {
+      Some(history) => provider.adopt(history, &params.prompt)?,
+      None => (
+        params.prompt.to_string(),
+        params.state.as_deref().map(String::from),
+      ),
+    };
+
+    if images.is_empty() {
+      provider.ask(&prompt, state.as_deref(), model).await
+    } else {
+      provider
+        .ask_with_images(&prompt, &images, state.as_deref(), model)
+        .await
+    }
   };
 
-  match provider.ask(&params.prompt, params.state.as_deref()).await {
+  match reply.instrument(span.clone()).await {
     Ok((msg_id, body)) => {
-      let mut builder =
-        Response::builder().header(header::CONTENT_TYPE, "application/octet-stream");
+      let mut builder = Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header("request-id", request_id.to_string());
 
       if let Some(msg_id) = msg_id {
         builder = builder.header("msg-id", msg_id);
       }
 
-      builder.body(body).unwrap()
+      builder
+        .body(body::boxed(Body::wrap_stream(end_with_error(
+          MeteredStream::new(BodyStream::from(body), name, started_at),
+        ))))
+        .unwrap()
     }
     Err(err) => {
-      error!("failed to ask to provider {}: {err}", params.provider);
-      Response::builder()
-        .status(StatusCode::INTERNAL_SERVER_ERROR)
-        .body(Body::from("unexpected error"))
-        .unwrap()
+      metrics.errors.with_label_values(&[name, "ask"]).inc();
+      span.in_scope(|| error!("failed to ask to provider: {err}"));
+
+      error_response(err, request_id)
+    }
+  }
+}
+
+/// Ends a reply that failed with an error record, the one sent by the provider
+/// or else one made from the error, instead of aborting it, which could lose
+/// the record.
+fn end_with_error<S>(stream: S) -> impl Stream<Item = Result<Bytes, IoError>>
+where
+  S: Stream<Item = Result<Bytes, IoError>>,
+{
+  // whether the record was sent, and whether the reply failed
+  stream.scan((false, false), |(recorded, failed), chunk| {
+    let chunk = match chunk {
+      _ if *failed => None,
+      Ok(chunk) => {
+        *recorded |= chunk.contains(&(ERROR_MARK as u8));
+        Some(Ok(chunk))
+      }
+      Err(_) if *recorded => None,
+      Err(err) => {
+        *failed = true;
+        Some(Ok(ProviderError::from(err).record().into()))
+      }
+    };
+
+    future::ready(chunk)
+  })
+}
+
+#[derive(Deserialize)]
+pub struct PromptForm {
+  prompt: String,
+  provider: Box<str>,
+  model: Option<Box<str>>,
+  /// The conversation so far, as rendered by the previous reply.
+  posted: Option<String>,
+}
+
+/// Replies to a prompt posted by a browser without JavaScript, waiting for the
+/// whole reply to render the page again with it.
+pub async fn respond(
+  State((providers, index_html_before, index_html_after)): State<(
+    Arc<provider::Map>,
+    String,
+    String,
+  )>,
+  usage: Option<Extension<KeyUsage>>,
+  headers: HeaderMap,
+  Path(id): Path<String>,
+  Form(form): Form<PromptForm>,
+) -> Response {
+  // other sites could post a conversation of their own to be rendered, and
+  // prompts billed to the access token of the cookie
+  if is_cross_site(&headers) {
+    return (StatusCode::FORBIDDEN, "cross-site form").into_response();
+  }
+
+  let Ok(conversation_id) = Uuid::parse_str(&id) else {
+    return default().await.into_response();
+  };
+  let mut posted = match form.posted.as_deref() {
+    Some(posted) => match serde_json::from_str::<Posted>(posted) {
+      Ok(posted) if posted.snapshot.is_valid() => posted,
+      _ => return (StatusCode::BAD_REQUEST, "invalid posted conversation").into_response(),
+    },
+    None => Posted {
+      snapshot: Snapshot {
+        name: String::new(),
+        messages: Vec::new(),
+        reply_providers: Vec::new(),
+      },
+      provider: String::new(),
+      model: None,
+      error: None,
+    },
+  };
+  let (name, provider) = match find_provider(&providers, &form.provider, form.model.as_deref()) {
+    Ok(provider) => provider,
+    Err(err) => return err.into_response(),
+  };
+
+  posted.provider = form.provider.into();
+  posted.model = form.model.map(Into::into);
+  posted.error = None;
+
+  if !form.prompt.trim().is_empty() {
+    if let Some(Extension(usage)) = usage {
+      usage.record();
+    }
+
+    let metrics = metrics::get();
+    metrics.requests.with_label_values(&[name]).inc();
+
+    let request_id = Uuid::new_v4();
+    let span = info_span!("respond", %request_id, provider = name);
+    let model = posted.model.as_deref();
+    let reply = async {
+      let (_, body) = if posted.snapshot.messages.is_empty() {
+        provider.ask(&form.prompt, None, model).await?
+      } else {
+        let history = posted
+          .snapshot
+          .messages
+          .iter()
+          .enumerate()
+          .map(|(i, msg)| {
+            let role = if i % 2 == 0 { "user" } else { "assistant" };
+
+            json!({ "role": role, "content": msg })
+          })
+          .collect::<Vec<_>>();
+        let (prompt, state) = provider.adopt(&serde_json::to_string(&history)?, &form.prompt)?;
+
+        provider.ask(&prompt, state.as_deref(), model).await?
+      };
+
+      Ok::<_, ProviderError>(hyper::body::to_bytes(body).await?)
+    };
+
+    match reply.instrument(span.clone()).await {
+      Ok(reply) => {
+        let mut prompt = form.prompt;
+        prompt.push('\n');
+
+        let mut reply = String::from_utf8_lossy(&reply).trim_end().to_owned();
+        reply.push('\n');
+
+        posted.snapshot.messages.push(prompt);
+        posted.snapshot.messages.push(reply);
+        posted
+          .snapshot
+          .reply_providers
+          .push(posted.provider.clone());
+      }
+      Err(err) => {
+        metrics.errors.with_label_values(&[name, "respond"]).inc();
+        span.in_scope(|| error!("failed to ask to provider: {err}"));
+
+        posted.error = Some(err.message());
+      }
+    }
+  }
+
+  page(
+    ServerRenderer::<App>::with_props(move || AppProps {
+      conversation_id: Some(conversation_id),
+      posted: Some(posted),
+    }),
+    index_html_before,
+    index_html_after,
+  )
+}
+
+/// Whether a form was posted from another site, as told by the browsers
+/// through the `Sec-Fetch-Site` header or else the `Origin` one.
+fn is_cross_site(headers: &HeaderMap) -> bool {
+  let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+  if let Some(site) = header("sec-fetch-site") {
+    return !matches!(site, "same-origin" | "none");
+  }
+
+  match header(header::ORIGIN.as_str()) {
+    Some(origin) => {
+      let origin_host = origin.split_once("://").map(|(_, host)| host);
+
+      origin_host.is_none() || origin_host != header(header::HOST.as_str())
+    }
+    None => false,
+  }
+}
+
+#[derive(Deserialize)]
+pub struct SummarizeParams {
+  provider: Box<str>,
+  model: Option<Box<str>>,
+}
+
+#[derive(Deserialize)]
+pub struct SummarizeBody {
+  /// Covers the messages before `messages`.
+  summary: Option<String>,
+  /// Alternating prompts and replies.
+  messages: Vec<String>,
+}
+
+/// Asks a provider to summarize the oldest messages of a conversation, which
+/// clients send in place of them afterwards.
+pub async fn summarize(
+  State(providers): State<Arc<provider::Map>>,
+  usage: Option<Extension<KeyUsage>>,
+  Query(params): Query<SummarizeParams>,
+  Json(body): Json<SummarizeBody>,
+) -> Response {
+  let (name, provider) = match find_provider(&providers, &params.provider, params.model.as_deref())
+  {
+    Ok(provider) => provider,
+    Err(err) => return err.into_response(),
+  };
+
+  if let Some(Extension(usage)) = usage {
+    usage.record();
+  }
+
+  let metrics = metrics::get();
+  metrics.requests.with_label_values(&[name]).inc();
+
+  let request_id = Uuid::new_v4();
+  let span = info_span!("summarize", %request_id, provider = name);
+  let summary = async {
+    let (_, reply) = provider
+      .ask(&summary_prompt(&body), None, params.model.as_deref())
+      .await?;
+    let reply = hyper::body::to_bytes(reply).await?;
+    let summary = String::from_utf8_lossy(&reply).trim().to_owned();
+
+    if summary.is_empty() {
+      return Err(ProviderError::MalformedResponse("empty summary".to_owned()));
+    }
+
+    Ok(summary)
+  }
+  .instrument(span.clone())
+  .await;
+
+  match summary {
+    Ok(summary) => Json(json!({ "summary": summary })).into_response(),
+    Err(err) => {
+      metrics.errors.with_label_values(&[name, "summarize"]).inc();
+      span.in_scope(|| error!("failed to summarize: {err}"));
+
+      error_response(err, request_id)
+    }
+  }
+}
+
+fn summary_prompt(body: &SummarizeBody) -> String {
+  let mut prompt = "Summarize the conversation below so that it can be continued from the \
+                    summary alone. Keep the facts, names, numbers and decisions it contains, \
+                    and reply with the summary only.\n\n"
+    .to_owned();
+
+  if let Some(summary) = body.summary.as_deref() {
+    prompt.push_str("Summary of the earlier messages:\n");
+    prompt.push_str(summary.trim());
+    prompt.push_str("\n\n");
+  }
+
+  for (i, msg) in body.messages.iter().enumerate() {
+    prompt.push_str(if i % 2 == 0 { "User: " } else { "Assistant: " });
+    prompt.push_str(msg.trim());
+    prompt.push_str("\n\n");
+  }
+
+  prompt
+}
+
+/// PDFs parsed at once. Crafted documents can keep the parser busy long past
+/// the timeout, holding on to their thread.
+static PDF_EXTRACTIONS: Semaphore = Semaphore::const_new(2);
+
+/// Longest a request waits for the text of a PDF.
+const PDF_EXTRACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+pub struct AttachmentParams {
+  provider: Box<str>,
+  model: Option<Box<str>>,
+}
+
+/// Extracts the text of a file attached to a prompt, truncated to fit in the
+/// context window of the model it's sent to.
+pub async fn extract_attachment(
+  State(providers): State<Arc<provider::Map>>,
+  Query(params): Query<AttachmentParams>,
+  body: Bytes,
+) -> Response {
+  let provider = match find_provider(&providers, &params.provider, params.model.as_deref()) {
+    Ok((_, provider)) => provider,
+    Err(err) => return err.into_response(),
+  };
+
+  let text = if body.starts_with(b"%PDF-") {
+    match extract_pdf(body).await {
+      Ok(text) => text,
+      Err(err) => return err.into_response(),
+    }
+  } else {
+    match String::from_utf8(body.into()) {
+      Ok(text) => text,
+      Err(_) => {
+        return (
+          StatusCode::UNSUPPORTED_MEDIA_TYPE,
+          "only text files and PDFs can be attached",
+        )
+          .into_response()
+      }
+    }
+  };
+
+  let text = text.trim();
+  let limit = provider.context_limit(params.model.as_deref()) / attachments::CONTEXT_DIVISOR;
+  let kept = tokens::truncate(text, limit);
+  let truncated = kept.len() < text.len();
+  let mut kept = kept.trim_end().to_owned();
+
+  // tells the model the text doesn't end there
+  if truncated {
+    kept.push_str(attachments::TRUNCATION_MARK);
+  }
+
+  Json(json!({ "text": kept, "truncated": truncated })).into_response()
+}
+
+/// Extracts the text of a PDF on one of the few threads set aside for it.
+async fn extract_pdf(body: Bytes) -> Result<String, (StatusCode, &'static str)> {
+  let extract = async {
+    let permit = PDF_EXTRACTIONS.acquire().await.unwrap();
+
+    // the parser panics on some malformed documents
+    tokio::task::spawn_blocking(move || {
+      let _permit = permit;
+      let doc = pdf_extract::Document::load_mem(&body).ok()?;
+
+      // the parser prints to the standard output about the encrypted ones
+      if doc.is_encrypted() {
+        return None;
+      }
+
+      let mut text = String::new();
+      pdf_extract::output_doc(&doc, &mut pdf_extract::PlainTextOutput::new(&mut text)).ok()?;
+
+      Some(text)
+    })
+    .await
+  };
+
+  match tokio::time::timeout(PDF_EXTRACTION_TIMEOUT, extract).await {
+    Ok(Ok(Some(text))) => Ok(text),
+    Ok(_) => Err((StatusCode::UNPROCESSABLE_ENTITY, "unreadable PDF")),
+    Err(_) => Err((
+      StatusCode::SERVICE_UNAVAILABLE,
+      "the PDF took too long to read",
+    )),
+  }
+}
+
+/// Parses the images attached to a prompt.
+fn parse_images(images: &str) -> Result<Vec<Image>, (StatusCode, &'static str)> {
+  let urls = serde_json::from_str::<Vec<String>>(images)
+    .map_err(|_| (StatusCode::BAD_REQUEST, "invalid images param"))?;
+
+  if urls.len() > attachments::MAX_IMAGES {
+    return Err((StatusCode::BAD_REQUEST, "too many images"));
+  }
+
+  urls
+    .into_iter()
+    .map(|url| Image::from_data_url(&url).ok_or((StatusCode::BAD_REQUEST, "invalid image")))
+    .collect()
+}
+
+/// Looks up the provider and the model a client asked for.
+fn find_provider<'p>(
+  providers: &'p provider::Map,
+  name: &str,
+  model: Option<&str>,
+) -> Result<(&'static str, &'p dyn Provider), (StatusCode, &'static str)> {
+  let Some((&name, provider)) = providers.get_key_value(name) else {
+    return Err((StatusCode::BAD_REQUEST, "invalid provider param"));
+  };
+
+  if let Some(model) = model {
+    if !provider.models().iter().any(|m| m == model) {
+      return Err((StatusCode::BAD_REQUEST, "invalid model param"));
+    }
+  }
+
+  Ok((name, provider.as_ref()))
+}
+
+fn error_response(err: ProviderError, request_id: Uuid) -> Response {
+  let mut res = err.into_response();
+  res.headers_mut().insert(
+    "request-id",
+    HeaderValue::try_from(request_id.to_string()).unwrap(),
+  );
+  res
+}
+
+/// Lists the ids of the conversations stored by a client, their versions and
+/// when they were last updated.
+pub async fn list_conversations(State(store): State<Store>, identity: Identity) -> Response {
+  match store.list(identity.as_str()).await {
+    Ok(entries) => Json(entries).into_response(),
+    Err(err) => err.into_response(),
+  }
+}
+
+pub async fn get_conversation(
+  State(store): State<Store>,
+  identity: Identity,
+  Path(id): Path<String>,
+) -> Response {
+  let id = match conversation_id(&id) {
+    Ok(id) => id,
+    Err(err) => return err.into_response(),
+  };
+
+  match store.get(identity.as_str(), &id).await {
+    Ok(Some((data, version))) => (
+      [
+        (
+          header::CONTENT_TYPE,
+          HeaderValue::from_static("application/octet-stream"),
+        ),
+        (header::ETAG, store::etag(version)),
+      ],
+      data,
+    )
+      .into_response(),
+    Ok(None) => default().await.into_response(),
+    Err(err) => err.into_response(),
+  }
+}
+
+/// Stores a conversation as the blob encrypted by the client, either new with
+/// `If-None-Match: *` or replacing the version in `If-Match`.
+pub async fn put_conversation(
+  State(store): State<Store>,
+  identity: Identity,
+  Path(id): Path<String>,
+  headers: HeaderMap,
+  body: Bytes,
+) -> Response {
+  let id = match conversation_id(&id) {
+    Ok(id) => id,
+    Err(err) => return err.into_response(),
+  };
+  let expected = match expected_version(&headers) {
+    Ok(expected) => expected,
+    Err(err) => return err.into_response(),
+  };
+
+  match store
+    .put(identity.as_str(), &id, body.to_vec(), expected)
+    .await
+  {
+    Ok(version) => (
+      StatusCode::NO_CONTENT,
+      [(header::ETAG, store::etag(version))],
+    )
+      .into_response(),
+    Err(err) => err.into_response(),
+  }
+}
+
+pub async fn delete_conversation(
+  State(store): State<Store>,
+  identity: Identity,
+  Path(id): Path<String>,
+) -> Response {
+  let id = match conversation_id(&id) {
+    Ok(id) => id,
+    Err(err) => return err.into_response(),
+  };
+
+  match store.delete(identity.as_str(), &id).await {
+    Ok(true) => StatusCode::NO_CONTENT.into_response(),
+    Ok(false) => default().await.into_response(),
+    Err(err) => err.into_response(),
+  }
+}
+
+/// Parses the version a client expects to replace, `None` if it creates the
+/// conversation.
+fn expected_version(headers: &HeaderMap) -> Result<Option<u64>, (StatusCode, &'static str)> {
+  if let Some(value) = headers.get(header::IF_MATCH) {
+    return value
+      .to_str()
+      .ok()
+      .and_then(|value| value.trim().trim_matches('"').parse().ok())
+      .map(Some)
+      .ok_or((StatusCode::BAD_REQUEST, "invalid If-Match header"));
+  }
+
+  if headers
+    .get(header::IF_NONE_MATCH)
+    .is_some_and(|value| value == "*")
+  {
+    return Ok(None);
+  }
+
+  Err((
+    StatusCode::PRECONDITION_REQUIRED,
+    "expected an If-Match or If-None-Match header",
+  ))
+}
+
+/// Normalizes the id of a conversation, a UUID generated by the client.
+fn conversation_id(id: &str) -> Result<String, (StatusCode, &'static str)> {
+  Uuid::parse_str(id)
+    .map(|id| id.to_string())
+    .map_err(|_| (StatusCode::BAD_REQUEST, "invalid conversation id"))
+}
+
+#[derive(Deserialize)]
+pub struct ShareBody {
+  #[serde(flatten)]
+  content: ShareContent,
+  /// Seconds the link stays valid for, forever if missing.
+  expires_in: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ShareContent {
+  Snapshot(Snapshot),
+  /// Base64 of the snapshot encrypted by the client, with a key the server
+  /// never sees.
+  Ciphertext(String),
+}
+
+/// Stores a snapshot of a conversation and returns the slug of its link.
+pub async fn create_share(
+  State(store): State<Store>,
+  identity: Identity,
+  Json(body): Json<ShareBody>,
+) -> Response {
+  let (data, encrypted) = match body.content {
+    ShareContent::Snapshot(snapshot) if snapshot.is_valid() => {
+      (serde_json::to_vec(&snapshot).unwrap(), false)
+    }
+    ShareContent::Snapshot(_) => {
+      return (StatusCode::BAD_REQUEST, "invalid snapshot").into_response();
+    }
+    ShareContent::Ciphertext(ciphertext) => match BASE64.decode(ciphertext) {
+      Ok(data) => (data, true),
+      Err(_) => return (StatusCode::BAD_REQUEST, "invalid ciphertext").into_response(),
+    },
+  };
+
+  match store
+    .create_share(
+      identity.as_str(),
+      data,
+      encrypted,
+      body.expires_in.map(Duration::from_secs),
+    )
+    .await
+  {
+    Ok(slug) => (StatusCode::CREATED, Json(json!({ "slug": slug }))).into_response(),
+    Err(err) => err.into_response(),
+  }
+}
+
+/// Returns the snapshot of a share, which the client decrypts if it's
+/// encrypted.
+pub async fn get_share(State(store): State<Store>, Path(slug): Path<String>) -> Response {
+  match store.get_share(&slug).await {
+    Ok(Some(share)) => {
+      let content_type = if share.encrypted {
+        "application/octet-stream"
+      } else {
+        "application/json"
+      };
+
+      ([(header::CONTENT_TYPE, content_type)], share.data).into_response()
+    }
+    Ok(None) => default().await.into_response(),
+    Err(err) => err.into_response(),
+  }
+}
+
+/// Renders a shared conversation read-only, encrypted ones being left to the
+/// client to decrypt.
+pub async fn render_share(
+  State((store, index_html_before, index_html_after)): State<(Store, String, String)>,
+  Path(slug): Path<String>,
+) -> Response {
+  let share = match store.get_share(&slug).await {
+    Ok(Some(share)) => share,
+    Ok(None) => return default().await.into_response(),
+    Err(err) => return err.into_response(),
+  };
+  let snapshot = if share.encrypted {
+    None
+  } else {
+    match serde_json::from_slice(&share.data) {
+      Ok(snapshot) => Some(snapshot),
+      Err(err) => {
+        error!(
+          "failed to read the share '{slug}': {}",
+          logging::json_error(&err)
+        );
+
+        return (StatusCode::INTERNAL_SERVER_ERROR, "unexpected error").into_response();
+      }
+    }
+  };
+  page(
+    ServerRenderer::<Shared>::with_props(move || SharedProps { slug, snapshot }),
+    index_html_before,
+    index_html_after,
+  )
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+  token: String,
+}
+
+/// Stores a valid access token in an HTTP-only cookie sent along every API
+/// request.
+pub async fn login(
+  State((tokens, tls)): State<(Arc<AccessTokens>, bool)>,
+  Form(form): Form<LoginForm>,
+) -> Response<Body> {
+  if tokens.usage(&form.token).is_none() {
+    return Response::builder()
+      .status(StatusCode::UNAUTHORIZED)
+      .body(Body::from("invalid access token"))
+      .unwrap();
+  }
+
+  let mut cookie = format!(
+    "{TOKEN_COOKIE}={}; Path=/api; Max-Age=31536000; HttpOnly; SameSite=Strict",
+    form.token
+  );
+
+  if tls {
+    cookie.push_str("; Secure");
+  }
+
+  let Ok(cookie) = HeaderValue::try_from(cookie) else {
+    return Response::builder()
+      .status(StatusCode::BAD_REQUEST)
+      .body(Body::from("access token can't be stored in a cookie"))
+      .unwrap();
+  };
+
+  Response::builder()
+    .status(StatusCode::NO_CONTENT)
+    .header(header::SET_COOKIE, cookie)
+    .body(Body::empty())
+    .unwrap()
+}
+
+pub async fn usage(usage: Option<Extension<KeyUsage>>) -> Response {
+  match usage {
+    Some(Extension(usage)) => Json(json!({ "requests": usage.requests() })).into_response(),
+    None => default().await.into_response(),
+  }
+}
+
+pub async fn status(State(statuses): State<Arc<Statuses>>) -> Response {
+  let statuses = statuses.read().unwrap();
+
+  Json(
+    statuses
+      .iter()
+      .map(|(&name, status)| (name, status.report()))
+      .collect::<HashMap<_, _>>(),
+  )
+  .into_response()
+}
+
+pub async fn metrics() -> impl IntoResponse {
+  (
+    [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+    metrics::get().encode(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use std::time::Duration;
+
+  use axum::http::Request;
+  use axum::{routing, Router};
+  use serde_json::Value;
+  use tempfile::TempDir;
+  use tower::ServiceExt;
+
+  use super::*;
+  use crate::config::Mock;
+  use crate::provider::{MockFailure, MockMode};
+  use crate::util::temp_dir;
+
+  fn mock(mode: MockMode, fixtures: PathBuf, failure: Option<MockFailure>) -> Mock {
+    Mock {
+      mode,
+      fixtures,
+      latency: Duration::ZERO,
+      failure_rate: if failure.is_some() { 1.0 } else { 0.0 },
+      failure: failure.unwrap_or(MockFailure::Timeout),
+    }
+  }
+
+  /// Replays the fixtures of `dir`, named after their order.
+  fn replay(dir: &TempDir) -> Mock {
+    mock(MockMode::Replay, dir.path().to_owned(), None)
+  }
+
+  fn app(cfg: Mock, max_retries: u32) -> Router {
+    Router::new()
+      .route("/api/ask", routing::get(ask))
+      .with_state(Arc::new(provider::mock(cfg, max_retries)))
+  }
+
+  async fn ask_mock(router: &Router, prompt: &str) -> (StatusCode, String) {
+    let res = router
+      .clone()
+      .oneshot(
+        Request::get(format!("/api/ask?provider=mock&prompt={prompt}"))
+          .body(Body::empty())
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+    (status, String::from_utf8(body.to_vec()).unwrap())
+  }
+
+  fn error_code(body: &str) -> String {
+    let json = serde_json::from_str::<Value>(body.trim_start_matches(ERROR_MARK)).unwrap();
+
+    json["error"].as_str().unwrap().to_owned()
+  }
+
+  fn respond_app() -> Router {
+    Router::new()
+      .route("/api/respond/:id", routing::post(respond))
+      .with_state((
+        Arc::new(provider::mock(
+          mock(MockMode::Echo, PathBuf::new(), None),
+          0,
+        )),
+        "<html><body>".to_owned(),
+        "</body></html>".to_owned(),
+      ))
+  }
+
+  async fn post_form(
+    router: &Router,
+    headers: &[(&str, &str)],
+    form: &[(&str, &str)],
+  ) -> (StatusCode, String) {
+    let mut req = Request::post("/api/respond/67e55044-10b1-426f-9247-bb680e5fe0c8")
+      .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+
+    for (name, value) in headers {
+      req = req.header(*name, *value);
+    }
+
+    let body = form
+      .iter()
+      .map(|(name, value)| format!("{name}={}", urlencoding(value)))
+      .collect::<Vec<_>>()
+      .join("&");
+    let res = router
+      .clone()
+      .oneshot(req.body(Body::from(body)).unwrap())
+      .await
+      .unwrap();
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+    (status, String::from_utf8(body.to_vec()).unwrap())
+  }
+
+  fn urlencoding(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+  }
+
+  #[tokio::test]
+  async fn cross_site_forms_are_refused() {
+    let router = respond_app();
+    let form = [("prompt", "a b"), ("provider", "mock")];
+
+    for headers in [
+      &[("sec-fetch-site", "cross-site")][..],
+      &[
+        ("sec-fetch-site", "same-site"),
+        ("origin", "http://localhost"),
+      ],
+      &[("origin", "https://example.com"), ("host", "localhost")],
+      &[("origin", "null"), ("host", "localhost")],
+    ] {
+      assert_eq!(
+        post_form(&router, headers, &form).await.0,
+        StatusCode::FORBIDDEN,
+        "{headers:?}"
+      );
+    }
+
+    for headers in [
+      &[
+        ("sec-fetch-site", "same-origin"),
+        ("origin", "https://example.com"),
+      ][..],
+      &[
+        ("origin", "http://localhost:8080"),
+        ("host", "localhost:8080"),
+      ],
+      // not sent by a browser
+      &[],
+    ] {
+      let (status, body) = post_form(&router, headers, &form).await;
+
+      assert_eq!(status, StatusCode::OK, "{headers:?}");
+      assert!(body.contains("a b"), "{body}");
+    }
+  }
+
+  #[tokio::test]
+  async fn posted_html_is_rendered_as_text() {
+    let posted = json!({
+      "snapshot": {
+        "name": "Forged",
+        "messages": ["<form action=\"https://example.com\"><button>Log in</button></form>\n", "<img src=x onerror=\"alert(1)\">\n"],
+        "reply_providers": ["mock"],
+      },
+      "provider": "mock",
+      "model": null,
+      "error": null,
+    });
+    let (status, body) = post_form(
+      &respond_app(),
+      &[("sec-fetch-site", "same-origin")],
+      &[
+        ("prompt", ""),
+        ("provider", "mock"),
+        ("posted", &posted.to_string()),
+      ],
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert!(
+      !body.contains("<img") && !body.contains("<button>"),
+      "{body}"
+    );
+    assert!(body.contains("&lt;img src=x onerror="), "{body}");
+  }
+
+  #[tokio::test]
+  async fn replies_are_streamed() {
+    let router = app(mock(MockMode::Echo, PathBuf::new(), None), 0);
+
+    assert_eq!(
+      ask_mock(&router, "a+b+c").await,
+      (StatusCode::OK, "a b c".to_owned())
+    );
+
+    let fixtures = temp_dir(&[("1.txt", "first"), ("2.txt", "second")]);
+    let router = app(replay(&fixtures), 0);
+
+    for reply in ["first", "second", "first"] {
+      assert_eq!(
+        ask_mock(&router, "prompt").await,
+        (StatusCode::OK, reply.to_owned())
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn failures_map_to_statuses() {
+    for (failure, status, code) in [
+      (
+        MockFailure::Unreachable,
+        StatusCode::BAD_GATEWAY,
+        "upstream_unreachable",
+      ),
+      (
+        MockFailure::Rejected,
+        StatusCode::SERVICE_UNAVAILABLE,
+        "upstream_rejected",
+      ),
+      (
+        MockFailure::RateLimited,
+        StatusCode::TOO_MANY_REQUESTS,
+        "upstream_rate_limited",
+      ),
+      (
+        MockFailure::MalformedResponse,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "malformed_response",
+      ),
+      (MockFailure::Timeout, StatusCode::GATEWAY_TIMEOUT, "timeout"),
+      (
+        MockFailure::ContentFiltered,
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "content_filtered",
+      ),
+    ] {
+      let router = app(mock(MockMode::Lorem, PathBuf::new(), Some(failure)), 0);
+      let (res_status, body) = ask_mock(&router, "prompt").await;
+
+      assert_eq!(res_status, status, "{code}");
+      assert_eq!(error_code(&body), code);
+    }
+  }
+
+  #[tokio::test]
+  async fn client_errors_map_to_statuses() {
+    let router = app(mock(MockMode::Echo, PathBuf::new(), None), 0);
+
+    for uri in [
+      "/api/ask?provider=none&prompt=a",
+      "/api/ask?provider=mock&prompt=a&model=none",
+      "/api/ask?provider=mock&prompt=a&images=[",
+    ] {
+      let res = router
+        .clone()
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+      assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+  }
+
+  #[tokio::test]
+  async fn interrupted_replies_end_with_an_error_record() {
+    let fixtures = temp_dir(&[("1.fail", "interrupted\na b c d")]);
+    let router = app(replay(&fixtures), 0);
+    let (status, body) = ask_mock(&router, "prompt").await;
+    let (reply, record) = body.split_once(ERROR_MARK).unwrap();
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(reply, "a b ");
+    assert_eq!(error_code(record), "timeout");
+
+    let router = app(
+      mock(
+        MockMode::Echo,
+        PathBuf::new(),
+        Some(MockFailure::Interrupted),
+      ),
+      0,
+    );
+    let (status, body) = ask_mock(&router, "a+b+c+d").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with(&format!("a b {ERROR_MARK}")), "{body}");
+  }
+
+  #[tokio::test]
+  async fn undelivered_and_rate_limited_prompts_are_retried() {
+    for failure in ["upstream_unreachable", "upstream_rate_limited"] {
+      let fixtures = temp_dir(&[("1.fail", failure), ("2.txt", "reply")]);
+      let router = app(replay(&fixtures), 1);
+
+      assert_eq!(
+        ask_mock(&router, "prompt").await,
+        (StatusCode::OK, "reply".to_owned()),
+        "{failure}"
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn retries_are_bounded() {
+    let fixtures = temp_dir(&[
+      ("1.fail", "upstream_rate_limited"),
+      ("2.fail", "upstream_rate_limited"),
+      ("3.txt", "reply"),
+    ]);
+    let router = app(replay(&fixtures), 1);
+    let (status, body) = ask_mock(&router, "prompt").await;
+
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(error_code(&body), "upstream_rate_limited");
+    assert_eq!(
+      ask_mock(&router, "prompt").await,
+      (StatusCode::OK, "reply".to_owned())
+    );
+  }
+
+  #[tokio::test]
+  async fn delivered_prompts_are_not_retried() {
+    for (failure, status) in [
+      ("timeout", StatusCode::GATEWAY_TIMEOUT),
+      ("upstream_rejected", StatusCode::SERVICE_UNAVAILABLE),
+      ("malformed_response", StatusCode::INTERNAL_SERVER_ERROR),
+    ] {
+      let fixtures = temp_dir(&[("1.fail", failure), ("2.txt", "reply")]);
+      let router = app(replay(&fixtures), 2);
+      let (res_status, body) = ask_mock(&router, "prompt").await;
+
+      assert_eq!(res_status, status, "{failure}");
+      assert_eq!(error_code(&body), failure);
+      // the failed fixture was the only one used
+      assert_eq!(
+        ask_mock(&router, "prompt").await,
+        (StatusCode::OK, "reply".to_owned())
+      );
     }
   }
 }
@@ -0,0 +1,397 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use base64::Engine;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use tokio::task;
+use tracing::error;
+
+use crate::config;
+
+/// Schema changes, applied in order from the `user_version` of the database.
+const MIGRATIONS: &[&str] = &[
+  "CREATE TABLE conversations (
+    owner TEXT NOT NULL,
+    id TEXT NOT NULL,
+    data BLOB NOT NULL,
+    version INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (owner, id)
+  ) WITHOUT ROWID",
+  "CREATE TABLE shares (
+    slug TEXT PRIMARY KEY,
+    owner TEXT NOT NULL,
+    data BLOB NOT NULL,
+    encrypted INTEGER NOT NULL,
+    expires_at INTEGER,
+    created_at INTEGER NOT NULL
+  ) WITHOUT ROWID",
+];
+
+/// Random bytes of the slugs of the shares.
+const SLUG_LEN: usize = 16;
+
+/// Conversations of the clients, as the encrypted blobs they send, keyed by
+/// the identity of their owner.
+#[derive(Clone)]
+pub struct Store {
+  conn: Arc<Mutex<Connection>>,
+  max_conversations: usize,
+  max_shares: usize,
+}
+
+/// A stored conversation, without its content.
+#[derive(Serialize)]
+pub struct Entry {
+  pub id: String,
+  /// Incremented by each update.
+  pub version: u64,
+  /// Milliseconds since the Unix epoch.
+  pub updated_at: i64,
+}
+
+/// A snapshot of a conversation shared through a link.
+pub struct Share {
+  /// The JSON document of the snapshot, or the blob it's encrypted as.
+  pub data: Vec<u8>,
+  /// Whether the key of the snapshot only lives in the fragment of the link.
+  pub encrypted: bool,
+}
+
+impl Store {
+  pub fn open(cfg: &config::Store) -> anyhow::Result<Self> {
+    let mut conn = Connection::open(&cfg.path)
+      .with_context(|| format!("failed to open '{}'", cfg.path.display()))?;
+
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.busy_timeout(Duration::from_secs(5))?;
+    migrate(&mut conn)?;
+
+    Ok(Self {
+      conn: Arc::new(Mutex::new(conn)),
+      max_conversations: cfg.max_conversations,
+      max_shares: cfg.max_shares,
+    })
+  }
+
+  pub async fn list(&self, owner: &str) -> Result<Vec<Entry>, StoreError> {
+    let owner = owner.to_owned();
+
+    self
+      .run(move |conn| {
+        let mut stmt = conn.prepare_cached(
+          "SELECT id, version, updated_at FROM conversations WHERE owner = ?1 ORDER BY \
+           updated_at",
+        )?;
+        let entries = stmt
+          .query_map([owner], |row| {
+            Ok(Entry {
+              id: row.get(0)?,
+              version: row.get(1)?,
+              updated_at: row.get(2)?,
+            })
+          })?
+          .collect::<Result<_, _>>()?;
+
+        Ok(entries)
+      })
+      .await
+  }
+
+  /// Returns the blob of a conversation and its version.
+  pub async fn get(&self, owner: &str, id: &str) -> Result<Option<(Vec<u8>, u64)>, StoreError> {
+    let (owner, id) = (owner.to_owned(), id.to_owned());
+
+    self
+      .run(move |conn| {
+        Ok(
+          conn
+            .prepare_cached("SELECT data, version FROM conversations WHERE owner = ?1 AND id = ?2")?
+            .query_row([owner, id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?,
+        )
+      })
+      .await
+  }
+
+  /// Creates a conversation if `expected` is `None`, or else replaces the
+  /// given version of it, and returns the new version.
+  pub async fn put(
+    &self,
+    owner: &str,
+    id: &str,
+    data: Vec<u8>,
+    expected: Option<u64>,
+  ) -> Result<u64, StoreError> {
+    let (owner, id) = (owner.to_owned(), id.to_owned());
+    let max_conversations = self.max_conversations;
+
+    self
+      .run(move |conn| {
+        let tx = conn.transaction()?;
+        let current = tx
+          .query_row(
+            "SELECT version FROM conversations WHERE owner = ?1 AND id = ?2",
+            [&owner, &id],
+            |row| row.get::<_, u64>(0),
+          )
+          .optional()?;
+
+        // another client updated the conversation since this one fetched it
+        if current != expected {
+          return Err(StoreError::Conflict(current));
+        }
+
+        if current.is_none() && max_conversations != 0 {
+          let count = tx.query_row(
+            "SELECT COUNT(*) FROM conversations WHERE owner = ?1",
+            [&owner],
+            |row| row.get::<_, usize>(0),
+          )?;
+
+          if count >= max_conversations {
+            return Err(StoreError::Full);
+          }
+        }
+
+        let version = current.map_or(1, |version| version + 1);
+
+        tx.execute(
+          "INSERT OR REPLACE INTO conversations (owner, id, data, version, updated_at) VALUES \
+           (?1, ?2, ?3, ?4, ?5)",
+          params![owner, id, data, version, now_ms()],
+        )?;
+        tx.commit()?;
+
+        Ok(version)
+      })
+      .await
+  }
+
+  /// Returns whether the conversation existed.
+  pub async fn delete(&self, owner: &str, id: &str) -> Result<bool, StoreError> {
+    let (owner, id) = (owner.to_owned(), id.to_owned());
+
+    self
+      .run(move |conn| {
+        let deleted = conn
+          .prepare_cached("DELETE FROM conversations WHERE owner = ?1 AND id = ?2")?
+          .execute([owner, id])?;
+
+        Ok(deleted > 0)
+      })
+      .await
+  }
+
+  /// Stores a snapshot under a new random slug and returns it, the expired
+  /// ones being dropped along the way.
+  pub async fn create_share(
+    &self,
+    owner: &str,
+    data: Vec<u8>,
+    encrypted: bool,
+    expires_in: Option<Duration>,
+  ) -> Result<String, StoreError> {
+    let owner = owner.to_owned();
+    let slug = BASE64.encode(rand::random::<[u8; SLUG_LEN]>());
+    let max_shares = self.max_shares;
+
+    self
+      .run(move |conn| {
+        let now = now_ms();
+        let expires_at =
+          expires_in.map(|expires_in| now.saturating_add(expires_in.as_millis() as i64));
+        let tx = conn.transaction()?;
+
+        tx.prepare_cached("DELETE FROM shares WHERE expires_at <= ?1")?
+          .execute([now])?;
+
+        if max_shares != 0 {
+          let count = tx
+            .prepare_cached("SELECT COUNT(*) FROM shares WHERE owner = ?1")?
+            .query_row([&owner], |row| row.get::<_, usize>(0))?;
+
+          if count >= max_shares {
+            return Err(StoreError::TooManyShares);
+          }
+        }
+
+        tx.prepare_cached(
+          "INSERT INTO shares (slug, owner, data, encrypted, expires_at, created_at) VALUES \
+             (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?
+        .execute(params![slug, owner, data, encrypted, expires_at, now])?;
+        tx.commit()?;
+
+        Ok(slug)
+      })
+      .await
+  }
+
+  /// Returns `None` if the share is missing or expired.
+  pub async fn get_share(&self, slug: &str) -> Result<Option<Share>, StoreError> {
+    let slug = slug.to_owned();
+
+    self
+      .run(move |conn| {
+        Ok(
+          conn
+            .prepare_cached(
+              "SELECT data, encrypted FROM shares WHERE slug = ?1 AND (expires_at IS NULL OR \
+               expires_at > ?2)",
+            )?
+            .query_row(params![slug, now_ms()], |row| {
+              Ok(Share {
+                data: row.get(0)?,
+                encrypted: row.get(1)?,
+              })
+            })
+            .optional()?,
+        )
+      })
+      .await
+  }
+
+  /// Runs a query on the blocking thread pool, SQLite calls being
+  /// synchronous.
+  async fn run<T, F>(&self, f: F) -> Result<T, StoreError>
+  where
+    T: Send + 'static,
+    F: FnOnce(&mut Connection) -> Result<T, StoreError> + Send + 'static,
+  {
+    let conn = self.conn.clone();
+
+    task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+      .await
+      .unwrap()
+  }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+  /// The owner has as many conversations as allowed.
+  Full,
+  /// The owner has as many unexpired shares as allowed.
+  TooManyShares,
+  /// The conversation isn't at the expected version, the current one is
+  /// `None` if it doesn't exist.
+  Conflict(Option<u64>),
+  Database(rusqlite::Error),
+}
+
+impl Display for StoreError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Full => f.write_str("too many conversations are stored"),
+      Self::TooManyShares => f.write_str("too many conversations are shared"),
+      Self::Conflict(_) => f.write_str("conversation was updated by another client"),
+      Self::Database(err) => write!(f, "database error: {err}"),
+    }
+  }
+}
+
+impl Error for StoreError {}
+
+impl IntoResponse for StoreError {
+  fn into_response(self) -> Response {
+    match self {
+      Self::Full | Self::TooManyShares => {
+        (StatusCode::INSUFFICIENT_STORAGE, self.to_string()).into_response()
+      }
+      Self::Conflict(current) => {
+        let mut res = (StatusCode::PRECONDITION_FAILED, self.to_string()).into_response();
+
+        if let Some(version) = current {
+          res.headers_mut().insert(header::ETAG, etag(version));
+        }
+
+        res
+      }
+      Self::Database(err) => {
+        error!("failed to access the store: {err}");
+
+        (StatusCode::INTERNAL_SERVER_ERROR, "unexpected error").into_response()
+      }
+    }
+  }
+}
+
+impl From<rusqlite::Error> for StoreError {
+  fn from(err: rusqlite::Error) -> Self {
+    Self::Database(err)
+  }
+}
+
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+  let version = conn.pragma_query_value(None, "user_version", |row| row.get::<_, usize>(0))?;
+
+  if version > MIGRATIONS.len() {
+    bail!("the database was created by a newer version");
+  }
+
+  let tx = conn.transaction()?;
+
+  for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+    tx.execute_batch(migration)
+      .with_context(|| format!("failed to apply migration {}", i + 1))?;
+  }
+
+  tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+  tx.commit()?;
+
+  Ok(())
+}
+
+/// Version of a conversation, as an entity tag.
+pub fn etag(version: u64) -> HeaderValue {
+  HeaderValue::try_from(format!("\"{version}\"")).unwrap()
+}
+
+fn now_ms() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn shares_are_capped_per_identity() {
+    let store = Store::open(&config::Store {
+      path: ":memory:".into(),
+      max_conversations: 0,
+      max_shares: 2,
+    })
+    .unwrap();
+    let share = |owner: &'static str, expires_in| {
+      let store = store.clone();
+
+      async move {
+        store
+          .create_share(owner, b"{}".to_vec(), false, expires_in)
+          .await
+      }
+    };
+
+    share("a", None).await.unwrap();
+    share("a", Some(Duration::ZERO)).await.unwrap();
+    // the expired share doesn't count
+    share("a", None).await.unwrap();
+
+    assert!(matches!(
+      share("a", None).await,
+      Err(StoreError::TooManyShares)
+    ));
+    share("b", None).await.unwrap();
+  }
+}
@@ -0,0 +1,7 @@
+//! Reply streams, the plain text of a reply followed by an error record when
+//! the provider failed in its middle.
+
+/// Precedes the JSON error, with the same `error` and `message` as the ones
+/// of the failed requests, sent before a reply stream is aborted. The
+/// providers drop this control character from the text of the replies.
+pub const ERROR_MARK: char = '\u{1e}';
@@ -0,0 +1,98 @@
+//! Approximate token counts, shared by the server trimming conversations and
+//! the counter under the prompt.
+
+/// Tokens taken by the role and the separators of a message.
+pub const MESSAGE_OVERHEAD: usize = 4;
+
+/// Counts the tokens of a text the way BPE tokenizers roughly split it: four
+/// characters of a word, a punctuation mark or a CJK character per token,
+/// spaces being merged with the following word.
+pub fn count(text: &str) -> usize {
+  costs(text).map(|(_, cost)| cost).sum()
+}
+
+/// Returns the longest start of a text that fits in `max` tokens.
+pub fn truncate(text: &str, max: usize) -> &str {
+  let mut tokens = 0;
+
+  for (i, cost) in costs(text) {
+    tokens += cost;
+
+    if tokens > max {
+      return &text[..i];
+    }
+  }
+
+  text
+}
+
+/// Counts the tokens of the messages of a conversation.
+pub fn count_messages<'a>(messages: impl IntoIterator<Item = &'a str>) -> usize {
+  messages
+    .into_iter()
+    .map(|msg| count(msg) + MESSAGE_OVERHEAD)
+    .sum()
+}
+
+/// Yields the byte index of each character of a text along the tokens it
+/// starts.
+fn costs(text: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+  let mut word_len = 0;
+
+  text.char_indices().map(move |(i, char)| {
+    if char.is_alphanumeric() && !is_cjk(char) {
+      let cost = usize::from(word_len % 4 == 0);
+      word_len += 1;
+
+      return (i, cost);
+    }
+
+    word_len = 0;
+
+    (i, usize::from(!char.is_whitespace()))
+  })
+}
+
+/// Whether a character usually makes up a token by itself.
+fn is_cjk(char: char) -> bool {
+  matches!(
+    char,
+    '\u{2E80}'..='\u{9FFF}' | '\u{AC00}'..='\u{D7AF}' | '\u{F900}'..='\u{FAFF}'
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn words_are_split_every_four_characters() {
+    assert_eq!(count(""), 0);
+    assert_eq!(count("café"), 1);
+    assert_eq!(count("Bonjour"), 2);
+    assert_eq!(count("Bonjour, le   monde !"), 7);
+  }
+
+  #[test]
+  fn cjk_characters_are_tokens() {
+    assert_eq!(count("日本語"), 3);
+    assert_eq!(count("한국어 text"), 4);
+  }
+
+  #[test]
+  fn texts_are_truncated_at_character_boundaries() {
+    assert_eq!(truncate("Bonjour !", 0), "");
+    assert_eq!(truncate("Bonjour !", 2), "Bonjour ");
+    assert_eq!(truncate("Bonjour !", 3), "Bonjour !");
+    assert_eq!(truncate("日本語", 2), "日本");
+  }
+
+  #[test]
+  fn messages_have_an_overhead() {
+    assert_eq!(count_messages([]), 0);
+    assert_eq!(
+      count_messages(["Bonjour", "日本語"]),
+      2 + 3 + 2 * MESSAGE_OVERHEAD
+    );
+  }
+}
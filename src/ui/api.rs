@@ -0,0 +1,265 @@
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsCast;
+use wasm_streams::readable::IntoStream;
+use wasm_streams::ReadableStream;
+use web_sys::{window, TextDecodeOptions, TextDecoder};
+
+use crate::stream::ERROR_MARK;
+use crate::ui::reducers::{Conversation, Summary};
+
+/// Most recent messages left out of summaries, so that the provider still gets
+/// them word for word.
+const SUMMARY_KEPT_MESSAGES: usize = 4;
+
+/// A reply streamed by the server.
+pub struct Reply {
+  /// Lets some providers continue the conversation.
+  pub msg_id: Option<String>,
+  stream: IntoStream<'static>,
+  decoder: TextDecoder,
+  decode_options: TextDecodeOptions,
+  /// What was received of the error record, once its mark was.
+  error: Option<String>,
+}
+
+impl Reply {
+  /// Returns the next part of the reply, or why it ended early.
+  pub async fn next(&mut self) -> Option<Result<String, String>> {
+    loop {
+      // the server aborts the stream when the provider fails mid-reply, after
+      // sending the error
+      let chunk = match self.stream.next().await {
+        Some(Ok(chunk)) => chunk,
+        Some(Err(_)) => return Some(Err(self.error())),
+        None => return self.error.is_some().then(|| Err(self.error())),
+      };
+      let text = self
+        .decoder
+        .decode_with_buffer_source_and_options(&js_sys::Object::from(chunk), &self.decode_options)
+        .unwrap();
+
+      if let Some(error) = self.error.as_mut() {
+        error.push_str(&text);
+        continue;
+      }
+
+      match text.split_once(ERROR_MARK) {
+        Some((text, error)) => {
+          self.error = Some(error.to_owned());
+
+          if !text.is_empty() {
+            return Some(Ok(text.to_owned()));
+          }
+        }
+        None => return Some(Ok(text)),
+      }
+    }
+  }
+
+  fn error(&mut self) -> String {
+    match self
+      .error
+      .take()
+      .and_then(|error| serde_json::from_str::<ApiError>(&error).ok())
+    {
+      Some(err) => format!("The reply was interrupted: {}.", err.message),
+      None => "The reply was interrupted.".to_owned(),
+    }
+  }
+}
+
+pub struct AskError {
+  /// Zero if the server couldn't be reached.
+  pub status: u16,
+  pub message: String,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+  message: String,
+}
+
+#[derive(Serialize)]
+struct SummarizeBody<'m> {
+  summary: Option<&'m str>,
+  messages: Vec<&'m str>,
+}
+
+#[derive(Deserialize)]
+struct SummarizeResponse {
+  summary: String,
+}
+
+/// Extracted text of an attached file.
+#[derive(Deserialize)]
+pub struct Extracted {
+  /// Ends with the truncation mark if it was cut to fit in the context window.
+  pub text: String,
+}
+
+/// Sends a prompt to a provider through the server. The params are posted, as
+/// attachments make prompts too long for a URL.
+pub async fn ask(params: Vec<(&str, &str)>) -> Result<Reply, AskError> {
+  let mut url = window().unwrap().location().origin().unwrap();
+  url.push_str("/api/ask");
+
+  let res = gloo_net::http::Request::post(&url)
+    .header("content-type", "application/x-www-form-urlencoded")
+    .body(form(&params))
+    .map_err(|_| unreachable_error())?
+    .send()
+    .await
+    .map_err(|_| unreachable_error())?;
+
+  if !res.ok() {
+    let message = res
+      .text()
+      .await
+      .ok()
+      .and_then(|text| serde_json::from_str::<ApiError>(&text).ok())
+      .map_or_else(
+        || format!("The request failed with status {}.", res.status()),
+        |err| err.message,
+      );
+
+    return Err(AskError {
+      status: res.status(),
+      message,
+    });
+  }
+
+  let mut decode_options = TextDecodeOptions::new();
+  decode_options.stream(true);
+
+  Ok(Reply {
+    msg_id: res.headers().get("msg-id"),
+    stream: ReadableStream::from_raw(res.body().unwrap().dyn_into().unwrap()).into_stream(),
+    decoder: TextDecoder::new().unwrap(),
+    decode_options,
+    error: None,
+  })
+}
+
+/// Has the server extract the text of a file, truncated for a provider and
+/// model.
+pub async fn extract(
+  provider: &str,
+  model: Option<&str>,
+  bytes: &[u8],
+) -> Result<Extracted, AskError> {
+  let mut url = window().unwrap().location().origin().unwrap();
+  url.push_str("/api/attachments");
+
+  let mut params = Vec::with_capacity(2);
+  params.push(("provider", provider));
+
+  if let Some(model) = model {
+    params.push(("model", model));
+  }
+
+  let res = gloo_net::http::Request::post(&url)
+    .query(params)
+    .header("content-type", "application/octet-stream")
+    .body(js_sys::Uint8Array::from(bytes))
+    .map_err(|_| unreachable_error())?
+    .send()
+    .await
+    .map_err(|_| unreachable_error())?;
+
+  let status = res.status();
+  let text = res.text().await.unwrap_or_default();
+
+  if !res.ok() {
+    let message = match status {
+      413 => "The file is too large.".to_owned(),
+      415 => "Only text files and PDFs can be attached.".to_owned(),
+      422 => "The text of the PDF couldn't be extracted.".to_owned(),
+      503 => "The PDF took too long to read.".to_owned(),
+      _ => format!("The request failed with status {status}."),
+    };
+
+    return Err(AskError { status, message });
+  }
+
+  serde_json::from_str(&text).map_err(|_| AskError {
+    status,
+    message: "The server sent an invalid response.".to_owned(),
+  })
+}
+
+/// Asks the server to summarize the messages before the last ones, along with
+/// the previous summary.
+pub async fn summarize(conv: &Conversation) -> Option<Summary> {
+  let start = conv.summary.as_ref().map_or(0, |summary| summary.len);
+  let end = conv.messages.len().saturating_sub(SUMMARY_KEPT_MESSAGES);
+
+  if end <= start {
+    return None;
+  }
+
+  let mut url = window().unwrap().location().origin().unwrap();
+  url.push_str("/api/summarize");
+
+  let mut params = Vec::with_capacity(2);
+  params.push(("provider", conv.provider.as_ref()));
+
+  if let Some(model) = conv.model.as_deref() {
+    params.push(("model", model));
+  }
+
+  let body = serde_json::to_string(&SummarizeBody {
+    summary: conv.summary.as_ref().map(|summary| summary.text.as_ref()),
+    messages: conv.messages[start..end]
+      .iter()
+      .map(AsRef::as_ref)
+      .collect(),
+  })
+  .unwrap();
+  let res = gloo_net::http::Request::post(&url)
+    .query(params)
+    .header("content-type", "application/json")
+    .body(body)
+    .ok()?
+    .send()
+    .await
+    .ok()?;
+
+  if !res.ok() {
+    return None;
+  }
+
+  let text = res.text().await.ok()?;
+  let summary = serde_json::from_str::<SummarizeResponse>(&text)
+    .ok()?
+    .summary;
+
+  Some(Summary {
+    text: summary.into(),
+    len: end,
+  })
+}
+
+fn unreachable_error() -> AskError {
+  AskError {
+    status: 0,
+    message: "The server couldn't be reached.".to_owned(),
+  }
+}
+
+/// Encodes params as an URL-encoded form.
+fn form(params: &[(&str, &str)]) -> String {
+  let mut form = String::new();
+
+  for (key, value) in params {
+    if !form.is_empty() {
+      form.push('&');
+    }
+
+    form.push_str(key);
+    form.push('=');
+    form.push_str(&String::from(js_sys::encode_uri_component(value)));
+  }
+
+  form
+}
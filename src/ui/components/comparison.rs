@@ -0,0 +1,159 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use yew::{
+  function_component, html, use_effect_with_deps, use_reducer, Callback, Html, Properties,
+};
+
+use crate::ui::api;
+use crate::ui::components::Message;
+use crate::ui::reducers::{Choice, Columns, ColumnsAction};
+
+/// Request of one of the columns.
+#[derive(Clone, PartialEq)]
+pub struct Ask {
+  pub provider: Rc<str>,
+  pub name: &'static str,
+  pub model: Option<Rc<str>>,
+  pub state: Option<String>,
+  /// Sent in place of the state if the conversation comes from another
+  /// provider.
+  pub history: Option<String>,
+}
+
+#[derive(Properties, PartialEq)]
+pub struct ComparisonProps {
+  pub prompt: Rc<str>,
+  /// JSON array of the images attached to the prompt.
+  #[prop_or_default]
+  pub images: Option<Rc<str>>,
+  pub asks: Rc<[Ask]>,
+  pub on_choose: Callback<Choice>,
+  pub on_close: Callback<()>,
+}
+
+/// Streams the replies of several providers to the same prompt side by side.
+/// The prompt is sent once, on mount.
+#[function_component]
+pub fn Comparison(props: &ComparisonProps) -> Html {
+  let columns = use_reducer(|| Columns::new(props.asks.len()));
+
+  {
+    let columns = columns.clone();
+    let prompt = props.prompt.clone();
+    let images = props.images.clone();
+    let asks = props.asks.clone();
+
+    use_effect_with_deps(
+      move |_| {
+        let closed = Rc::new(Cell::new(false));
+
+        for (i, ask) in asks.iter().cloned().enumerate() {
+          let columns = columns.clone();
+          let prompt = prompt.clone();
+          let images = images.clone();
+          let closed = closed.clone();
+
+          wasm_bindgen_futures::spawn_local(async move {
+            let mut params = Vec::with_capacity(5);
+            params.push(("provider", ask.provider.as_ref()));
+            params.push(("prompt", prompt.as_ref()));
+
+            // the providers that don't take images reject the prompt
+            if let Some(images) = images.as_deref() {
+              params.push(("images", images));
+            }
+
+            if let Some(state) = ask.state.as_deref() {
+              params.push(("state", state));
+            }
+
+            if let Some(history) = ask.history.as_deref() {
+              params.push(("history", history));
+            }
+
+            if let Some(model) = ask.model.as_deref() {
+              params.push(("model", model));
+            }
+
+            match api::ask(params).await {
+              Ok(mut reply) => {
+                if let Some(msg_id) = reply.msg_id.take() {
+                  columns.dispatch(ColumnsAction::SetMessageId(i, msg_id));
+                }
+
+                while let Some(chunk) = reply.next().await {
+                  // the comparison was closed or replaced
+                  if closed.get() {
+                    return;
+                  }
+
+                  match chunk {
+                    Ok(chunk) => columns.dispatch(ColumnsAction::PushReply(i, chunk)),
+                    Err(err) => {
+                      columns.dispatch(ColumnsAction::SetError(i, err));
+                      break;
+                    }
+                  }
+                }
+              }
+              Err(err) => columns.dispatch(ColumnsAction::SetError(i, err.message)),
+            }
+
+            columns.dispatch(ColumnsAction::Finish(i));
+          });
+        }
+
+        move || closed.set(true)
+      },
+      (),
+    );
+  }
+
+  let on_close = props.on_close.reform(|_| ());
+  let mut prompt = props.prompt.to_string();
+  prompt.push('\n');
+
+  html! {
+    <div class="w-full flex flex-col gap-3 lg:gap-4">
+      <Message index={0} content={Rc::<str>::from(prompt)} />
+      <div class="flex gap-3 overflow-x-auto">
+        {for props.asks.iter().zip(columns.inner.iter()).map(|(ask, column)| {
+          let usable = column.done && column.error.is_none() && !column.reply.trim().is_empty();
+          let onclick = {
+            let on_choose = props.on_choose.clone();
+            let provider = ask.provider.clone();
+            let prompt = props.prompt.clone();
+            let reply = column.reply.clone();
+            let msg_id = column.msg_id.clone();
+
+            Callback::from(move |_| {
+              on_choose.emit(Choice {
+                provider: provider.clone(),
+                prompt: prompt.clone(),
+                reply: reply.clone(),
+                msg_id: msg_id.clone(),
+              });
+            })
+          };
+
+          html! {
+            <div key={ask.provider.as_ref()} class="flex-1 min-w-[16rem] p-3 rounded-xl bg-[#E1E1E1] dark:bg-[#151515] flex flex-col gap-3">
+              <span class="px-1 text-sm font-bold">{ask.name}</span>
+              if !column.reply.is_empty() {
+                <Message index={1} content={column.reply.clone()} />
+              } else if !column.done {
+                <span class="px-1 text-sm text-black/50 dark:text-white/50">{"Waiting for the reply..."}</span>
+              }
+              if let Some(error) = column.error.as_ref() {
+                <div class="px-3.5 py-3 rounded-xl bg-red-600/10 text-sm text-red-600 dark:text-red-400">{error.clone()}</div>
+              }
+              <button type="button" class="mt-auto px-3 py-2.5 rounded-xl bg-[#FF983F] dark:bg-[#FF7A1F] text-sm disabled:cursor-not-allowed disabled:opacity-50" disabled={!usable} {onclick}>{"Continue with this reply"}</button>
+            </div>
+          }
+        })}
+      </div>
+      <button type="button" class="self-center px-3 py-2.5 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm" onclick={on_close}>{"Close the comparison"}</button>
+    </div>
+  }
+}
@@ -0,0 +1,63 @@
+use web_sys::{window, HtmlInputElement};
+use yew::events::SubmitEvent;
+use yew::{function_component, html, use_node_ref, use_state, Callback, Html, Properties};
+
+#[derive(Properties, PartialEq)]
+pub struct LoginProps {
+  pub on_login: Callback<()>,
+}
+
+#[function_component]
+pub fn Login(props: &LoginProps) -> Html {
+  let token_ref = use_node_ref();
+  let invalid = use_state(|| false);
+  let onsubmit = {
+    let token_ref = token_ref.clone();
+    let invalid = invalid.clone();
+    let on_login = props.on_login.clone();
+
+    Callback::from(move |e: SubmitEvent| {
+      e.prevent_default();
+
+      let token_el: HtmlInputElement = token_ref.cast().unwrap();
+      let mut body = String::from("token=");
+      body.push_str(&String::from(js_sys::encode_uri_component(
+        &token_el.value(),
+      )));
+
+      let mut url = window().unwrap().location().origin().unwrap();
+      url.push_str("/api/login");
+
+      let invalid = invalid.clone();
+      let on_login = on_login.clone();
+
+      wasm_bindgen_futures::spawn_local(async move {
+        let res = gloo_net::http::Request::post(&url)
+          .header("content-type", "application/x-www-form-urlencoded")
+          .body(body)
+          .unwrap()
+          .send()
+          .await;
+
+        if res.is_ok_and(|res| res.ok()) {
+          on_login.emit(());
+        } else {
+          invalid.set(true);
+        }
+      });
+    })
+  };
+
+  html! {
+    <div class="absolute w-full h-full z-30 flex items-center justify-center bg-[#E1E1E1] dark:bg-[#151515]">
+      <form class="w-80 p-5 rounded-xl bg-[#EBEBEB] dark:bg-[#1A1A1A] flex flex-col gap-3" {onsubmit}>
+        <span class="px-2.5 py-2 w-fit rounded-xl bg-[#F5F5F5] dark:bg-[#292929] font-bold">{"LibreGPT"}</span>
+        <input ref={token_ref} type="password" required={true} autofocus=true placeholder="Access token" class="px-3 py-2.5 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm outline-none" />
+        if *invalid {
+          <span class="text-sm text-red-600">{"Invalid access token"}</span>
+        }
+        <button type="submit" class="px-3 py-2.5 rounded-xl bg-[#FF983F] dark:bg-[#FF7A1F] text-sm">{"Log in"}</button>
+      </form>
+    </div>
+  }
+}
@@ -1,11 +1,18 @@
 use std::rc::Rc;
 
+use pulldown_cmark::{Event, Options, Parser};
 use yew::{function_component, html, Html, Properties};
 
+use crate::attachments::{self, Attachment};
+use crate::tokens;
+
 #[derive(Properties, PartialEq)]
 pub struct MessageProps {
   pub index: usize,
   pub content: Rc<str>,
+  /// Shown above the bubble, like the provider of a reply.
+  #[prop_or_default]
+  pub label: Option<&'static str>,
 }
 
 #[function_component]
@@ -15,30 +22,109 @@ pub fn Message(props: &MessageProps) -> Html {
     "rounded-xl bg-[#F5F5F5] dark:bg-[#292929] break-words max-w-full flex flex-col gap-3"
       .to_owned();
 
-  if props.index % 2 == 0 {
+  // the files attached to a prompt are shown as chips in place of their text
+  let (text, attachments) = if props.index % 2 == 0 {
     container_class.push_str(" justify-end");
     bubble_class.push_str(" bg-[#FF983F] dark:bg-[#FF7A1F]");
+
+    attachments::split(&props.content)
+  } else {
+    (props.content.as_ref(), Vec::new())
+  };
+
+  if props.label.is_some() {
+    container_class.push_str(" flex-col items-start gap-1");
   }
 
-  let mut lines = props.content.lines();
+  let mut lines = text.lines();
   lines.next();
 
-  if lines.next().is_some() {
+  if lines.next().is_some() || !attachments.is_empty() {
     bubble_class.push_str(" px-4 py-3.5");
   } else {
     bubble_class.push_str(" px-3 py-2.5");
   }
 
-  let parser = pulldown_cmark::Parser::new_ext(&props.content, pulldown_cmark::Options::all());
-
-  let mut content = String::with_capacity(props.content.len() / 2 * 3);
-  pulldown_cmark::html::push_html(&mut content, parser);
+  let content = render_markdown(text);
 
   html! {
     <div class={container_class}>
+      if let Some(label) = props.label {
+        <span class="px-1 text-xs text-black/50 dark:text-white/50">{label}</span>
+      }
       <div class={bubble_class}>
         {Html::from_html_unchecked(content.into())}
+        if !attachments.is_empty() {
+          <div class="flex flex-wrap gap-1.5">
+            {for attachments.iter().map(|attachment| html! {
+              <span class="px-2 py-1 rounded-lg bg-black/10 text-xs whitespace-nowrap" title={attachment_title(attachment)}>{if attachment.is_image() { "🖼️ " } else { "📄 " }}{attachment.name}</span>
+            })}
+          </div>
+        }
       </div>
     </div>
   }
 }
+
+/// Renders the Markdown of a message, its raw HTML as text. Messages come from
+/// the providers, and from anyone for shared or posted conversations.
+fn render_markdown(text: &str) -> String {
+  let parser = Parser::new_ext(text, Options::all()).map(|event| match event {
+    Event::Html(html) => Event::Text(html),
+    event => event,
+  });
+
+  let mut content = String::with_capacity(text.len() / 2 * 3);
+  pulldown_cmark::html::push_html(&mut content, parser);
+
+  content
+}
+
+/// Describes what the provider got of an attached file.
+pub fn attachment_title(attachment: &Attachment) -> String {
+  if attachment.is_image() {
+    return "Image, only sent along this prompt".to_owned();
+  }
+
+  let tokens = tokens::count(&attachment.text);
+
+  if attachment.is_truncated() {
+    format!("~{tokens} tokens, truncated to fit in the context window")
+  } else {
+    format!("~{tokens} tokens")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use yew::ServerRenderer;
+
+  use super::*;
+
+  const FORGED: &str = "<form action=\"https://example.com\"><input name=\"token\"></form>\n\nClick <img src=x onerror=\"alert(1)\"> **here**";
+
+  #[test]
+  fn raw_html_is_text() {
+    assert_eq!(
+      render_markdown(FORGED),
+      "&lt;form action=&quot;https://example.com&quot;&gt;&lt;input name=&quot;token&quot;&gt;&lt;/form&gt;\n\
+       <p>Click &lt;img src=x onerror=&quot;alert(1)&quot;&gt; <strong>here</strong></p>\n"
+    );
+  }
+
+  #[tokio::test]
+  async fn messages_render_raw_html_as_text() {
+    for index in [0, 1] {
+      let html = ServerRenderer::<Message>::with_props(move || MessageProps {
+        index,
+        content: FORGED.into(),
+        label: None,
+      })
+      .render()
+      .await;
+
+      assert!(!html.contains("<form") && !html.contains("<img"), "{html}");
+      assert!(html.contains("&lt;img src=x onerror="), "{html}");
+    }
+  }
+}
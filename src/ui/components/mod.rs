@@ -1,5 +1,17 @@
+mod comparison;
+mod login;
 mod message;
+mod posted;
+mod share_form;
+mod shared;
+mod sync_form;
 mod theme_switcher;
 
+pub use comparison::*;
+pub use login::*;
 pub use message::*;
+pub use posted::*;
+pub use share_form::*;
+pub use shared::*;
+pub use sync_form::*;
 pub use theme_switcher::*;
@@ -0,0 +1,83 @@
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use web_sys::window;
+use yew::{function_component, html, use_state, Html, Properties};
+
+use crate::ui::reducers::Conversation;
+use crate::ui::share::Snapshot;
+
+/// Name of the hidden field carrying the conversation when the prompt form is
+/// posted without JavaScript.
+const POSTED_FIELD: &str = "posted";
+
+/// A conversation replied to by the server, for browsers without JavaScript.
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct Posted {
+  pub snapshot: Snapshot,
+  pub provider: String,
+  pub model: Option<String>,
+  /// Why the last prompt failed.
+  pub error: Option<String>,
+}
+
+impl Posted {
+  /// Reads the posted conversation back from the hidden field of the page.
+  pub fn from_document() -> Option<Self> {
+    let posted = window()?
+      .document()?
+      .query_selector(&format!("input[name={POSTED_FIELD}]"))
+      .ok()??
+      .get_attribute("value")?;
+
+    serde_json::from_str(&posted).ok()
+  }
+
+  pub fn conversation(&self) -> Conversation {
+    let mut conv = Conversation::new(self.provider.as_str().into());
+
+    if !self.snapshot.name.is_empty() {
+      conv.name = self.snapshot.name.as_str().into();
+    }
+
+    conv.model = self.model.as_deref().map(Rc::from);
+    conv.messages = self
+      .snapshot
+      .messages
+      .iter()
+      .map(|msg| Rc::from(msg.as_str()))
+      .collect();
+    conv.reply_providers = self
+      .snapshot
+      .reply_providers
+      .iter()
+      .map(|provider| Rc::from(provider.as_str()))
+      .collect();
+    conv.error = self.error.as_deref().map(Rc::from);
+
+    conv
+  }
+}
+
+#[derive(Properties, PartialEq)]
+pub struct PostedFieldProps {
+  pub posted: Posted,
+  /// Name of the conversation when the page was rendered.
+  pub name: Rc<str>,
+}
+
+/// Sends the posted conversation back along the next prompt posted without
+/// JavaScript, which ignores the later changes.
+#[function_component]
+pub fn PostedField(props: &PostedFieldProps) -> Html {
+  let value = use_state(|| {
+    let mut posted = props.posted.clone();
+    posted.snapshot.name = props.name.to_string();
+
+    serde_json::to_string(&posted).unwrap()
+  });
+
+  html! {
+    <input type="hidden" name={POSTED_FIELD} value={(*value).clone()} />
+  }
+}
@@ -0,0 +1,80 @@
+use web_sys::{HtmlInputElement, HtmlSelectElement};
+use yew::events::{FocusEvent, SubmitEvent};
+use yew::{
+  function_component, html, use_node_ref, use_state, Callback, Html, Properties, TargetCast,
+};
+
+use crate::ui::reducers::Conversation;
+use crate::ui::share;
+
+#[derive(Properties, PartialEq)]
+pub struct ShareFormProps {
+  pub conversation: Conversation,
+}
+
+/// Creates a read-only link to a snapshot of a conversation.
+#[function_component]
+pub fn ShareForm(props: &ShareFormProps) -> Html {
+  let sharing = use_state(|| Sharing::Idle);
+  let expiry_ref = use_node_ref();
+  let encrypted_ref = use_node_ref();
+  let onsubmit = {
+    let conv = props.conversation.clone();
+    let sharing = sharing.clone();
+    let expiry_ref = expiry_ref.clone();
+    let encrypted_ref = encrypted_ref.clone();
+
+    Callback::from(move |e: SubmitEvent| {
+      e.prevent_default();
+
+      let expires_in = expiry_ref
+        .cast::<HtmlSelectElement>()
+        .unwrap()
+        .value()
+        .parse()
+        .ok();
+      let encrypted = encrypted_ref.cast::<HtmlInputElement>().unwrap().checked();
+      let conv = conv.clone();
+      let sharing = sharing.clone();
+
+      sharing.set(Sharing::Creating);
+
+      wasm_bindgen_futures::spawn_local(async move {
+        match share::create(&conv, expires_in, encrypted).await {
+          Ok(link) => sharing.set(Sharing::Created(link)),
+          Err(err) => sharing.set(Sharing::Failed(err)),
+        }
+      });
+    })
+  };
+
+  html! {
+    <form class="w-full px-3.5 py-3 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm flex flex-wrap gap-3 items-center" {onsubmit}>
+      <select ref={expiry_ref} class="px-2 py-1.5 rounded-lg bg-[#EBEBEB] dark:bg-[#1A1A1A] outline-none">
+        <option value="">{"Never expires"}</option>
+        <option value="3600">{"Expires in an hour"}</option>
+        <option value="86400">{"Expires in a day"}</option>
+        <option value="604800">{"Expires in a week"}</option>
+        <option value="2592000">{"Expires in 30 days"}</option>
+      </select>
+      <label class="flex gap-1.5 items-center cursor-pointer" title="The server can't read the conversation, the key only lives in the link">
+        <input ref={encrypted_ref} type="checkbox" checked={true} />
+        {"Encrypt"}
+      </label>
+      <button type="submit" disabled={*sharing == Sharing::Creating} class="px-3 py-1.5 rounded-lg bg-[#FF983F] dark:bg-[#FF7A1F] disabled:cursor-not-allowed disabled:opacity-50">{"Create a read-only link"}</button>
+      if let Sharing::Created(link) = &*sharing {
+        <input type="text" readonly={true} value={link.clone()} class="w-full px-2 py-1.5 rounded-lg bg-[#EBEBEB] dark:bg-[#1A1A1A] outline-none" onfocus={Callback::from(|e: FocusEvent| e.target_unchecked_into::<HtmlInputElement>().select())} />
+      } else if let Sharing::Failed(error) = *sharing {
+        <span class="w-full text-red-600 dark:text-red-400">{error}</span>
+      }
+    </form>
+  }
+}
+
+#[derive(PartialEq)]
+enum Sharing {
+  Idle,
+  Creating,
+  Created(String),
+  Failed(&'static str),
+}
@@ -0,0 +1,101 @@
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use web_sys::window;
+use yew::{function_component, html, use_effect_with_deps, use_state, Html, Properties};
+
+use crate::provider_name;
+use crate::ui::components::{Message, ThemeSwitcher};
+use crate::ui::share::{self, Snapshot};
+
+/// Attribute the props are rendered in by the server, for the client to
+/// hydrate the page with the same ones.
+const PROPS_ATTRIBUTE: &str = "data-share";
+
+#[derive(Clone, Deserialize, PartialEq, Properties, Serialize)]
+pub struct SharedProps {
+  pub slug: String,
+  /// `None` if the snapshot is encrypted, it's decrypted by the client then.
+  pub snapshot: Option<Snapshot>,
+}
+
+impl SharedProps {
+  /// Returns the props of the shared conversation rendered by the server,
+  /// `None` on the other pages.
+  pub fn from_document() -> Option<Self> {
+    let props = window()?
+      .document()?
+      .query_selector(&format!("[{PROPS_ATTRIBUTE}]"))
+      .ok()??
+      .get_attribute(PROPS_ATTRIBUTE)?;
+
+    serde_json::from_str(&props).ok()
+  }
+}
+
+/// A shared conversation, read-only.
+#[function_component]
+pub fn Shared(props: &SharedProps) -> Html {
+  let snapshot = use_state(|| props.snapshot.clone());
+  let error = use_state(|| None::<&'static str>);
+
+  {
+    let snapshot = snapshot.clone();
+    let error = error.clone();
+    let slug = props.slug.clone();
+
+    use_effect_with_deps(
+      move |_| {
+        if snapshot.is_none() {
+          wasm_bindgen_futures::spawn_local(async move {
+            match share::decrypt(&slug).await {
+              Ok(decrypted) => snapshot.set(Some(decrypted)),
+              Err(err) => error.set(Some(err)),
+            }
+          });
+        }
+      },
+      (),
+    );
+  }
+
+  let props_attribute = serde_json::to_string(props).unwrap();
+  let name = snapshot
+    .as_ref()
+    .map_or("Shared conversation", |snapshot| snapshot.name.as_str())
+    .to_owned();
+
+  html! {
+    <div data-share={props_attribute} class="h-screen flex lg:p-4 bg-[#E1E1E1] dark:bg-[#151515] text-[#333333] dark:text-[#F5F5F5]">
+      <div class="flex-1 w-full p-5 pb-6 lg:pb-5 lg:rounded-xl xl:px-[13vw] bg-[#EBEBEB] dark:bg-[#1A1A1A] flex flex-col gap-5 items-center">
+        <div class="w-full flex gap-3 items-center">
+          <a href="/" class="px-2.5 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] font-bold">{"LibreGPT"}</a>
+          <span class="px-3 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] whitespace-nowrap overflow-hidden text-ellipsis">{name}</span>
+          <span class="ml-auto text-xs text-black/50 dark:text-white/50 whitespace-nowrap">{"Read-only"}</span>
+          <ThemeSwitcher />
+        </div>
+
+        <div class="flex-1 w-full flex flex-col gap-3 overflow-y-auto lg:gap-4">
+          if let Some(snapshot) = snapshot.as_ref() {
+            {for snapshot.messages.iter().enumerate().map(|(i, msg)| {
+              let label = (i % 2 == 1)
+                .then(|| snapshot.reply_providers.get(i / 2))
+                .flatten()
+                .and_then(|provider| provider_name(provider));
+
+              html! {
+                <Message key={i} index={i} content={Rc::<str>::from(msg.as_str())} {label} />
+              }
+            })}
+          } else if let Some(error) = *error {
+            <div class="px-3.5 py-3 rounded-xl bg-red-600/10 text-sm text-red-600 dark:text-red-400">{error}</div>
+          } else {
+            <span class="px-1 text-sm text-black/50 dark:text-white/50">{"Decrypting the conversation..."}</span>
+          }
+        </div>
+
+        <a href="/" class="px-3 py-2.5 rounded-xl bg-[#FF983F] dark:bg-[#FF7A1F] text-sm">{"Start a conversation"}</a>
+      </div>
+    </div>
+  }
+}
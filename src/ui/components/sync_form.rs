@@ -0,0 +1,124 @@
+use web_sys::HtmlInputElement;
+use yew::events::SubmitEvent;
+use yew::{
+  function_component, html, use_effect_with_deps, use_mut_ref, use_node_ref, use_state, Callback,
+  Html, Properties, UseReducerHandle,
+};
+
+use crate::ui::reducers::{Conversations, ConversationsAction};
+use crate::ui::sync;
+
+#[derive(Properties, PartialEq)]
+pub struct SyncFormProps {
+  pub conversations: UseReducerHandle<Conversations>,
+  /// Called once the server turns out to store conversations.
+  pub on_available: Callback<()>,
+}
+
+/// Unlocks the conversations stored on the server with a passphrase, then
+/// keeps them in sync. Renders nothing if the server doesn't store them.
+#[function_component]
+pub fn SyncForm(props: &SyncFormProps) -> Html {
+  // conversations as stored on the server, `None` until they're unlocked
+  let synced = use_mut_ref(|| None::<sync::Synced>);
+  let status = use_state(|| SyncStatus::Unavailable);
+
+  {
+    let status = status.clone();
+    let on_available = props.on_available.clone();
+
+    use_effect_with_deps(
+      move |_| {
+        wasm_bindgen_futures::spawn_local(async move {
+          if sync::is_available().await {
+            status.set(SyncStatus::Locked);
+            on_available.emit(());
+          }
+        });
+      },
+      (),
+    );
+  }
+
+  {
+    let synced = synced.clone();
+
+    use_effect_with_deps(
+      move |conversations| sync::push_changes(&synced, conversations, conversations.dispatcher()),
+      props.conversations.clone(),
+    );
+  }
+
+  let passphrase_ref = use_node_ref();
+  let unlock = {
+    let conversations = props.conversations.clone();
+    let synced = synced.clone();
+    let status = status.clone();
+    let passphrase_ref = passphrase_ref.clone();
+
+    Callback::from(move |e: SubmitEvent| {
+      e.prevent_default();
+
+      let input = passphrase_ref.cast::<HtmlInputElement>().unwrap();
+      let passphrase = input.value();
+
+      input.set_value("");
+      status.set(SyncStatus::Unlocking);
+
+      let conversations = conversations.clone();
+      let synced = synced.clone();
+      let status = status.clone();
+
+      wasm_bindgen_futures::spawn_local(async move {
+        match sync::unlock(passphrase).await {
+          Ok((state, loaded)) => {
+            *synced.borrow_mut() = Some(state);
+            status.set(SyncStatus::Unlocked);
+            conversations.dispatch(ConversationsAction::LoadConversations(loaded));
+          }
+          Err(sync::UnlockError::WrongPassphrase) => status.set(SyncStatus::WrongPassphrase),
+          Err(sync::UnlockError::Failed) => status.set(SyncStatus::Failed),
+        }
+      });
+    })
+  };
+
+  html! {
+    if *status == SyncStatus::Unlocked {
+      <span class="px-1 text-xs text-black/50 dark:text-white/50">{"Synced, end-to-end encrypted"}</span>
+    } else if *status != SyncStatus::Unavailable {
+      <form class="flex flex-col gap-1.5" onsubmit={unlock}>
+        <div class="flex gap-1.5">
+          <input ref={passphrase_ref} type="password" required={true} minlength="8" placeholder="Sync passphrase" disabled={*status == SyncStatus::Unlocking} class="w-full min-w-0 px-3 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm outline-none" />
+          <button type="submit" disabled={*status == SyncStatus::Unlocking} class="px-3 py-2 rounded-xl bg-[#F5F5F5] dark:bg-[#292929] text-sm disabled:cursor-not-allowed disabled:opacity-50">{"Sync"}</button>
+        </div>
+        if let Some(error) = status.error() {
+          <span class="px-1 text-xs text-red-600 dark:text-red-400">{error}</span>
+        }
+      </form>
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SyncStatus {
+  /// The server doesn't store conversations.
+  Unavailable,
+  Locked,
+  Unlocking,
+  WrongPassphrase,
+  Failed,
+  Unlocked,
+}
+
+impl SyncStatus {
+  fn error(self) -> Option<&'static str> {
+    match self {
+      Self::WrongPassphrase => {
+        Some("Wrong passphrase, the stored conversations can't be decrypted.")
+      }
+      Self::Failed => Some("The stored conversations couldn't be fetched."),
+      _ => None,
+    }
+  }
+}
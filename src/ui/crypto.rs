@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use uuid::Uuid;
+
+/// First byte of the blobs, changed along their layout or key derivation.
+const FORMAT: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+pub const KEY_LEN: usize = 32;
+
+/// Encrypts conversations with keys derived from a passphrase, which never
+/// leaves the browser.
+///
+/// A blob is the format byte, the salt of its key, its nonce and the
+/// ciphertext.
+pub struct Vault {
+  passphrase: String,
+  /// Salt of the key new blobs are encrypted with, replaced by the one of the
+  /// stored blobs so that a single key is derived per device.
+  salt: [u8; SALT_LEN],
+  ciphers: HashMap<[u8; SALT_LEN], XChaCha20Poly1305>,
+}
+
+impl Vault {
+  pub fn new(passphrase: String) -> Self {
+    let mut salt = [0; SALT_LEN];
+    getrandom::getrandom(&mut salt).unwrap();
+
+    Self {
+      passphrase,
+      salt,
+      ciphers: HashMap::new(),
+    }
+  }
+
+  /// The id of the conversation is authenticated along the blob so that the
+  /// server can't swap the blobs of two conversations.
+  pub fn encrypt(&mut self, id: Uuid, plaintext: &[u8]) -> Vec<u8> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).unwrap();
+
+    let salt = self.salt;
+    let ciphertext = self
+      .cipher(salt)
+      .encrypt(
+        XNonce::from_slice(&nonce),
+        Payload {
+          msg: plaintext,
+          aad: id.as_bytes(),
+        },
+      )
+      .unwrap();
+
+    let mut blob = Vec::with_capacity(1 + SALT_LEN + NONCE_LEN + ciphertext.len());
+    blob.push(FORMAT);
+    blob.extend_from_slice(&salt);
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&ciphertext);
+
+    blob
+  }
+
+  /// Returns `None` if the passphrase is wrong or the blob was tampered with.
+  pub fn decrypt(&mut self, id: Uuid, blob: &[u8]) -> Option<Vec<u8>> {
+    if blob.len() < 1 + SALT_LEN + NONCE_LEN || blob[0] != FORMAT {
+      return None;
+    }
+
+    let (salt, rest) = blob[1..].split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let salt = salt.try_into().unwrap();
+    let plaintext = self
+      .cipher(salt)
+      .decrypt(
+        XNonce::from_slice(nonce),
+        Payload {
+          msg: ciphertext,
+          aad: id.as_bytes(),
+        },
+      )
+      .ok()?;
+
+    self.salt = salt;
+
+    Some(plaintext)
+  }
+
+  /// Derives the key of a salt the first time it's used, which is slow on
+  /// purpose.
+  fn cipher(&mut self, salt: [u8; SALT_LEN]) -> &XChaCha20Poly1305 {
+    let passphrase = &self.passphrase;
+
+    self.ciphers.entry(salt).or_insert_with(|| {
+      let mut key = [0; 32];
+
+      Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .unwrap();
+
+      XChaCha20Poly1305::new(&key.into())
+    })
+  }
+}
+
+pub fn random_key() -> [u8; KEY_LEN] {
+  let mut key = [0; KEY_LEN];
+  getrandom::getrandom(&mut key).unwrap();
+
+  key
+}
+
+/// Encrypts a shared conversation with a random key, which is kept in the
+/// fragment of its link. The blob is the nonce and the ciphertext.
+pub fn seal(key: &[u8; KEY_LEN], plaintext: &[u8]) -> Vec<u8> {
+  let mut nonce = [0; NONCE_LEN];
+  getrandom::getrandom(&mut nonce).unwrap();
+
+  let ciphertext = XChaCha20Poly1305::new(key.into())
+    .encrypt(XNonce::from_slice(&nonce), plaintext)
+    .unwrap();
+
+  let mut blob = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+  blob.extend_from_slice(&nonce);
+  blob.extend_from_slice(&ciphertext);
+
+  blob
+}
+
+/// Returns `None` if the key is wrong or the blob was tampered with.
+pub fn open(key: &[u8; KEY_LEN], blob: &[u8]) -> Option<Vec<u8>> {
+  if blob.len() < NONCE_LEN {
+    return None;
+  }
+
+  let (nonce, ciphertext) = blob.split_at(NONCE_LEN);
+
+  XChaCha20Poly1305::new(key.into())
+    .decrypt(XNonce::from_slice(nonce), ciphertext)
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const ID: Uuid = Uuid::from_u128(0x936d_a01f_9abd_4d9d_80c7_02af_85c8_22a8);
+  const TEXT: &[u8] = b"[{\"role\":\"user\",\"content\":\"Bonjour\"}]";
+
+  #[test]
+  fn blobs_round_trip() {
+    let mut vault = Vault::new("correct horse".to_owned());
+    let blob = vault.encrypt(ID, TEXT);
+
+    assert_eq!(vault.decrypt(ID, &blob).as_deref(), Some(TEXT));
+
+    // on another device, which learns the salt from the blob
+    let mut other = Vault::new("correct horse".to_owned());
+
+    assert_eq!(other.decrypt(ID, &blob).as_deref(), Some(TEXT));
+    assert_eq!(&other.encrypt(ID, TEXT)[1..1 + SALT_LEN], &blob[1..1 + SALT_LEN]);
+  }
+
+  #[test]
+  fn wrong_passphrases_are_refused() {
+    let blob = Vault::new("correct horse".to_owned()).encrypt(ID, TEXT);
+
+    assert_eq!(Vault::new("battery staple".to_owned()).decrypt(ID, &blob), None);
+  }
+
+  #[test]
+  fn tampered_blobs_are_refused() {
+    let mut vault = Vault::new("correct horse".to_owned());
+    let blob = vault.encrypt(ID, TEXT);
+
+    for i in [0, 1 + SALT_LEN, blob.len() - 1] {
+      let mut tampered = blob.clone();
+      tampered[i] ^= 1;
+
+      assert_eq!(vault.decrypt(ID, &tampered), None, "byte {i}");
+    }
+
+    assert_eq!(vault.decrypt(ID, &blob[..1 + SALT_LEN + NONCE_LEN - 1]), None);
+    assert_eq!(vault.decrypt(ID, &blob[..blob.len() - 1]), None);
+  }
+
+  #[test]
+  fn blobs_are_bound_to_their_conversation() {
+    let mut vault = Vault::new("correct horse".to_owned());
+    let blob = vault.encrypt(ID, TEXT);
+
+    assert_eq!(vault.decrypt(Uuid::nil(), &blob), None);
+  }
+
+  #[test]
+  fn shares_round_trip() {
+    let key = random_key();
+    let blob = seal(&key, TEXT);
+
+    assert_eq!(open(&key, &blob).as_deref(), Some(TEXT));
+    assert_eq!(open(&random_key(), &blob), None);
+
+    let mut tampered = blob.clone();
+    tampered[NONCE_LEN] ^= 1;
+
+    assert_eq!(open(&key, &tampered), None);
+    assert_eq!(open(&key, &blob[..NONCE_LEN - 1]), None);
+  }
+}
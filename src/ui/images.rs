@@ -0,0 +1,67 @@
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{window, CanvasRenderingContext2d, File, HtmlCanvasElement, HtmlImageElement, Url};
+
+use crate::attachments::MAX_IMAGE_DIMENSION;
+
+const JPEG_QUALITY: f64 = 0.85;
+
+/// Scales an image down to fit in `MAX_IMAGE_DIMENSION` and re-encodes it as
+/// a JPEG `data:` URL. Drawing it on a canvas leaves its metadata out, like
+/// the place a photo was taken at. Errors follow the name of the file in a
+/// sentence.
+pub async fn prepare(file: &File) -> Result<String, &'static str> {
+  let url = Url::create_object_url_with_blob(file).map_err(|_| "couldn't be read")?;
+  let img = HtmlImageElement::new().unwrap();
+  let loaded = js_sys::Promise::new(&mut |resolve, reject| {
+    img.set_onload(Some(&resolve));
+    img.set_onerror(Some(&reject));
+  });
+
+  img.set_src(&url);
+
+  let res = JsFuture::from(loaded).await;
+
+  img.set_onload(None);
+  img.set_onerror(None);
+  drop(Url::revoke_object_url(&url));
+  res.map_err(|_| "isn't an image the browser can read")?;
+
+  let (width, height) = (img.natural_width(), img.natural_height());
+
+  if width == 0 || height == 0 {
+    return Err("is empty");
+  }
+
+  let scale = (f64::from(MAX_IMAGE_DIMENSION) / f64::from(width.max(height))).min(1.0);
+  let width = (f64::from(width) * scale).round().max(1.0);
+  let height = (f64::from(height) * scale).round().max(1.0);
+
+  let canvas: HtmlCanvasElement = window()
+    .unwrap()
+    .document()
+    .unwrap()
+    .create_element("canvas")
+    .unwrap()
+    .unchecked_into();
+  canvas.set_width(width as u32);
+  canvas.set_height(height as u32);
+
+  let ctx: CanvasRenderingContext2d = canvas
+    .get_context("2d")
+    .ok()
+    .flatten()
+    .ok_or("couldn't be drawn")?
+    .unchecked_into();
+
+  // JPEGs have no transparency, which would turn black
+  ctx.set_fill_style(&JsValue::from_str("#FFFFFF"));
+  ctx.fill_rect(0.0, 0.0, width, height);
+  ctx
+    .draw_image_with_html_image_element_and_dw_and_dh(&img, 0.0, 0.0, width, height)
+    .map_err(|_| "couldn't be drawn")?;
+
+  canvas
+    .to_data_url_with_type_and_encoder_options("image/jpeg", &JsValue::from_f64(JPEG_QUALITY))
+    .map_err(|_| "couldn't be encoded")
+}
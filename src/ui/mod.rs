@@ -1,3 +1,9 @@
+pub mod api;
 pub mod components;
+pub mod crypto;
+pub mod images;
 pub mod reducers;
+pub mod routing;
+pub mod share;
+pub mod sync;
 pub mod utils;
@@ -0,0 +1,150 @@
+use std::rc::Rc;
+
+use uuid::Uuid;
+use yew::Reducible;
+
+use crate::ui::components::Ask;
+
+/// Reply of one of the providers a prompt is compared on.
+#[derive(Clone, Default, PartialEq)]
+pub struct Column {
+  pub reply: Rc<str>,
+  pub msg_id: Option<String>,
+  pub done: bool,
+  pub error: Option<Rc<str>>,
+}
+
+/// Reply picked to continue the conversation with.
+pub struct Choice {
+  pub provider: Rc<str>,
+  pub prompt: Rc<str>,
+  pub reply: Rc<str>,
+  pub msg_id: Option<String>,
+}
+
+#[derive(PartialEq)]
+pub struct Columns {
+  pub inner: Vec<Column>,
+}
+
+impl Columns {
+  pub fn new(len: usize) -> Self {
+    Self {
+      inner: vec![Column::default(); len],
+    }
+  }
+}
+
+impl Reducible for Columns {
+  type Action = ColumnsAction;
+
+  fn reduce(self: Rc<Self>, action: Self::Action) -> Rc<Self> {
+    let mut inner = self.inner.clone();
+
+    match action {
+      Self::Action::Finish(i) => inner[i].done = true,
+      Self::Action::PushReply(i, text) => {
+        let mut reply = inner[i].reply.to_string();
+
+        reply.push_str(&text);
+        inner[i].reply = reply.into();
+      }
+      Self::Action::SetError(i, error) => inner[i].error = Some(error.into()),
+      Self::Action::SetMessageId(i, msg_id) => inner[i].msg_id = Some(msg_id),
+    }
+
+    Self { inner }.into()
+  }
+}
+
+pub enum ColumnsAction {
+  Finish(usize),
+  PushReply(usize, String),
+  SetError(usize, String),
+  SetMessageId(usize, String),
+}
+
+/// Whether prompts are sent to several providers, which ones, and the
+/// comparison shown.
+#[derive(Default)]
+pub struct Comparing {
+  pub active: bool,
+  pub providers: Vec<Rc<str>>,
+  pub pending: Option<Rc<PendingComparison>>,
+}
+
+/// A prompt sent to several providers at once from a conversation.
+pub struct PendingComparison {
+  /// Remounts the comparison when another prompt replaces it.
+  pub id: u32,
+  pub conv_id: Uuid,
+  pub prompt: Rc<str>,
+  /// JSON array of the images attached to the prompt.
+  pub images: Option<Rc<str>>,
+  pub asks: Rc<[Ask]>,
+}
+
+impl Reducible for Comparing {
+  type Action = ComparingAction;
+
+  fn reduce(self: Rc<Self>, action: Self::Action) -> Rc<Self> {
+    let mut active = self.active;
+    let mut providers = self.providers.clone();
+    let mut pending = self.pending.clone();
+
+    match action {
+      Self::Action::Toggle => active = !active,
+      Self::Action::ToggleProvider(provider) => {
+        if let Some(i) = providers.iter().position(|p| *p == provider) {
+          providers.remove(i);
+        } else {
+          providers.push(provider);
+        }
+      }
+      Self::Action::Start {
+        conv_id,
+        prompt,
+        images,
+        asks,
+      } => {
+        pending = Some(
+          PendingComparison {
+            id: pending.as_ref().map_or(0, |pending| pending.id + 1),
+            conv_id,
+            prompt,
+            images,
+            asks,
+          }
+          .into(),
+        );
+      }
+      Self::Action::Close => pending = None,
+      Self::Action::Finish => {
+        active = false;
+        pending = None;
+      }
+    }
+
+    Self {
+      active,
+      providers,
+      pending,
+    }
+    .into()
+  }
+}
+
+pub enum ComparingAction {
+  Toggle,
+  ToggleProvider(Rc<str>),
+  Start {
+    conv_id: Uuid,
+    prompt: Rc<str>,
+    images: Option<Rc<str>>,
+    asks: Rc<[Ask]>,
+  },
+  /// Closes the comparison without leaving the comparing mode.
+  Close,
+  /// Closes the comparison once a reply was picked.
+  Finish,
+}
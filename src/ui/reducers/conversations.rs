@@ -1,3 +1,4 @@
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
@@ -5,18 +6,40 @@ use time::OffsetDateTime;
 use uuid::Uuid;
 use yew::Reducible;
 
+use super::Choice;
+
+/// Sent before the summary of the earlier messages, as the first prompt of a
+/// conversation.
+const SUMMARY_INTRO: &str = "Here is a summary of our conversation so far:";
+const SUMMARY_REPLY: &str = "Got it, let's continue from there.";
+
 #[derive(Clone, PartialEq)]
 pub struct Conversation {
   pub created_at: OffsetDateTime,
   pub name: Rc<str>,
   pub provider: Rc<str>,
+  /// `None` for the provider's default model.
+  pub model: Option<Rc<str>>,
   pub messages: Vec<Rc<str>>,
+  /// Provider of each reply.
+  pub reply_providers: Vec<Rc<str>>,
   pub updating_last_msg: bool,
   pub last_msg_id: Option<String>,
+  /// Why the last reply failed or is incomplete.
+  pub error: Option<Rc<str>>,
+  /// Sent in place of the messages it covers.
+  pub summary: Option<Summary>,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Summary {
+  pub text: Rc<str>,
+  /// Number of messages it covers from the beginning.
+  pub len: usize,
 }
 
 impl Conversation {
-  fn new(provider: Rc<str>) -> Self {
+  pub fn new(provider: Rc<str>) -> Self {
     let now = OffsetDateTime::now_utc();
     let name = now
       .format(time::macros::format_description!(
@@ -28,11 +51,39 @@ impl Conversation {
       created_at: now,
       name: name.into(),
       provider,
+      model: None,
       messages: Vec::new(),
+      reply_providers: Vec::new(),
       updating_last_msg: false,
       last_msg_id: None,
+      error: None,
+      summary: None,
     }
   }
+
+  /// Whether the last reply comes from another provider, whose state doesn't
+  /// apply to the current one.
+  pub fn switched_provider(&self) -> bool {
+    self
+      .reply_providers
+      .last()
+      .is_some_and(|provider| *provider != self.provider)
+  }
+
+  /// Messages sent along a prompt, the summary replacing the messages it
+  /// covers.
+  pub fn context(&self) -> Vec<Rc<str>> {
+    let Some(summary) = self.summary.as_ref() else {
+      return self.messages.clone();
+    };
+    let mut context = Vec::with_capacity(2 + self.messages.len() - summary.len);
+
+    context.push(format!("{SUMMARY_INTRO}\n\n{}", summary.text).into());
+    context.push(SUMMARY_REPLY.into());
+    context.extend(self.messages[summary.len..].iter().cloned());
+
+    context
+  }
 }
 
 #[derive(PartialEq)]
@@ -43,9 +94,11 @@ pub struct Conversations {
 }
 
 impl Conversations {
-  pub fn new(default_provider: &str) -> Self {
+  /// The first conversation gets `first_id` if set, like the one in the URL
+  /// of the page.
+  pub fn new(default_provider: &str, first_id: Option<Uuid>) -> Self {
     let default_provider = Rc::<str>::from(default_provider);
-    let first_id = Uuid::new_v4();
+    let first_id = first_id.unwrap_or_else(Uuid::new_v4);
 
     Self {
       default_provider: default_provider.clone(),
@@ -97,6 +150,31 @@ impl Reducible for Conversations {
   fn reduce(self: Rc<Self>, action: Self::Action) -> Rc<Self> {
     let mut current_id = self.current_id;
     let inner = match action {
+      Self::Action::ContinueWith(id, choice) => {
+        let mut inner = self.inner.clone();
+
+        if let Some(conv) = inner.get_mut(&id) {
+          let mut prompt = choice.prompt.to_string();
+          prompt.push('\n');
+
+          let mut reply = choice.reply.to_string();
+          reply.push('\n');
+
+          if conv.provider != choice.provider {
+            conv.provider = choice.provider;
+            conv.model = None;
+          }
+
+          conv.error = None;
+          conv.last_msg_id = choice.msg_id;
+          conv.messages.reserve_exact(2);
+          conv.messages.push(prompt.into());
+          conv.messages.push(reply.into());
+          conv.reply_providers.push(conv.provider.clone());
+        }
+
+        inner
+      }
       Self::Action::CreateConversation => {
         let mut inner = self.inner.clone();
         let id = Uuid::new_v4();
@@ -129,15 +207,59 @@ impl Reducible for Conversations {
 
         inner
       }
+      Self::Action::LoadConversations(convs) => {
+        let mut inner = self.inner.clone();
+
+        // the ones changed since they were fetched are kept, unlike the unused
+        // ones opened from a link
+        for (id, conv) in convs {
+          match inner.entry(id) {
+            Entry::Occupied(mut entry) if entry.get().messages.is_empty() => {
+              entry.insert(conv);
+            }
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+              entry.insert(conv);
+            }
+          }
+        }
+
+        inner
+      }
+      Self::Action::OpenConversation(id) => {
+        let mut inner = self.inner.clone();
+
+        if !inner.contains_key(&id) {
+          // an unused conversation gives way rather than piling up
+          if self.current().messages.is_empty() {
+            inner.remove(&self.current_id);
+          }
+
+          inner.insert(id, Conversation::new(self.default_provider.clone()));
+        }
+
+        current_id = id;
+
+        inner
+      }
       Self::Action::PushMessage(id, mut msg) => {
         msg.push('\n');
 
         let mut inner = self.inner.clone();
         let conv = inner.get_mut(&id).unwrap();
 
+        conv.error = None;
         conv.messages.reserve_exact(2);
         conv.messages.push(msg.into());
         conv.messages.push("\n".into());
+        conv.reply_providers.push(conv.provider.clone());
+
+        inner
+      }
+      Self::Action::ReplaceConversation(id, conv) => {
+        let mut inner = self.inner.clone();
+
+        inner.insert(id, conv);
 
         inner
       }
@@ -153,6 +275,15 @@ impl Reducible for Conversations {
         current_id = id;
         self.inner.clone()
       }
+      Self::Action::SetError(id, error) => {
+        let mut inner = self.inner.clone();
+
+        if let Some(conv) = inner.get_mut(&id) {
+          conv.error = Some(error.into());
+        }
+
+        inner
+      }
       Self::Action::SetLastMessageId(id, last_msg_id) => {
         let mut inner = self.inner.clone();
         let conv = inner.get_mut(&id).unwrap();
@@ -160,11 +291,29 @@ impl Reducible for Conversations {
 
         inner
       }
+      Self::Action::SetModel(model) => {
+        let mut inner = self.inner.clone();
+        let conv = inner.get_mut(&self.current_id).unwrap();
+
+        conv.model = Some(model.into());
+
+        inner
+      }
       Self::Action::SetProvider(provider) => {
         let mut inner = self.inner.clone();
         let conv = inner.get_mut(&self.current_id).unwrap();
 
         conv.provider = provider.into();
+        conv.model = None;
+
+        inner
+      }
+      Self::Action::SetSummary(id, summary) => {
+        let mut inner = self.inner.clone();
+
+        if let Some(conv) = inner.get_mut(&id) {
+          conv.summary = Some(summary);
+        }
 
         inner
       }
@@ -204,13 +353,20 @@ impl Reducible for Conversations {
 }
 
 pub enum ConversationsAction {
+  ContinueWith(Uuid, Choice),
   CreateConversation,
   DeleteConversation(Uuid, usize),
+  LoadConversations(Vec<(Uuid, Conversation)>),
+  OpenConversation(Uuid),
   PushMessage(Uuid, String),
+  ReplaceConversation(Uuid, Conversation),
   SetCurrentConversationName(String),
   SetCurrentId(Uuid),
+  SetError(Uuid, String),
   SetLastMessageId(Uuid, String),
+  SetModel(String),
   SetProvider(String),
+  SetSummary(Uuid, Summary),
   SetUpdatingLastMessage(Uuid, bool),
   UpdateLastMessage(Uuid, char),
 }
@@ -1,3 +1,5 @@
+mod comparison;
 mod conversations;
 
+pub use comparison::*;
 pub use conversations::*;
@@ -0,0 +1,41 @@
+use uuid::Uuid;
+use wasm_bindgen::JsValue;
+use web_sys::window;
+
+/// Prefix of the paths of the conversations.
+const PREFIX: &str = "/c/";
+
+pub fn path(id: Uuid) -> String {
+  format!("{PREFIX}{id}")
+}
+
+/// Parses the id of the conversation a path points at.
+pub fn conversation_id(path: &str) -> Option<Uuid> {
+  Uuid::parse_str(path.strip_prefix(PREFIX)?.trim_end_matches('/')).ok()
+}
+
+/// Returns the id of the conversation in the URL of the page, `None` if it
+/// doesn't point at one.
+pub fn current() -> Option<Uuid> {
+  conversation_id(&window()?.location().pathname().ok()?)
+}
+
+/// Points the URL of the page at a conversation, in a new history entry unless
+/// it didn't point at one yet.
+pub fn navigate(id: Uuid) {
+  let window = window().unwrap();
+  let history = window.history().unwrap();
+  let current = conversation_id(&window.location().pathname().unwrap());
+
+  if current == Some(id) {
+    return;
+  }
+
+  let path = path(id);
+
+  if current.is_some() {
+    drop(history.push_state_with_url(&JsValue::NULL, "", Some(&path)));
+  } else {
+    drop(history.replace_state_with_url(&JsValue::NULL, "", Some(&path)));
+  }
+}
@@ -0,0 +1,146 @@
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use web_sys::window;
+
+use crate::ui::crypto::{self, KEY_LEN};
+use crate::ui::reducers::Conversation;
+
+/// A conversation as shared through a link, read-only.
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct Snapshot {
+  pub name: String,
+  /// Alternating prompts and replies.
+  pub messages: Vec<String>,
+  /// Provider of each reply.
+  pub reply_providers: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ShareBody<'s> {
+  #[serde(flatten)]
+  content: ShareContent<'s>,
+  expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ShareContent<'s> {
+  Snapshot(&'s Snapshot),
+  Ciphertext(String),
+}
+
+#[derive(Deserialize)]
+struct Created {
+  slug: String,
+}
+
+impl Snapshot {
+  pub fn is_valid(&self) -> bool {
+    // the messages alternate prompts and replies
+    self.messages.len() % 2 != 1 && self.reply_providers.len() <= self.messages.len() / 2
+  }
+}
+
+impl From<&Conversation> for Snapshot {
+  fn from(conv: &Conversation) -> Self {
+    Self {
+      name: conv.name.to_string(),
+      messages: conv.messages.iter().map(|msg| msg.to_string()).collect(),
+      reply_providers: conv
+        .reply_providers
+        .iter()
+        .map(|provider| provider.to_string())
+        .collect(),
+    }
+  }
+}
+
+/// Uploads a snapshot of a conversation and returns its link. When
+/// `encrypted`, the key is only kept in the fragment of the link, which
+/// browsers don't send.
+pub async fn create(
+  conv: &Conversation,
+  expires_in: Option<u64>,
+  encrypted: bool,
+) -> Result<String, &'static str> {
+  let snapshot = Snapshot::from(conv);
+  let key = encrypted.then(crypto::random_key);
+  let content = match key {
+    Some(key) => {
+      let plaintext = serde_json::to_vec(&snapshot).unwrap();
+
+      ShareContent::Ciphertext(BASE64.encode(crypto::seal(&key, &plaintext)))
+    }
+    None => ShareContent::Snapshot(&snapshot),
+  };
+  let body = serde_json::to_string(&ShareBody {
+    content,
+    expires_in,
+  })
+  .unwrap();
+
+  let origin = window().unwrap().location().origin().unwrap();
+  let res = gloo_net::http::Request::post(&format!("{origin}/api/shares"))
+    .header("content-type", "application/json")
+    .body(body)
+    .map_err(|_| "The conversation couldn't be shared.")?
+    .send()
+    .await
+    .map_err(|_| "The server couldn't be reached.")?;
+
+  match res.status() {
+    429 => return Err("Too many requests were made, try again in a minute."),
+    507 => return Err("Too many conversations are shared already."),
+    _ if !res.ok() => return Err("The conversation couldn't be shared."),
+    _ => {}
+  }
+
+  let slug = res
+    .text()
+    .await
+    .ok()
+    .and_then(|text| serde_json::from_str::<Created>(&text).ok())
+    .ok_or("The conversation couldn't be shared.")?
+    .slug;
+  let mut link = format!("{origin}/s/{slug}");
+
+  if let Some(key) = key {
+    link.push('#');
+    link.push_str(&BASE64_URL.encode(key));
+  }
+
+  Ok(link)
+}
+
+/// Fetches an encrypted snapshot and decrypts it with the key in the fragment
+/// of the page.
+pub async fn decrypt(slug: &str) -> Result<Snapshot, &'static str> {
+  let location = window().unwrap().location();
+  let key: [u8; KEY_LEN] = location
+    .hash()
+    .ok()
+    .and_then(|hash| BASE64_URL.decode(hash.trim_start_matches('#')).ok())
+    .and_then(|key| key.try_into().ok())
+    .ok_or("The link is missing the key of the conversation.")?;
+
+  let origin = location.origin().unwrap();
+  let res = gloo_net::http::Request::get(&format!("{origin}/api/shares/{slug}"))
+    .send()
+    .await
+    .map_err(|_| "The server couldn't be reached.")?;
+
+  if !res.ok() {
+    return Err("The conversation couldn't be fetched.");
+  }
+
+  let blob = res
+    .binary()
+    .await
+    .map_err(|_| "The conversation couldn't be fetched.")?;
+
+  crypto::open(&key, &blob)
+    .and_then(|plaintext| serde_json::from_slice::<Snapshot>(&plaintext).ok())
+    .filter(Snapshot::is_valid)
+    .ok_or("The conversation couldn't be decrypted, the link may be incomplete.")
+}
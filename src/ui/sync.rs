@@ -0,0 +1,421 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use uuid::Uuid;
+use web_sys::window;
+use yew::UseReducerDispatcher;
+
+use crate::ui::crypto::Vault;
+use crate::ui::reducers::{Conversation, Conversations, ConversationsAction, Summary};
+
+/// Key of the local storage entry holding the id of this client.
+const CLIENT_ID_KEY: &str = "client-id";
+
+/// Conversations as stored on the server, and the vault they're encrypted
+/// with.
+pub struct Synced {
+  vault: Vault,
+  convs: HashMap<Uuid, SyncedConversation>,
+}
+
+struct SyncedConversation {
+  /// Content of `version`, or the one being sent while `busy`.
+  conv: Conversation,
+  /// `None` until the conversation is first stored.
+  version: Option<u64>,
+  busy: bool,
+  /// Latest change made while `busy`, sent afterwards.
+  queued: Option<Conversation>,
+}
+
+pub enum UnlockError {
+  /// A stored conversation couldn't be decrypted.
+  WrongPassphrase,
+  Failed,
+}
+
+/// A conversation stored by the server.
+#[derive(Deserialize)]
+struct Entry {
+  id: String,
+}
+
+/// The JSON document of a conversation, before it's encrypted.
+#[derive(Deserialize, Serialize)]
+struct StoredConversation {
+  /// Milliseconds since the Unix epoch.
+  created_at: i64,
+  name: String,
+  provider: String,
+  model: Option<String>,
+  messages: Vec<String>,
+  reply_providers: Vec<String>,
+  last_msg_id: Option<String>,
+  summary: Option<StoredSummary>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct StoredSummary {
+  text: String,
+  len: usize,
+}
+
+enum Put {
+  Stored(u64),
+  /// The conversation isn't at the expected version.
+  Conflict,
+  Failed,
+}
+
+impl From<&Conversation> for StoredConversation {
+  fn from(conv: &Conversation) -> Self {
+    Self {
+      created_at: (conv.created_at.unix_timestamp_nanos() / 1_000_000) as i64,
+      name: conv.name.to_string(),
+      provider: conv.provider.to_string(),
+      model: conv.model.as_deref().map(String::from),
+      messages: conv.messages.iter().map(|msg| msg.to_string()).collect(),
+      reply_providers: conv
+        .reply_providers
+        .iter()
+        .map(|provider| provider.to_string())
+        .collect(),
+      last_msg_id: conv.last_msg_id.clone(),
+      summary: conv.summary.as_ref().map(|summary| StoredSummary {
+        text: summary.text.to_string(),
+        len: summary.len,
+      }),
+    }
+  }
+}
+
+impl TryFrom<StoredConversation> for Conversation {
+  type Error = ();
+
+  fn try_from(stored: StoredConversation) -> Result<Self, Self::Error> {
+    // the messages alternate prompts and replies
+    if stored.messages.len() % 2 == 1
+      || stored
+        .summary
+        .as_ref()
+        .is_some_and(|summary| summary.len > stored.messages.len())
+    {
+      return Err(());
+    }
+
+    Ok(Self {
+      created_at: OffsetDateTime::from_unix_timestamp_nanos(stored.created_at as i128 * 1_000_000)
+        .map_err(|_| ())?,
+      name: stored.name.into(),
+      provider: stored.provider.into(),
+      model: stored.model.map(Rc::from),
+      messages: stored.messages.into_iter().map(Rc::from).collect(),
+      reply_providers: stored.reply_providers.into_iter().map(Rc::from).collect(),
+      updating_last_msg: false,
+      last_msg_id: stored.last_msg_id,
+      error: None,
+      summary: stored.summary.map(|summary| Summary {
+        text: summary.text.into(),
+        len: summary.len,
+      }),
+    })
+  }
+}
+
+/// Whether the server stores conversations.
+pub async fn is_available() -> bool {
+  list().await.is_some()
+}
+
+/// Fetches and decrypts the stored conversations. Nothing is synced if one of
+/// them can't be decrypted, so that they aren't overwritten with another
+/// passphrase.
+pub async fn unlock(
+  passphrase: String,
+) -> Result<(Synced, Vec<(Uuid, Conversation)>), UnlockError> {
+  let entries = list().await.ok_or(UnlockError::Failed)?;
+  let mut vault = Vault::new(passphrase);
+  let mut convs = HashMap::with_capacity(entries.len());
+  let mut loaded = Vec::with_capacity(entries.len());
+
+  for entry in entries {
+    let Ok(id) = Uuid::parse_str(&entry.id) else {
+      continue;
+    };
+    let (blob, version) = get(id).await.ok_or(UnlockError::Failed)?;
+    let plaintext = vault
+      .decrypt(id, &blob)
+      .ok_or(UnlockError::WrongPassphrase)?;
+    let Some(conv) = decode(&plaintext) else {
+      continue;
+    };
+
+    convs.insert(
+      id,
+      SyncedConversation {
+        conv: conv.clone(),
+        version: Some(version),
+        busy: false,
+        queued: None,
+      },
+    );
+    loaded.push((id, conv));
+  }
+
+  Ok((Synced { vault, convs }, loaded))
+}
+
+/// Sends the conversations changed since they were stored and deletes the
+/// removed ones, once unlocked.
+pub fn push_changes(
+  synced: &Rc<RefCell<Option<Synced>>>,
+  conversations: &Conversations,
+  dispatcher: UseReducerDispatcher<Conversations>,
+) {
+  let mut state = synced.borrow_mut();
+  let Some(state) = state.as_mut() else {
+    return;
+  };
+
+  // replies are stored once complete, and conversations once used
+  for (&id, conv) in conversations
+    .inner
+    .iter()
+    .filter(|(_, conv)| !conv.updating_last_msg && !conv.messages.is_empty())
+  {
+    match state.convs.get_mut(&id) {
+      Some(entry) if entry.busy => {
+        entry.queued = Some(conv.clone());
+        continue;
+      }
+      Some(entry) if entry.conv != *conv => {
+        entry.conv = conv.clone();
+        entry.busy = true;
+      }
+      Some(_) => continue,
+      None => {
+        state.convs.insert(
+          id,
+          SyncedConversation {
+            conv: conv.clone(),
+            version: None,
+            busy: true,
+            queued: None,
+          },
+        );
+      }
+    }
+
+    wasm_bindgen_futures::spawn_local(upload(synced.clone(), id, dispatcher.clone()));
+  }
+
+  state.convs.retain(|id, _| {
+    let kept = conversations.inner.contains_key(id);
+
+    if !kept {
+      wasm_bindgen_futures::spawn_local(delete(*id));
+    }
+
+    kept
+  });
+}
+
+/// Sends a conversation until its latest change is stored. When another
+/// client updated it in the meantime, the copy with the most messages wins.
+async fn upload(
+  synced: Rc<RefCell<Option<Synced>>>,
+  id: Uuid,
+  dispatcher: UseReducerDispatcher<Conversations>,
+) {
+  loop {
+    let (blob, version) = {
+      let mut state = synced.borrow_mut();
+      let Some(state) = state.as_mut() else {
+        return;
+      };
+      let Some(entry) = state.convs.get(&id) else {
+        return;
+      };
+      let plaintext = serde_json::to_vec(&StoredConversation::from(&entry.conv)).unwrap();
+      let version = entry.version;
+
+      (state.vault.encrypt(id, &plaintext), version)
+    };
+
+    let res = put(id, blob, version).await;
+    let remote = match res {
+      Put::Conflict => get(id).await,
+      _ => None,
+    };
+
+    let mut state = synced.borrow_mut();
+    let Some(state) = state.as_mut() else {
+      return;
+    };
+    let remote = remote.map(|(blob, version)| {
+      let conv = state
+        .vault
+        .decrypt(id, &blob)
+        .and_then(|plaintext| decode(&plaintext));
+
+      (conv, version)
+    });
+    // the conversation was deleted meanwhile
+    let Some(entry) = state.convs.get_mut(&id) else {
+      return;
+    };
+
+    match res {
+      Put::Stored(version) => entry.version = Some(version),
+      Put::Conflict => {
+        // created again if another client deleted it
+        entry.version = remote.as_ref().map(|(_, version)| *version);
+
+        if let Some((Some(remote), _)) = remote {
+          let local = entry.queued.as_ref().unwrap_or(&entry.conv);
+
+          if remote.messages.len() > local.messages.len() {
+            entry.conv = remote.clone();
+            entry.queued = None;
+            entry.busy = false;
+            dispatcher.dispatch(ConversationsAction::ReplaceConversation(id, remote));
+
+            return;
+          }
+        }
+
+        continue;
+      }
+      // the conversation is sent again with its next change
+      Put::Failed => {
+        entry.queued = None;
+        entry.busy = false;
+
+        return;
+      }
+    }
+
+    match entry.queued.take() {
+      Some(conv) if conv != entry.conv => entry.conv = conv,
+      _ => {
+        entry.busy = false;
+
+        return;
+      }
+    }
+  }
+}
+
+fn decode(plaintext: &[u8]) -> Option<Conversation> {
+  serde_json::from_slice::<StoredConversation>(plaintext)
+    .ok()?
+    .try_into()
+    .ok()
+}
+
+/// Id the server keys the conversations of this client with when it doesn't
+/// use an access token, generated on the first visit.
+fn client_id() -> String {
+  let storage = window().unwrap().local_storage().ok().flatten();
+
+  if let Some(id) = storage
+    .as_ref()
+    .and_then(|storage| storage.get_item(CLIENT_ID_KEY).ok().flatten())
+  {
+    return id;
+  }
+
+  let id = Uuid::new_v4().to_string();
+
+  if let Some(storage) = storage {
+    drop(storage.set_item(CLIENT_ID_KEY, &id));
+  }
+
+  id
+}
+
+fn url(id: Option<Uuid>) -> String {
+  let mut url = window().unwrap().location().origin().unwrap();
+  url.push_str("/api/conversations");
+
+  if let Some(id) = id {
+    url.push('/');
+    url.push_str(&id.to_string());
+  }
+
+  url
+}
+
+/// Parses the version of a conversation from its entity tag.
+fn version(etag: &str) -> Option<u64> {
+  etag.trim().trim_matches('"').parse().ok()
+}
+
+/// Returns `None` if the server doesn't store conversations.
+async fn list() -> Option<Vec<Entry>> {
+  let res = gloo_net::http::Request::get(&url(None))
+    .header("client-id", &client_id())
+    .send()
+    .await
+    .ok()?;
+
+  if !res.ok() {
+    return None;
+  }
+
+  serde_json::from_str(&res.text().await.ok()?).ok()
+}
+
+/// Returns the blob of a conversation and its version, `None` if it's missing.
+async fn get(id: Uuid) -> Option<(Vec<u8>, u64)> {
+  let res = gloo_net::http::Request::get(&url(Some(id)))
+    .header("client-id", &client_id())
+    .send()
+    .await
+    .ok()?;
+
+  if !res.ok() {
+    return None;
+  }
+
+  let version = version(&res.headers().get("etag")?)?;
+
+  Some((res.binary().await.ok()?, version))
+}
+
+/// Creates a conversation if `version` is `None`, or else replaces that
+/// version of it.
+async fn put(id: Uuid, blob: Vec<u8>, version: Option<u64>) -> Put {
+  let req = gloo_net::http::Request::put(&url(Some(id)))
+    .header("client-id", &client_id())
+    .header("content-type", "application/octet-stream");
+  let req = match version {
+    Some(version) => req.header("if-match", &format!("\"{version}\"")),
+    None => req.header("if-none-match", "*"),
+  };
+  let Ok(req) = req.body(js_sys::Uint8Array::from(blob.as_slice())) else {
+    return Put::Failed;
+  };
+
+  match req.send().await {
+    Ok(res) if res.status() == 412 => Put::Conflict,
+    Ok(res) if res.ok() => res
+      .headers()
+      .get("etag")
+      .and_then(|etag| self::version(&etag))
+      .map_or(Put::Failed, Put::Stored),
+    _ => Put::Failed,
+  }
+}
+
+async fn delete(id: Uuid) {
+  drop(
+    gloo_net::http::Request::delete(&url(Some(id)))
+      .header("client-id", &client_id())
+      .send()
+      .await,
+  );
+}
@@ -1,12 +1,18 @@
+use std::future::Future;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use futures::Stream;
 use hyper::body::{self, Body, HttpBody};
-use hyper::client::HttpConnector;
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use pin_project::pin_project;
+use tokio::task;
+use tokio::time::{self, Instant, Sleep};
+use tracing::Instrument;
+
+use crate::proxy::{Proxy, ProxyConnector};
 
 #[pin_project]
 pub struct BodyStream {
@@ -32,11 +38,90 @@ impl From<Body> for BodyStream {
   }
 }
 
-pub fn new_rustls_connector() -> HttpsConnector<HttpConnector> {
+/// Fails with a `TimedOut` error when the inner stream doesn't yield anything
+/// for `timeout`.
+#[pin_project]
+pub struct IdleTimeout<S> {
+  #[pin]
+  inner: S,
+  #[pin]
+  sleep: Sleep,
+  timeout: Duration,
+  timed_out: bool,
+}
+
+impl<S> IdleTimeout<S> {
+  pub fn new(inner: S, timeout: Duration) -> Self {
+    Self {
+      inner,
+      sleep: time::sleep(timeout),
+      timeout,
+      timed_out: false,
+    }
+  }
+}
+
+impl<S, T> Stream for IdleTimeout<S>
+where
+  S: Stream<Item = Result<T, IoError>>,
+{
+  type Item = S::Item;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    let mut this = self.project();
+
+    if *this.timed_out {
+      return Poll::Ready(None);
+    }
+
+    if let Poll::Ready(item) = this.inner.poll_next(cx) {
+      this.sleep.as_mut().reset(Instant::now() + *this.timeout);
+
+      return Poll::Ready(item);
+    }
+
+    futures::ready!(this.sleep.poll(cx));
+    *this.timed_out = true;
+
+    Poll::Ready(Some(Err(IoError::new(
+      IoErrorKind::TimedOut,
+      "stream timed out",
+    ))))
+  }
+}
+
+pub fn new_rustls_connector(
+  proxies: Vec<Proxy>,
+  connect_timeout: Duration,
+) -> HttpsConnector<ProxyConnector> {
   HttpsConnectorBuilder::new()
     .with_native_roots()
-    .https_only()
+    // plain HTTP is only used by endpoints replaced in the configuration
+    .https_or_http()
     .enable_http1()
     .enable_http2()
-    .build()
+    .wrap_connector(ProxyConnector::new(proxies, connect_timeout))
+}
+
+/// Makes a directory holding `files`, removed along with them once the
+/// returned guard is dropped.
+#[cfg(test)]
+pub fn temp_dir(files: &[(&str, &str)]) -> tempfile::TempDir {
+  let dir = tempfile::tempdir().unwrap();
+
+  for (name, content) in files {
+    std::fs::write(dir.path().join(name), content).unwrap();
+  }
+
+  dir
+}
+
+/// Spawns a task that logs within the current span, which carries the request
+/// id.
+pub fn spawn_in_current_span<F>(future: F)
+where
+  F: Future + Send + 'static,
+  F::Output: Send + 'static,
+{
+  task::spawn(future.in_current_span());
 }